@@ -1,20 +1,41 @@
 //! Handlers for the REST API
 //!
-//! Each handler reads the request if need be, gets the information from getters
-//! and returns a Response accordingly,
+//! Each handler reads the request if need be, gets the information from upstream
+//! and returns a Response accordingly. The work is done by a private function
+//! returning a `Result`, and `misc::respond()` turns its errors into JSON
+//! responses with the matching status code.
 extern crate serde;
 
+use std::cmp;
+
 use utils;
+use error::{Error, Result};
 
 use serde::{Serialize, Deserialize};
-use super::hyper::status::StatusCode;
-use super::hyper::client::Response as HyperResponse;
-use super::pencil::{Request, PencilResult};
+use super::hyper::header::{Authorization, Bearer};
+use super::pencil::{Request, PencilResult, Response as PencilResponse, redirect};
+use super::serde_json::{Map, Value, to_value};
 
-use super::DB_BASE_URL;
-use super::{GenericSpace, Space};
-use super::{getters, misc};
-use super::SearchResult;
+use auth::{Identity, Role, STATE_TTL};
+use availability::{self, AvailabilityQuery, AvailableRoom};
+use cache::CacheStats;
+use config::CheckInPolicy;
+use events::{Event, EventKind};
+use fenix::PathSegment;
+use import::{self, ImportReport, RoomOverrides};
+use oauth::{AccessToken, Person};
+use reaper::MAX_DURATION;
+use reservations;
+use schedule::{Day, ScheduledEvent};
+use search::{self, Hit};
+use stats::{self, Granularity, OccupancyReport};
+use store::{User, Room, NewRoom, CheckIn, CheckOut, CheckOutReason, Visit, Reservation,
+            NewReservation, ReservationQuery};
+use webhooks::{Webhook, NewWebhook, Delivery};
+use super::{auth, config, events, fenix, oauth, publish, search_index, store, webhooks};
+use super::{Space, Spaces};
+use super::misc;
+use super::sse;
 
 // /////////////////////////////////////////////////////////////////////////////
 // ID Handling
@@ -22,74 +43,33 @@ use super::SearchResult;
 
 /// Process the provided `id`
 ///
-/// To process the `id` a get request is sent to `FenixEDU` with it. Then the
-/// status of the response is checked. If the result is Ok the contents of the
-/// body are read and passed along to the client. Otherwise one of two things
-/// can happen. First, the provided `id` may not be valid (it doesn't belong to
-/// any space) or the `FenixEDU` servers are down. Error messages and status
-/// codes are sent apropriately.
+/// To process the `id` a get request is sent to `FenixEDU` with it. If the
+/// result is Ok the contents of the body are converted to `T`, which drops the
+/// unnecessary fields, and passed along to the client. Otherwise one of two
+/// things can happen. First, the provided `id` may not be valid (it doesn't
+/// belong to any space) or the `FenixEDU` servers are down. The matching
+/// `Error` is returned.
 ///
 /// # Arguments
 /// * `id` => the id of the space to get information
 ///
 /// # Return Value
 /// The JSON message processed or an error.
-fn process_id<T>(id: &str) -> PencilResult
+fn process_id<T>(id: &str) -> Result<PencilResponse>
     where T: Serialize + Deserialize
 {
-    // Perform GET request with id
-    let mut get_response: HyperResponse = match getters::get_spaces_from_id(id) {
-        Ok(response) => response,
-        Err(err) => {
-            return Ok(misc::build_response(500, &format!("{{ \"error\": \"{}\" }}", err.desc)));
-        }
-    };
-
-    let buffer: String;
-    let status_code: u16;
-
-    // If the GET request is successful read the body and process the request
-    if get_response.status == StatusCode::Ok {
-        let body: String = match utils::read_response_body(&mut get_response) {
-            Ok(buf) => buf,
-            Err(err) => {
-                return Ok(misc::build_response(500, &format!("{{ \"error\": \"{}\" }}", err)));
-            }
-        };
-
-        // Convert JSON to Object removing the unnecessary fields in the process
-        let space: T = match utils::from_json_to_obj(&body) {
-            Ok(space) => space,
-            Err(err) => {
-                return Ok(misc::build_response(500, &format!("{{ \"error\": \"{}\" }}", err)));
-            }
-        };
-
-        // Turn the simplified object back into JSON
-        buffer = match utils::from_obj_to_json(&space) {
-            Ok(json) => json,
-            Err(err) => {
-                return Ok(misc::build_response(500, &format!("{{ \"error\": \"{}\" }}", err)));
-            }
-        };
+    // Convert JSON to Object removing the unnecessary fields in the process
+    let space: T = fenix().get(id)?;
 
-        status_code = 200;
-    } else if get_response.status == StatusCode::NotFound {
-        // When the id is not valid warn the user
-        status_code = 404;
-        buffer = format!("{{\"error\": \"The id: {} was not found\"}}", id);
-    } else {
-        // When the `FenixEDU` servers are down warn the user
-        status_code = 503;
-        buffer = "{{\"error\": \"Fenix had an error\"}}".to_owned();
-    }
+    // Turn the simplified object back into JSON
+    let buffer: String = utils::from_obj_to_json(&space)?;
 
-    Ok(misc::build_response(status_code, &buffer))
+    Ok(misc::build_response(200, &buffer))
 }
 
 /// Handler for the top level spaces at IST
 ///
-/// The handler calls `utils::get_spaces_from_id()` to perform the GET request
+/// The handler calls `FenixSpacesClient::get()` to perform the GET request
 /// required. If the request was successful its contents will be sent as JSON.
 /// Otherwise an error will be sent, provided by the function.
 ///
@@ -100,26 +80,121 @@ fn process_id<T>(id: &str) -> PencilResult
 ///        declare as unused with `_`.
 ///
 /// # Return Value
-/// Error if the `utils::get_spaces_from_id()` fails. Otherwise
+/// Error if the `FenixSpacesClient::get()` fails. Otherwise
 /// read the contents and send it as JSON.
 pub fn spaces_handler(_: &mut Request) -> PencilResult {
-    process_id::<Space>("")
+    misc::respond(process_id::<Spaces>(""))
+}
+
+/// Fields of a space only sent when asked in the `fields` query argument
+const EXTENDED_FIELDS: &'static [&'static str] =
+    &["type", "parentSpace", "topLevelSpace", "blueprint", "examCapacity"];
+
+/// Get the extended fields asked in the `fields` query argument, a comma
+/// separated list of `EXTENDED_FIELDS` or `all`
+///
+/// # Return Value
+/// The fields, none when the argument is missing, or an `Error::Validation`
+/// for an unknown field.
+fn requested_fields(request: &mut Request) -> Result<Vec<&'static str>> {
+    let fields: String = match misc::query_arg(request, "fields") {
+        Some(fields) => fields,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut requested: Vec<&'static str> = Vec::new();
+    for field in fields.split(',').map(|field| field.trim()).filter(|field| !field.is_empty()) {
+        if field == "all" {
+            return Ok(EXTENDED_FIELDS.to_vec());
+        }
+        match EXTENDED_FIELDS.iter().find(|&&known| known == field) {
+            Some(known) => requested.push(known),
+            None => return Err(Error::Validation(format!("Unknown field {}", field))),
+        }
+    }
+
+    Ok(requested)
+}
+
+/// Build the response sending `space` with the extended fields in `fields`
+/// only
+fn space_response(space: &Space, fields: &[&str]) -> Result<PencilResponse> {
+    let mut value: Value = to_value(space);
+
+    if let Value::Object(ref mut obj) = value {
+        for &field in EXTENDED_FIELDS.iter().filter(|field| !fields.contains(field)) {
+            if field == "examCapacity" {
+                if let Some(&mut Value::Object(ref mut capacity)) = obj.get_mut("capacity") {
+                    capacity.remove("exam");
+                }
+            } else {
+                obj.remove(field);
+            }
+        }
+    }
+
+    misc::json_response(200, &value)
 }
 
 /// Handler for IDs using the `FenixEDU` API. The id sent in the url will be processed.
 ///
+/// The name, contained spaces and capacity of the space are sent. The
+/// `fields` query argument adds the extended fields: `type`, `parentSpace`,
+/// `topLevelSpace`, `blueprint` and `examCapacity`, or `all` of them.
+///
 /// # Arguments
 /// * `id` => id to process
 ///
 /// # Return Value
-/// Error if the `get_spaces_from_id()` fails. Otherwise read the contents and
-/// send it as JSON.
+/// Error if the `FenixSpacesClient::space()` fails. Otherwise read the contents
+/// and send it as JSON.
 pub fn id_handler(request: &mut Request) -> PencilResult {
+    misc::respond(space_by_id(request))
+}
+
+fn space_by_id(request: &mut Request) -> Result<PencilResponse> {
     // Get ID from request
-    match request.view_args.get("id") {
-        Some(id) => process_id::<GenericSpace>(id),
-        None => Ok(misc::build_response(400, "{\"error\": \"The id wasn't provided\"}")),
-    }
+    let id: String = misc::view_arg(request, "id")?;
+    let fields: Vec<&str> = requested_fields(request)?;
+
+    space_response(&fenix().space(&id)?, &fields)
+}
+
+/// Path of a space, as used by `/api/path/`
+///
+/// # Fields
+/// * `path` => slugs of the spaces joined by `/`;
+/// * `spaces` => spaces from the campus to the space requested.
+#[derive(Serialize)]
+struct SpacePath {
+    path: String,
+    spaces: Vec<PathSegment>,
+}
+
+/// Handler for the path of an ID, the reverse of `path_handler`
+///
+/// The parents of the space are followed up to its campus, reading them from
+/// the snapshot and the cache when possible.
+///
+/// # Output
+/// JSON message with the path and the id, name and slug of every space in it.
+pub fn id_path_handler(request: &mut Request) -> PencilResult {
+    misc::respond(id_path(request))
+}
+
+fn id_path(request: &mut Request) -> Result<PencilResponse> {
+    let id: String = misc::view_arg(request, "id")?;
+    let spaces: Vec<PathSegment> = fenix().path_of(&id)?;
+
+    let path: String = {
+        let slugs: Vec<&str> = spaces.iter().map(|space| space.slug.as_str()).collect();
+        slugs.join("/")
+    };
+    misc::json_response(200,
+                        &SpacePath {
+                            path: path,
+                            spaces: spaces,
+                        })
 }
 
 /// Translate the ID's to names
@@ -127,87 +202,335 @@ pub fn id_handler(request: &mut Request) -> PencilResult {
 /// This handler translates id's to names letting you browse the spaces API
 /// hierarchically. Previously `/api/id/<id>` and now
 /// `/api/path/level1/level2/level3`. Keep in mind that by increasing the amount
-/// of levels in the path the more GET requests are made. The response time is
-/// now dependent on the responsiveness of the `FenixEDU` API. Also this might
-/// cause a mini DDOS.
+/// of levels in the path the more GET requests are made. The spaces are kept
+/// in the `SpaceCache` of the client, so only the levels missing from the
+/// cache reach the `FenixEDU` API. The `fields` query argument works as in
+/// `id_handler`.
 ///
 /// # Output
 /// JSON message with the contents of the requested space.
 pub fn path_handler(request: &mut Request) -> PencilResult {
-    let path: String = match request.view_args.get("my_path") {
-        Some(path) => path.to_owned(),
-        None => {
-            return Ok(misc::build_response(400, "{\"error\": \"No path provided\"}"));
-        }
+    misc::respond(resolve_path(request))
+}
+
+/// Walk the path in the request from the top level spaces
+fn resolve_path(request: &mut Request) -> Result<PencilResponse> {
+    let path: String = misc::view_arg(request, "my_path")?;
+    let points: Vec<&str> = path.split('/').filter(|point| !point.is_empty()).collect();
+    let fields: Vec<&str> = requested_fields(request)?;
+
+    // Search for the path in FenixEDU API
+    let my_space: Space = fenix().resolve_path(&points)?;
+
+    space_response(&my_space, &fields)
+}
+
+/// Events scheduled in a space during a day
+///
+/// # Fields
+/// * `id` => id of the space;
+/// * `day` => day of the events, `dd/mm/yyyy`;
+/// * `events` => events of the day, first to start first;
+/// * `current` => event taking place now, if any.
+#[derive(Serialize)]
+struct SpaceSchedule {
+    id: String,
+    day: Day,
+    events: Vec<ScheduledEvent>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    current: Option<ScheduledEvent>,
+}
+
+/// Handler for the schedule of an ID
+///
+/// The `day` query argument, written `dd/mm/yyyy`, selects the day; today in
+/// Lisbon by default. The events are the lessons, evaluations and generic
+/// events `FenixEDU` has for the space, with UNIX times.
+///
+/// # Output
+/// JSON message with the events of the day and the one taking place now.
+pub fn id_schedule_handler(request: &mut Request) -> PencilResult {
+    misc::respond(id_schedule(request))
+}
+
+fn id_schedule(request: &mut Request) -> Result<PencilResponse> {
+    let id: String = misc::view_arg(request, "id")?;
+
+    schedule_response(request, id)
+}
+
+/// Handler for the schedule of a path, as `id_schedule_handler`
+pub fn path_schedule_handler(request: &mut Request) -> PencilResult {
+    misc::respond(path_schedule(request))
+}
+
+fn path_schedule(request: &mut Request) -> Result<PencilResponse> {
+    let path: String = misc::view_arg(request, "my_path")?;
+    let points: Vec<&str> = path.split('/').filter(|point| !point.is_empty()).collect();
+
+    let space: Space = fenix().resolve_path(&points)?;
+    schedule_response(request, space.id)
+}
+
+/// Build the response with the schedule of the space `id` for the day in the
+/// `day` query argument
+fn schedule_response(request: &mut Request, id: String) -> Result<PencilResponse> {
+    let day: Day = match misc::query_arg(request, "day") {
+        Some(day) => Day::parse(&day)?,
+        None => Day::today(),
     };
+    let events: Vec<ScheduledEvent> = fenix().schedule(&id, day)?;
 
-    // Get all spaces from Fenix
-    let mut get_response: HyperResponse = match getters::get_spaces_from_id("") {
-        Ok(response) => response,
-        Err(err) => {
-            return Ok(misc::build_response(500, &format!("{{ \"error\": \"{}\" }}", err.desc)));
-        }
+    let now: u64 = utils::unix_time();
+    let current: Option<ScheduledEvent> = events.iter().find(|event| event.is_active(now)).cloned();
+    misc::json_response(200,
+                        &SpaceSchedule {
+                            id: id,
+                            day: day,
+                            events: events,
+                            current: current,
+                        })
+}
+
+/// Result of a search
+#[derive(Serialize)]
+struct SearchResults {
+    query: String,
+    hits: Vec<Hit>,
+}
+
+/// Search the spaces by name
+///
+/// The `q` query argument is looked up in the names of every space of the
+/// tree, tolerating accents, case, partial words and typos. The hits are
+/// ranked from the exact matches to the fuzzy ones; `limit` sets how many are
+/// returned, 20 by default and 100 at most.
+///
+/// # Output
+/// JSON message with the hits, each with its path, type and whether it is a
+/// room.
+pub fn search_handler(request: &mut Request) -> PencilResult {
+    misc::respond(search_spaces(request))
+}
+
+fn search_spaces(request: &mut Request) -> Result<PencilResponse> {
+    let query: String = match misc::query_arg(request, "q") {
+        Some(query) => query,
+        None => return Err(Error::Validation("q wasn't provided".to_owned())),
+    };
+    let limit: usize = match misc::query_unsigned(request, "limit")? {
+        Some(limit) => cmp::min(limit as usize, search::MAX_LIMIT),
+        None => search::DEFAULT_LIMIT,
     };
 
-    let spaces: Space;
-    let buffer: String = if get_response.status == StatusCode::Ok {
-        match utils::read_response_body(&mut get_response) {
-            Ok(buf) => buf,
-            Err(err) => {
-                return Ok(misc::build_response(500, &format!("{{ \"error\": \"{}\" }}", err)));
+    let hits: Vec<Hit> = search_index()?.search(&query, limit)?;
+    misc::json_response(200,
+                        &SearchResults {
+                            query: query,
+                            hits: hits,
+                        })
+}
+
+/// Rooms free at a given time
+#[derive(Serialize)]
+struct Availability {
+    at: u64,
+    rooms: Vec<AvailableRoom>,
+}
+
+/// Find the rooms where a user can sit
+///
+/// The rooms are those under the `path` query argument, or every room, with
+/// at least `min_capacity` seats. A room is available when fewer users are
+/// checked in than its capacity and `FenixEDU` has no event scheduled in it at
+/// `at`, a UNIX time, now by default and capped at `MAX_TIME`. Rooms without
+/// a capacity are left out.
+/// `limit` sets how many rooms are found, 20 by default and 100 at most.
+///
+/// # Output
+/// JSON message with the rooms, most free seats first, with their occupancy,
+/// free seats and the start of their next event.
+pub fn available_handler(request: &mut Request) -> PencilResult {
+    misc::respond(available_rooms(request))
+}
+
+fn available_rooms(request: &mut Request) -> Result<PencilResponse> {
+    let under: Option<String> = match misc::query_arg(request, "path") {
+        Some(path) => Some(misc::space_at(&path)?.id),
+        None => None,
+    };
+    let query = AvailabilityQuery {
+        under: under,
+        min_capacity: misc::query_unsigned(request, "min_capacity")?.unwrap_or(0),
+        at: cmp::min(misc::query_unsigned(request, "at")?.unwrap_or_else(utils::unix_time),
+                     MAX_TIME),
+        limit: match misc::query_unsigned(request, "limit")? {
+            Some(limit) => cmp::min(limit as usize, availability::MAX_LIMIT),
+            None => availability::DEFAULT_LIMIT,
+        },
+    };
+
+    let rooms: Vec<AvailableRoom> = availability::find(&*fenix(), &*store(), &query)?;
+    misc::json_response(200,
+                        &Availability {
+                            at: query.at,
+                            rooms: rooms,
+                        })
+}
+
+/// Counters of the `FenixEDU` space cache
+///
+/// # Output
+/// JSON message with the hits, misses, size and capacity of the cache.
+pub fn cache_stats_handler(_: &mut Request) -> PencilResult {
+    let stats: CacheStats = match fenix().cache() {
+        Some(cache) => cache.stats(),
+        None => {
+            CacheStats {
+                hits: 0,
+                misses: 0,
+                size: 0,
+                capacity: 0,
             }
         }
-    } else {
-        "{\"error\": \"Fenix had an error\"}".to_owned()
     };
 
-    spaces = match utils::from_json_to_obj(&buffer) {
-        Ok(obj) => obj,
-        Err(err) => {
-            return Ok(misc::build_response(500, &format!("{{ \"error\": \"{}\" }}", err)));
-        }
+    misc::respond(misc::json_response(200, &stats))
+}
+
+// /////////////////////////////////////////////////////////////////////////////
+// Authentication
+// /////////////////////////////////////////////////////////////////////////////
+
+/// Reject requests carrying an invalid bearer token
+///
+/// Registered with `Pencil::before_request`. Requests without a token go
+/// through, the handlers that need an identity ask for it with
+/// `misc::identity()`.
+///
+/// # Return Value
+/// `None` to continue with the handler or a 401 response.
+pub fn authenticate(request: &mut Request) -> Option<PencilResult> {
+    if !request.headers().has::<Authorization<Bearer>>() {
+        return None;
+    }
+
+    match misc::identity(request) {
+        Ok(_) => None,
+        Err(err) => Some(misc::respond(Err(err))),
+    }
+}
+
+/// User acting in a check in or check out: the `user_id` in the body or the
+/// caller when it is missing.
+///
+/// # Return Value
+/// The user id or an `Error::Forbidden` when a regular user acts for someone
+/// else.
+fn acting_user(obj: &Map<String, Value>, identity: &Identity) -> Result<String> {
+    let user_id: String = match obj.get("user_id") {
+        Some(_) => misc::get_str(obj, "user_id")?.to_owned(),
+        None => identity.user_id.clone(),
     };
 
-    let mut contained_spaces: Space = spaces;
-    let mut my_space: GenericSpace = Default::default();
+    identity.authorize_user(&user_id)?;
+    Ok(user_id)
+}
 
-    // Search for the path in FenixEDU API
-    for point in path.split('/') {
-        // Send a GET request to Fenix and convert the response into an object
-        my_space = match getters::search_contained_spaces(point, &contained_spaces) {
-            Ok(result) => {
-                let body: String = match result {
-                    SearchResult::Ok(body) => body,
-                    SearchResult::NotFound(msg) => {
-                        return Ok(misc::build_response(404,
-                                                       &format!("{{\"error\": \"{}\"}}", msg)));
-                    }
-                    SearchResult::Error(msg) => {
-                        return Ok(misc::build_response(503,
-                                                       &format!("{{\"error\": \"{}\"}}", msg)));
-                    }
-                };
-
-                match utils::from_json_to_obj(&body) {
-                    Ok(spaces) => spaces,
-                    Err(err) => {
-                        return Ok(misc::build_response(500,
-                                                       &format!("{{ \"error\": \"{}\" }}", err)));
-                    }
-                }
-            }
-            Err(err) => {
-                return Ok(misc::build_response(500, &format!("{{ \"error\": \"{}\" }}", err.desc)));
-            }
-        };
+/// Cookie keeping the `state` of a login in the browser that started it
+const STATE_COOKIE: &'static str = "fenix_rooms_login_state";
+
+/// `Set-Cookie` header storing `state` for the login callback, or removing
+/// it when `max_age` is 0
+fn state_cookie(state: &str, max_age: u64) -> Vec<u8> {
+    format!("{}={}; Max-Age={}; Path=/api/login; HttpOnly; SameSite=Lax",
+            STATE_COOKIE,
+            state,
+            max_age)
+        .into_bytes()
+}
+
+/// Start the `FenixEDU` login
+///
+/// # Output
+/// A redirect to the `FenixEDU` login page, setting the cookie with the
+/// `state` of the login.
+pub fn login_handler(_: &mut Request) -> PencilResult {
+    let state: String = auth().issue_state();
+
+    match oauth().and_then(|oauth| oauth.authorize_url(&state)) {
+        Ok(url) => {
+            let mut response: PencilResponse = redirect(&url, 302)?;
+            response.headers.set_raw("Set-Cookie", vec![state_cookie(&state, STATE_TTL)]);
+            Ok(response)
+        }
+        Err(err) => misc::respond(Err(err)),
+    }
+}
+
+/// End the `FenixEDU` login
+///
+/// `FenixEDU` redirects here with the authorization `code` and the `state`
+/// issued by `login_handler`, which must match the cookie it set. The
+/// `FenixEDU` person is mapped to the local user with the same username,
+/// which is created on the first login. Once the login is configured, users
+/// can't pick their username through `/api/users` anymore.
+///
+/// # Output
+/// JSON message with the user and its bearer token.
+pub fn login_callback_handler(request: &mut Request) -> PencilResult {
+    misc::respond(login_callback(request))
+}
 
-        contained_spaces = my_space.contained_spaces.clone(); // <= hate this
+fn login_callback(request: &mut Request) -> Result<PencilResponse> {
+    if let Some(err) = misc::query_arg(request, "error") {
+        return Err(Error::Unauthorized(format!("The FenixEDU login failed: {}", err)));
     }
+    let state: String = misc::query_arg(request, "state")
+        .ok_or_else(|| Error::Validation("The state wasn't provided".to_owned()))?;
+    let code: String = misc::query_arg(request, "code")
+        .ok_or_else(|| Error::Validation("The code wasn't provided".to_owned()))?;
+
+    let cookie: Option<String> = misc::cookie(request, STATE_COOKIE);
+    auth().verify_state(&state, cookie.as_ref().map(|cookie| &cookie[..]))?;
+
+    let oauth = oauth()?;
+    let access_token: AccessToken = oauth.exchange_code(&code)?;
+    let person: Person = oauth.person(&access_token.access_token)?;
+
+    let store = store();
+    let user: User = match store.find_user(&person.username) {
+        Err(Error::NotFound(_)) => store.create_user(&person.username)?,
+        result => result?,
+    };
+
+    let mut response: PencilResponse = misc::json_response(200, &Registration::new(user))?;
+    response.headers.set_raw("Set-Cookie", vec![state_cookie("", 0)]);
+    Ok(response)
+}
 
-    // Convert Object to JSON
-    match utils::from_obj_to_json(&my_space) {
-        Ok(json) => Ok(misc::build_response(200, &json)),
-        Err(err) => Ok(misc::build_response(500, &format!("{{ \"error\": \"{}\" }}", err))),
+/// Body of the response to the creation or login of a user
+#[derive(Serialize)]
+struct Registration {
+    id: String,
+    username: String,
+    role: Role,
+    token: String,
+    key: String,
+}
+
+impl Registration {
+    /// Issue a token and a login key for `user`
+    fn new(user: User) -> Registration {
+        let auth = auth();
+
+        Registration {
+            role: auth.role_of(&user),
+            token: auth.issue(&user),
+            key: auth.issue_key(&user),
+            id: user.id,
+            username: user.username,
+        }
     }
 }
 
@@ -215,371 +538,360 @@ pub fn path_handler(request: &mut Request) -> PencilResult {
 // Database Requests Handling
 // /////////////////////////////////////////////////////////////////////////////
 
-/// Create an entity in `url` with `body`
+/// Creates a User in the Database
 ///
-/// To create an entity a POST request is sent to the database. Then the status
-/// of the response is checked. If the result is Ok the contents of the body are
-/// read and passed along to the client. Otherwise one of three things can
-/// happen. First, the object may already exist, an entity might be created that
-/// doesn't exist in the `FenixEDU` API or the database is down.
-/// Error messages and status codes are sent apropriately.
+/// Create a user in the database with the specified `username` in the body.
+/// The usernames of `Config::admin_users` also need the `admin_secret`. When
+/// the `FenixEDU` login is configured, users are only created by logging in,
+/// so nobody can take the username of a `FenixEDU` person.
 ///
 /// # Arguments
-/// * `url` => the url where the entity will be created
-/// * `body` => the body of the request
+/// * `request` - The request sent by the client
 ///
-/// # Return Value
-/// The JSON message processed or an error.
-fn create_entity(url: &str, body: &str) -> PencilResult {
-    let mut response: HyperResponse = match utils::post_request(url, body) {
-        Ok(response) => response,
-        Err(err) => {
-            return Ok(misc::build_response(500, &format!("{{ \"error\": \"{}\" }}", err)));
-        }
-    };
-
-    let buffer: String;
-    let status_code: u16;
+/// # Output
+/// A Response with a JSON messsage and correct status code.
+pub fn create_user_handler(request: &mut Request) -> PencilResult {
+    misc::respond(create_user(request))
+}
 
-    if response.status == StatusCode::Ok || response.status == StatusCode::Created {
-        status_code = 200;
-        buffer = match utils::read_response_body(&mut response) {
-            Ok(buffer) => buffer,
-            Err(err) => {
-                return Ok(misc::build_response(500, &format!("{{ \"error\": \"{}\" }}", err)));
-            }
-        };
-    } else if response.status == StatusCode::UnprocessableEntity {
-        status_code = 422;
-        buffer = match utils::read_response_body(&mut response) {
-            Ok(buffer) => buffer,
-            Err(err) => {
-                return Ok(misc::build_response(500, &format!("{{ \"error\": \"{}\" }}", err)));
-            }
-        };
-    } else if response.status == StatusCode::Conflict {
-        status_code = 409;
-        buffer = match utils::read_response_body(&mut response) {
-            Ok(buffer) => buffer,
-            Err(err) => {
-                return Ok(misc::build_response(500, &format!("{{ \"error\": \"{}\" }}", err)));
-            }
-        };
-    } else if response.status == StatusCode::NotFound {
-        status_code = 404;
-        buffer = match utils::read_response_body(&mut response) {
-            Ok(buffer) => buffer,
-            Err(err) => {
-                return Ok(misc::build_response(500, &format!("{{ \"error\": \"{}\" }}", err)));
-            }
-        };
-    } else if response.status == StatusCode::BadRequest {
-        status_code = 400;
-        buffer = match utils::read_response_body(&mut response) {
-            Ok(buffer) => buffer,
-            Err(err) => {
-                return Ok(misc::build_response(500, &format!("{{ \"error\": \"{}\" }}", err)));
-            }
-        };
-    } else {
-        status_code = 503;
-        buffer = "{\"error\": \"There is an error in the database\"}".to_owned();
+fn create_user(request: &mut Request) -> Result<PencilResponse> {
+    if oauth().is_ok() {
+        return Err(Error::Forbidden("Users are created by logging in with FenixEDU at /api/login"
+            .to_owned()));
     }
 
-    Ok(misc::build_response(status_code, &buffer))
+    // Get the username from the JSON of the request if it exists
+    let obj = misc::get_json_object(request)?;
+    let username: &str = misc::get_str(&obj, "username")?;
+    let admin_secret: Option<&str> = match obj.get("admin_secret") {
+        Some(_) => Some(misc::get_str(&obj, "admin_secret")?),
+        None => None,
+    };
+    auth().authorize_registration(username, admin_secret)?;
+
+    let user: User = store().create_user(username)?;
+    misc::json_response(200, &Registration::new(user))
 }
 
-/// Creates a User in the Database
+/// Renews the Token of a User
 ///
-/// Create a user in the database with the specified `username` in the body.
+/// Exchange the login `key` in the body, returned with every token, for a new
+/// token, so a user whose token expired or was lost can log in again. The role
+/// is the one the username has now.
 ///
 /// # Arguments
 /// * `request` - The request sent by the client
 ///
 /// # Output
 /// A Response with a JSON messsage and correct status code.
-pub fn create_user_handler(mut request: &mut Request) -> PencilResult {
-    if misc::is_content_type_json(request.headers().clone()) {
-        // Get the username from the JSON of the request if it exists
-        match misc::get_json(&mut request) {
-            Some(json) => {
-                match json.as_object() {
-                    Some(obj) => {
-                        match obj.get("username") {
-                            Some(username) => {
-                                if username.is_string() {
-                                    let url: String = format!("{}/users", DB_BASE_URL);
-                                    let body: String = format!("{{\"username\": {}}}", username);
-                                    create_entity(&url, &body)
-                                } else {
-                                    Ok(misc::build_response(400,
-                                                            "{\"error\": \"username doesn't have \
-                                                             correct type\"}"))
-                                }
-                            }
-                            None => {
-                                Ok(misc::build_response(400,
-                                                        "{\"error\": \"username wasn't \
-                                                         provided\"}"))
-                            }
-                        }
-                    }
-                    None => Ok(misc::build_response(400, "{\"error\": \"JSON isn't an object\"}")),
-                }
-            }
-            None => Ok(misc::build_response(500, "{\"error\": \"Failed to parse JSON\"}")),
-        }
-    } else {
-        Ok(misc::build_response(415, "{\"error\": \"Wrong content-type used\"}"))
-    }
+pub fn token_handler(request: &mut Request) -> PencilResult {
+    misc::respond(token(request))
+}
+
+fn token(request: &mut Request) -> Result<PencilResponse> {
+    let obj = misc::get_json_object(request)?;
+    let user: User = auth().verify_key(misc::get_str(&obj, "key")?)?;
+
+    misc::json_response(200, &Registration::new(user))
 }
 
 /// Creates a Room in the Database
 ///
-/// Create a room in the database with the specified `fenix_id`, `capacity`,
-/// `location` and `user_id` in the body. Only the admin can create rooms in
-/// the DB.
+/// Create a room in the database for the space `fenix_id` in the body. The
+/// location is the path of names of the space and the capacity its normal
+/// capacity in `FenixEDU`. A `location` or a `capacity` (a number) sent by the
+/// client is only used along with `override_location` or `override_capacity`
+/// set to `true`. Only admins can create rooms in the DB.
 ///
 /// # Arguments
 /// * `request` - The request sent by the client
 ///
 /// # Output
 /// A Response with a JSON messsage and correct status code.
-pub fn create_room_handler(mut request: &mut Request) -> PencilResult {
-    if misc::is_content_type_json(request.headers().clone()) {
-        // Get the username from the JSON of the request if it exists
-        match misc::get_json(&mut request) {
-            Some(json) => {
-                match json.as_object() {
-                    Some(obj) => {
-                        if obj.contains_key("location") && obj.contains_key("fenix_id") &&
-                           obj.contains_key("capacity") &&
-                           obj.contains_key("user_id") {
-                            // Declare and initialize variables
-                            let user_id: &str = match obj.get("user_id").unwrap().as_str() {
-                                Some(user_id) => user_id,
-                                None => "",
-                            };
-                            let fenix_id: &str = match obj.get("fenix_id").unwrap().as_str() {
-                                Some(fenix_id) => fenix_id,
-                                None => "",
-                            };
-                            let location: &str = match obj.get("location").unwrap().as_str() {
-                                Some(location) => location,
-                                None => "",
-                            };
-                            let capacity: &str = match obj.get("capacity").unwrap().as_str() {
-                                Some(capacity) => capacity,
-                                None => "",
-                            };
-
-                            if user_id.is_empty() || fenix_id.is_empty() || location.is_empty() ||
-                               capacity.is_empty() {
-                                return Ok(misc::build_response(400,
-                                                               "{\"error\": \"parameters don't \
-                                                                have correct type\"}"));
-                            }
-
-                            // Default to Unauthorized.
-                            let mut status_code: u16 = 401;
-                            let mut buffer: String =
-                                "{ \"error\": \"Unauthorized access to database\"}".to_owned();
-
-                            if user_id == "0" {
-                                let url: String = format!("{}/rooms", DB_BASE_URL);
-                                let body: String = format!("{{\"location\": \"{}\", \
-                                                            \"capacity\": \"{}\", \"fenix_id\": \
-                                                            \"{}\"}}",
-                                                           location,
-                                                           capacity,
-                                                           fenix_id);
-
-                                let room_exists: bool = match misc::is_room(fenix_id) {
-                                    Ok(room_exists) => room_exists,
-                                    Err(err) => {
-                                        return Ok(misc::build_response(500,
-                                                                       &format!("{{ \"error\": \
-                                                                                 \"{}\" }}",
-                                                                                err.desc)));
-                                    }
-                                };
-
-                                if room_exists {
-                                    return create_entity(&url, &body);
-                                } else {
-                                    status_code = 404;
-                                    buffer = "{ \"error\" : \"The provided fenix_id does not \
-                                              match a space or room in FenixEDU\"}"
-                                        .to_owned();
-                                }
-                            }
-
-                            Ok(misc::build_response(status_code, &buffer))
-                        } else {
-                            Ok(misc::build_response(400,
-                                                    "{\"error\": \"One of the necessary \
-                                                     arguments wasn't provided\"}"))
-                        }
-                    }
-                    None => Ok(misc::build_response(400, "{\"error\": \"JSON isn't an object\"}")),
-                }
-            }
-            None => Ok(misc::build_response(500, "{\"error\": \"Failed to parse JSON\"}")),
+pub fn create_room_handler(request: &mut Request) -> PencilResult {
+    misc::respond(create_room(request))
+}
+
+fn create_room(request: &mut Request) -> Result<PencilResponse> {
+    misc::identity(request)?.authorize_admin()?;
+    let obj = misc::get_json_object(request)?;
+
+    let fenix_id: &str = misc::get_str(&obj, "fenix_id")?;
+    let overrides = RoomOverrides {
+        location: if overridden(&obj, "location")? {
+            Some(misc::get_str(&obj, "location")?.to_owned())
+        } else {
+            None
+        },
+        capacity: if overridden(&obj, "capacity")? {
+            Some(misc::get_unsigned(&obj, "capacity")?)
+        } else {
+            None
+        },
+    };
+
+    let new_room: NewRoom = import::describe(&*fenix(), fenix_id, &overrides)?;
+    let room: Room = store().create_room(&new_room)?;
+    publish(EventKind::RoomCreated, &room.id, None, None);
+
+    misc::json_response(200, &room)
+}
+
+/// Check whether the field `key` of a new room is overridden by the client
+///
+/// # Return Value
+/// The value of the `override_<key>` flag, false when missing, or an
+/// `Error::Validation` when the flag isn't a boolean or `key` was sent without
+/// the flag.
+fn overridden(obj: &Map<String, Value>, key: &str) -> Result<bool> {
+    let flag: String = format!("override_{}", key);
+
+    let overridden: bool = match obj.get(&flag).map(|value| value.as_bool()) {
+        Some(Some(overridden)) => overridden,
+        Some(None) => return Err(Error::Validation(format!("{} doesn't have correct type", flag))),
+        None => false,
+    };
+    if !overridden && obj.contains_key(key) {
+        return Err(Error::Validation(format!("{} comes from FenixEDU, set {} to override it",
+                                             key,
+                                             flag)));
+    }
+
+    Ok(overridden)
+}
+
+/// A check in as sent to the client
+///
+/// # Fields
+/// * `moved_from` => room the user was checked out of to check in this one,
+///   following `Config::checkin_policy`.
+#[derive(Serialize)]
+struct CheckInReport {
+    user_id: String,
+    room_id: String,
+    checked_in_at: u64,
+    #[serde(skip_serializing_if="Option::is_none")]
+    expected_duration: Option<u64>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    moved_from: Option<String>,
+}
+
+impl CheckInReport {
+    fn new(check_in: CheckIn, moved_from: Option<String>) -> CheckInReport {
+        CheckInReport {
+            user_id: check_in.user_id,
+            room_id: check_in.room_id,
+            checked_in_at: check_in.checked_in_at,
+            expected_duration: check_in.expected_duration,
+            moved_from: moved_from,
         }
-    } else {
-        Ok(misc::build_response(415, "{\"error\": \"Wrong content-type used\"}"))
     }
 }
 
 /// Checks in in the Database
 ///
-/// The check in is performed with a `room_id` and a `user_id`. Then, a POST
-/// request is sent and its content read and sent to the client.
+/// The check in is performed with a `room_id` and a `user_id`, which are
+/// forwarded to the store. The check in created is sent to the client. The
+/// `user_id` defaults to the caller; only admins can check in other users.
+/// The check in is refused with an `Error::RoomFull` when the room already
+/// has as many users as its capacity plus `Config::capacity_overflow`. The
+/// optional `expected_duration`, in seconds and at most `MAX_DURATION`, tells
+/// when the check in expires.
+/// A user checked in another room is moved or refused according to
+/// `Config::checkin_policy`; it only leaves the other room once the new
+/// check in is made, so a refused check in leaves it where it was. With
+/// `Config::require_reservation` the user must have a reservation for the
+/// room at the time of the check in, and with `Config::block_during_classes`
+/// no lesson or evaluation may be taking place in it, as far as `FenixEDU`
+/// can tell.
 ///
 /// # Arguments
 /// * `request` - The request sent by the client
 ///
 /// # Output
 /// A Response with a JSON messsage and correct status code.
-pub fn check_in_handler(mut request: &mut Request) -> PencilResult {
-    if misc::is_content_type_json(request.headers().clone()) {
-        // Get the username from the JSON of the request if it exists
-        match misc::get_json(&mut request) {
-            Some(json) => {
-                match json.as_object() {
-                    Some(obj) => {
-                        if obj.contains_key("user_id") && obj.contains_key("room_id") {
-                            let user_id: &str = match obj.get("user_id").unwrap().as_str() {
-                                Some(user_id) => user_id,
-                                None => "",
-                            };
-                            let room_id: &str = match obj.get("room_id").unwrap().as_str() {
-                                Some(room_id) => room_id,
-                                None => "",
-                            };
-
-                            if user_id.is_empty() || room_id.is_empty() {
-                                return Ok(misc::build_response(400,
-                                                               "{\"error\": \"parameters don't \
-                                                                have correct type\"}"));
-                            }
-
-                            let url: String = format!("{}/checkins", DB_BASE_URL);
-                            let body: String = format!("{{\"user_id\": \"{}\", \"room_id\": \
-                                                        \"{}\"}}",
-                                                       user_id,
-                                                       room_id);
-                            create_entity(&url, &body)
-                        } else {
-                            Ok(misc::build_response(400,
-                                                    "{\"error\": \"One of the necessary \
-                                                     arguments wasn't provided\"}"))
-                        }
-                    }
-                    None => Ok(misc::build_response(400, "{\"error\": \"JSON isn't an object\"}")),
+pub fn check_in_handler(request: &mut Request) -> PencilResult {
+    misc::respond(check_in(request))
+}
+
+fn check_in(request: &mut Request) -> Result<PencilResponse> {
+    let identity: Identity = misc::identity(request)?;
+    let obj = misc::get_json_object(request)?;
+    let user_id: String = acting_user(&obj, &identity)?;
+    let room_id: &str = misc::get_str(&obj, "room_id")?;
+
+    let expected_duration: Option<u64> = match obj.get("expected_duration") {
+        Some(_) => Some(cmp::min(misc::get_unsigned(&obj, "expected_duration")?, MAX_DURATION)),
+        None => None,
+    };
+
+    if config().require_reservation {
+        reservations::current(&*store(), &user_id, room_id, utils::unix_time())?;
+    }
+    if config().block_during_classes {
+        check_schedule(room_id)?;
+    }
+    let limit: Option<u64> = store().room(room_id)?.capacity_limit(config().capacity_overflow);
+    let previous: Vec<CheckIn> = rooms_to_leave(&user_id, room_id)?;
+
+    let check_in = CheckIn {
+        user_id: user_id,
+        room_id: room_id.to_owned(),
+        checked_in_at: utils::unix_time(),
+        expected_duration: expected_duration,
+    };
+    let check_in: CheckIn = store().check_in_with_limit(&check_in, limit)?;
+    publish(EventKind::CheckIn, &check_in.room_id, Some(&check_in.user_id), None);
+    let moved_from: Option<String> = leave_rooms(previous);
+
+    misc::json_response(200, &CheckInReport::new(check_in, moved_from))
+}
+
+/// Apply `Config::checkin_policy` before checking the user `user_id` in the
+/// room `room_id`: find the other rooms the user must leave or refuse.
+/// Nothing is changed; the rooms are left by `leave_rooms()` once the new
+/// check in is made.
+///
+/// The policy can't be applied by a store unable to list the check-ins, in
+/// which case the user may stay checked in several rooms.
+///
+/// # Return Value
+/// The check-ins of the user in other rooms, an `Error::Conflict` when the
+/// user is already in a room and can't leave it or the error of the store.
+fn rooms_to_leave(user_id: &str, room_id: &str) -> Result<Vec<CheckIn>> {
+    let check_ins: Vec<CheckIn> = match store().check_ins_of(user_id) {
+        Err(Error::Unsupported(_)) => return Ok(Vec::new()),
+        result => result?,
+    };
+
+    let previous: String = match check_ins.first() {
+        Some(check_in) => check_in.room_id.clone(),
+        None => return Ok(check_ins),
+    };
+    if check_ins.iter().any(|check_in| check_in.room_id == room_id) {
+        return Err(Error::Conflict(format!("The user {} is already checked in the room {}",
+                                           user_id,
+                                           room_id)));
+    }
+    if config().checkin_policy == CheckInPolicy::Reject {
+        return Err(Error::Conflict(format!("The user {} is already checked in the room {}, \
+                                            check out first",
+                                           user_id,
+                                           previous)));
+    }
+
+    Ok(check_ins)
+}
+
+/// Check the user out of the rooms of `check_ins` with the reason `moved`
+///
+/// The new check in is already made, so a check out failing is only logged
+/// and one closed in the meantime is skipped.
+///
+/// # Return Value
+/// The first room left, if any.
+fn leave_rooms(check_ins: Vec<CheckIn>) -> Option<String> {
+    let mut moved_from: Option<String> = None;
+
+    for check_in in check_ins {
+        let check_out = CheckOut {
+            user_id: check_in.user_id.clone(),
+            room_id: check_in.room_id.clone(),
+            checked_out_at: utils::unix_time(),
+            reason: CheckOutReason::Moved,
+        };
+        match store().check_out(&check_out) {
+            Ok(()) => {
+                publish(EventKind::CheckOut,
+                        &check_in.room_id,
+                        Some(&check_in.user_id),
+                        Some(CheckOutReason::Moved));
+                if moved_from.is_none() {
+                    moved_from = Some(check_in.room_id);
                 }
             }
-            None => Ok(misc::build_response(500, "{\"error\": \"Failed to parse JSON\"}")),
+            Err(Error::NotFound(_)) => {}
+            Err(err) => {
+                println!("Moving the user {} out of the room {} failed: {}",
+                         check_in.user_id,
+                         check_in.room_id,
+                         err)
+            }
         }
-    } else {
-        Ok(misc::build_response(415, "{\"error\": \"Wrong content-type used\"}"))
+    }
+
+    moved_from
+}
+
+/// Check that `FenixEDU` schedules no lesson or evaluation in the room
+/// `room_id` now
+///
+/// A schedule `FenixEDU` can't give doesn't block the check in, unless
+/// `Config::block_on_schedule_error` is set.
+///
+/// # Return Value
+/// Nothing, an `Error::Conflict` with the class taking place, the error of
+/// the store or, with `Config::block_on_schedule_error`, the error of
+/// `FenixEDU`.
+fn check_schedule(room_id: &str) -> Result<()> {
+    let room: Room = store().room(room_id)?;
+    let now: u64 = utils::unix_time();
+
+    let events: Vec<ScheduledEvent> = match fenix().schedule(&room.fenix_id, Day::of(now)) {
+        Ok(events) => events,
+        Err(err) => {
+            if config().block_on_schedule_error {
+                return Err(err);
+            }
+            println!("Schedule of the room {} unknown, check in accepted: {}", room_id, err);
+            return Ok(());
+        }
+    };
+    match events.iter().find(|event| event.kind.is_class() && event.is_active(now)) {
+        Some(event) => {
+            Err(Error::Conflict(format!("The room {} has a {} scheduled until {}",
+                                        room_id,
+                                        event.kind.name().to_lowercase(),
+                                        event.end)))
+        }
+        None => Ok(()),
     }
 }
 
 /// Checks out in the Database
 ///
-/// The check out is performed with a `room_id` and a `user_id`. Then, a DELETE
-/// request is sent and its content read and sent to the client.
+/// The check out is performed with a `room_id` and a `user_id`, which are
+/// forwarded to the store. The response has no content. The `user_id`
+/// defaults to the caller; only admins can check out other users.
 ///
 /// # Arguments
 /// * `request` - The request sent by the client
 ///
 /// # Output
 /// A Response with a JSON messsage and correct status code.
-pub fn check_out_handler(mut request: &mut Request) -> PencilResult {
-    if misc::is_content_type_json(request.headers().clone()) {
-        // Get the username from the JSON of the request if it exists
-        match misc::get_json(&mut request) {
-            Some(json) => {
-                match json.as_object() {
-                    Some(obj) => {
-                        if obj.contains_key("user_id") && obj.contains_key("room_id") {
-                            let user_id: &str = match obj.get("user_id").unwrap().as_str() {
-                                Some(user_id) => user_id,
-                                None => "",
-                            };
-                            let room_id: &str = match obj.get("room_id").unwrap().as_str() {
-                                Some(room_id) => room_id,
-                                None => "",
-                            };
-
-                            let status_code: u16;
-                            let buffer: String;
-
-                            if room_id.is_empty() || user_id.is_empty() {
-                                status_code = 400;
-                                buffer = "{\"error\": \"parameters don't \
-                                          have correct type\"}"
-                                    .to_owned();
-                            } else {
-                                let url: String = format!("{}/checkins", DB_BASE_URL);
-                                let body: String = format!("{{\"user_id\": \"{}\", \"room_id\": \
-                                                            \"{}\"}}",
-                                                           user_id,
-                                                           room_id);
-
-                                let mut response: HyperResponse =
-                                    match utils::delete_request(&url, &body) {
-                                        Ok(response) => response,
-                                        Err(err) => {
-                                            return Ok(misc::build_response(500,
-                                                                           &format!("{{ \"error\": \
-                                                                                     \"{}\" }}",
-                                                                                    err)));
-                                        }
-                                    };
-
-                                // Deleting returns Ok or NoContent with no body
-                                if response.status == StatusCode::NoContent ||
-                                   response.status == StatusCode::Ok {
-                                    status_code = 200;
-                                    buffer = "".to_owned();
-                                } else if response.status == StatusCode::NotFound {
-                                    // The resource asked to delete was not found
-                                    status_code = 404;
-                                    buffer = match utils::read_response_body(&mut response) {
-                                        Ok(buffer) => buffer,
-                                        Err(err) => {
-                                            return Ok(misc::build_response(500,
-                                                                           &format!("{{ \"error\": \
-                                                                                     \"{}\" }}",
-                                                                                    err)));
-                                        }
-                                    };
-                                } else {
-                                    // The database had an error
-                                    status_code = 503;
-                                    buffer = "{\"error\": \"There is an error in the database\"}"
-                                        .to_owned();
-                                }
-                            }
-
-                            Ok(misc::build_response(status_code, &buffer))
-                        } else {
-                            Ok(misc::build_response(400,
-                                                    "{\"error\": \"One of the necessary \
-                                                     arguments wasn't provided\"}"))
-                        }
-                    }
-                    None => Ok(misc::build_response(400, "{\"error\": \"JSON isn't an object\"}")),
-                }
-            }
-            None => Ok(misc::build_response(500, "{\"error\": \"Failed to parse JSON\"}")),
-        }
-    } else {
-        Ok(misc::build_response(415, "{\"error\": \"Wrong content-type used\"}"))
-    }
+pub fn check_out_handler(request: &mut Request) -> PencilResult {
+    misc::respond(check_out(request))
+}
+
+fn check_out(request: &mut Request) -> Result<PencilResponse> {
+    let identity: Identity = misc::identity(request)?;
+    let obj = misc::get_json_object(request)?;
+    let user_id: String = acting_user(&obj, &identity)?;
+    let room_id: &str = misc::get_str(&obj, "room_id")?;
 
+    let check_out = CheckOut {
+        user_id: user_id,
+        room_id: room_id.to_owned(),
+        checked_out_at: utils::unix_time(),
+        reason: CheckOutReason::User,
+    };
+    store().check_out(&check_out)?;
+    publish(EventKind::CheckOut,
+            &check_out.room_id,
+            Some(&check_out.user_id),
+            Some(check_out.reason));
+
+    Ok(misc::build_response(200, ""))
 }
 
 /// Gets the list of rooms in the Database
@@ -587,82 +899,433 @@ pub fn check_out_handler(mut request: &mut Request) -> PencilResult {
 /// # Output
 /// A Response with a JSON messsage and correct status code.
 pub fn rooms_handler(_: &mut Request) -> PencilResult {
-    let url: String = format!("{}/rooms", DB_BASE_URL);
+    misc::respond(store().list_rooms().and_then(|rooms| misc::json_response(200, &rooms)))
+}
 
-    let mut response: HyperResponse = match utils::get_request(&url) {
-        Ok(response) => response,
-        Err(err) => {
-            return Ok(misc::build_response(500, &format!("{{ \"error\": \"{}\" }}", err)));
+/// Handler for IDs using the database. The id sent in the url will be processed.
+///
+/// # Arguments
+/// * `id` => id to process
+///
+/// # Return Value
+/// Read the contents and send it as JSON.
+pub fn check_in_get_handler(request: &mut Request) -> PencilResult {
+    // Get ID from request
+    misc::respond(misc::view_arg(request, "room_id")
+        .and_then(|id| store().occupants(&id))
+        .and_then(|users| misc::json_response(200, &users)))
+}
+
+// /////////////////////////////////////////////////////////////////////////////
+// Events
+// /////////////////////////////////////////////////////////////////////////////
+
+/// Streams the events of a room
+///
+/// The response is a Server-Sent Events stream of the check-ins, check-outs
+/// and capacity changes of the room, with its occupancy after each of them.
+///
+/// # Arguments
+/// * `request` - The request sent by the client
+///
+/// # Output
+/// A `text/event-stream` Response or a JSON error, with the status 503 when
+/// too many streams are open.
+pub fn room_events_handler(request: &mut Request) -> PencilResult {
+    misc::respond(room_events(request))
+}
+
+fn room_events(request: &mut Request) -> Result<PencilResponse> {
+    let room_id: String = misc::view_arg(request, "room_id")?;
+    store().room(&room_id)?;
+    let slot = sse::StreamSlot::acquire()?;
+
+    let receiver = events().subscribe(Box::new(move |event: &Event| event.room_id == room_id));
+    Ok(sse::response(slot, receiver))
+}
+
+/// Streams the events of every room
+///
+/// # Output
+/// A `text/event-stream` Response or a JSON error when too many streams are
+/// open.
+pub fn events_handler(_: &mut Request) -> PencilResult {
+    misc::respond(sse::StreamSlot::acquire()
+        .map(|slot| sse::response(slot, events().subscribe(Box::new(|_: &Event| true)))))
+}
+
+// /////////////////////////////////////////////////////////////////////////////
+// Reservations
+// /////////////////////////////////////////////////////////////////////////////
+
+/// Books a room
+///
+/// The reservation is made with a `room_id`, a `start` and an `end` in UNIX
+/// time. The `user_id` defaults to the caller; only admins can book for other
+/// users. It is refused when the user has another reservation at the same
+/// time or when the room is fully booked.
+///
+/// # Arguments
+/// * `request` - The request sent by the client
+///
+/// # Output
+/// A Response with a JSON messsage and correct status code.
+pub fn create_reservation_handler(request: &mut Request) -> PencilResult {
+    misc::respond(create_reservation(request))
+}
+
+fn create_reservation(request: &mut Request) -> Result<PencilResponse> {
+    let identity: Identity = misc::identity(request)?;
+    let obj = misc::get_json_object(request)?;
+
+    let new_reservation = NewReservation {
+        user_id: acting_user(&obj, &identity)?,
+        room_id: misc::get_str(&obj, "room_id")?.to_owned(),
+        start: misc::get_unsigned(&obj, "start")?,
+        end: misc::get_unsigned(&obj, "end")?,
+        created_at: utils::unix_time(),
+    };
+
+    let limit: Option<u64> = store().room(&new_reservation.room_id)?.capacity_limit(0);
+
+    let reservation: Reservation = store().create_reservation_checked(&new_reservation, limit)?;
+    misc::json_response(200, &reservation)
+}
+
+/// Lists the active reservations
+///
+/// The `room_id` and `user_id` query arguments select the reservations of a
+/// room or of a user, and `from` and `to`, in UNIX time, the period. By
+/// default the reservations not over yet are sent. Users only see their own
+/// reservations; admins see everyone's.
+///
+/// # Arguments
+/// * `request` - The request sent by the client
+///
+/// # Output
+/// A Response with a JSON messsage and correct status code.
+pub fn reservations_handler(request: &mut Request) -> PencilResult {
+    misc::respond(list_reservations(request))
+}
+
+fn list_reservations(request: &mut Request) -> Result<PencilResponse> {
+    let identity: Identity = misc::identity(request)?;
+
+    let user_id: Option<String> = match misc::query_arg(request, "user_id") {
+        Some(user_id) => {
+            identity.authorize_user(&user_id)?;
+            Some(user_id)
         }
+        None if identity.is_admin() => None,
+        None => Some(identity.user_id.clone()),
+    };
+    let query = ReservationQuery {
+        room_id: misc::query_arg(request, "room_id"),
+        user_id: user_id,
+        from: misc::query_unsigned(request, "from")?.unwrap_or_else(utils::unix_time),
+        to: misc::query_unsigned(request, "to")?.unwrap_or(u64::max_value()),
     };
 
-    let buffer: String;
-    let status_code: u16;
+    let reservations: Vec<Reservation> = store().reservations(&query)?;
+    misc::json_response(200, &reservations)
+}
 
-    if response.status == StatusCode::Ok {
-        buffer = match utils::read_response_body(&mut response) {
-            Ok(buffer) => buffer,
-            Err(err) => {
-                return Ok(misc::build_response(500, &format!("{{ \"error\": \"{}\" }}", err)));
-            }
-        };
-        status_code = 200;
-    } else {
-        status_code = 503;
-        buffer = "{\"error\": \"There is an error in the database\"}".to_owned();
+/// Cancels a reservation
+///
+/// Users can cancel their own reservations; admins can cancel any. The
+/// cancelled reservation is sent to the client.
+///
+/// # Arguments
+/// * `request` - The request sent by the client
+///
+/// # Output
+/// A Response with a JSON messsage and correct status code.
+pub fn cancel_reservation_handler(request: &mut Request) -> PencilResult {
+    misc::respond(cancel_reservation(request))
+}
+
+fn cancel_reservation(request: &mut Request) -> Result<PencilResponse> {
+    let identity: Identity = misc::identity(request)?;
+    let id: String = misc::view_arg(request, "reservation_id")?;
+
+    let reservation: Reservation = store().reservation(&id)?;
+    identity.authorize_user(&reservation.user_id)?;
+
+    let reservation: Reservation = store().cancel_reservation(&id, utils::unix_time())?;
+    misc::json_response(200, &reservation)
+}
+
+// /////////////////////////////////////////////////////////////////////////////
+// History and Analytics
+// /////////////////////////////////////////////////////////////////////////////
+
+/// Seconds covered by the history and the statistics when `from` is missing
+const DEFAULT_PERIOD: u64 = 24 * 60 * 60;
+
+/// Latest UNIX time of a query, the end of the year 9999
+const MAX_TIME: u64 = 253402300800;
+
+/// Read the `from` and `to` query arguments, in UNIX time. `to` defaults to
+/// now, and is capped at `MAX_TIME`, and `from` to `DEFAULT_PERIOD` before
+/// `to`.
+fn period(request: &mut Request) -> Result<(u64, u64)> {
+    let to: u64 = cmp::min(misc::query_unsigned(request, "to")?.unwrap_or_else(utils::unix_time),
+                           MAX_TIME);
+    let from: u64 = misc::query_unsigned(request, "from")?
+        .unwrap_or_else(|| to.saturating_sub(DEFAULT_PERIOD));
+
+    if from >= to {
+        return Err(Error::Validation("from must be before to".to_owned()));
     }
 
-    Ok(misc::build_response(status_code, &buffer))
+    Ok((from, to))
 }
 
-/// Handler for IDs using the database. The id sent in the url will be processed.
+/// Gets the visits of a room
+///
+/// The visits, closed or not, overlapping the period between the `from` and
+/// `to` query arguments are sent, oldest first. Admins only.
 ///
 /// # Arguments
-/// * `id` => id to process
+/// * `request` - The request sent by the client
 ///
-/// # Return Value
-/// Read the contents and send it as JSON.
-pub fn check_in_get_handler(request: &mut Request) -> PencilResult {
-    // Get ID from request
-    match request.view_args.get("room_id") {
-        Some(id) => {
-            let url: String = format!("{}/checkins/{}", DB_BASE_URL, id);
-
-            let mut get_response: HyperResponse = match utils::get_request(&url) {
-                Ok(response) => response,
-                Err(err) => {
-                    return Ok(misc::build_response(500,
-                                                   &format!("{{ \"error\": \"{}\" }}", err)));
-                }
-            };
-
-            let buffer: String;
-            let status_code: u16;
-
-            println!("Status: {}", get_response.status);
-
-            // If the GET request is successful read the body and process the request
-            if get_response.status == StatusCode::Ok {
-                buffer = match utils::read_response_body(&mut get_response) {
-                    Ok(buf) => buf,
-                    Err(err) => {
-                        return Ok(misc::build_response(500,
-                                                       &format!("{{ \"error\": \"{}\" }}", err)));
-                    }
-                };
-                status_code = 200;
-            } else if get_response.status == StatusCode::NotFound {
-                // When the id is not valid warn the user
-                status_code = 404;
-                buffer = format!("{{\"error\": \"The room id: {} was not found\"}}", id);
-            } else {
-                // When the `FenixEDU` servers are down warn the user
-                status_code = 503;
-                buffer = "{\"error\": \"Database had an error\"}".to_owned();
-            }
+/// # Output
+/// A Response with a JSON messsage and correct status code.
+pub fn room_history_handler(request: &mut Request) -> PencilResult {
+    misc::respond(room_history(request))
+}
+
+fn room_history(request: &mut Request) -> Result<PencilResponse> {
+    misc::identity(request)?.authorize_admin()?;
+    let room_id: String = misc::view_arg(request, "room_id")?;
+    let (from, to) = period(request)?;
+
+    let visits: Vec<Visit> = store().visits(Some(&room_id), from, to)?;
+    misc::json_response(200, &visits)
+}
+
+/// Gets the occupancy of every room
+///
+/// The report covers the period between the `from` and `to` query arguments
+/// and, when `granularity` is `hour` or `day`, each hour or day of it. Admins
+/// only, like the history it is computed from.
+///
+/// # Arguments
+/// * `request` - The request sent by the client
+///
+/// # Output
+/// A Response with a JSON messsage and correct status code.
+pub fn occupancy_stats_handler(request: &mut Request) -> PencilResult {
+    misc::respond(occupancy_stats(request))
+}
+
+fn occupancy_stats(request: &mut Request) -> Result<PencilResponse> {
+    misc::identity(request)?.authorize_admin()?;
+    let (from, to) = period(request)?;
+    let granularity: Option<Granularity> = match misc::query_arg(request, "granularity") {
+        Some(name) => Some(Granularity::from_name(&name)?),
+        None => None,
+    };
+
+    let rooms: Vec<Room> = store().list_rooms()?;
+    let visits: Vec<Visit> = store().visits(None, from, to)?;
+    let report: OccupancyReport =
+        stats::report(&rooms, &visits, from, to, granularity, utils::unix_time())?;
+
+    misc::json_response(200, &report)
+}
+
+// /////////////////////////////////////////////////////////////////////////////
+// Administration
+// /////////////////////////////////////////////////////////////////////////////
+
+/// Percentage of users accepted in a room above its capacity
+#[derive(Serialize)]
+struct Overflow {
+    capacity_overflow: u64,
+}
+
+/// Gets the percentage of users accepted in a room above its capacity
+///
+/// # Output
+/// A Response with a JSON messsage and correct status code.
+pub fn capacity_overflow_handler(_: &mut Request) -> PencilResult {
+    misc::respond(misc::json_response(200,
+                                      &Overflow { capacity_overflow: config().capacity_overflow }))
+}
+
+/// Changes the percentage of users accepted in a room above its capacity
+///
+/// The new percentage is the `capacity_overflow` in the body. It applies to
+/// the next check-ins and lasts until the server restarts. Admins only.
+///
+/// # Arguments
+/// * `request` - The request sent by the client
+///
+/// # Output
+/// A Response with a JSON messsage and correct status code.
+pub fn set_capacity_overflow_handler(request: &mut Request) -> PencilResult {
+    misc::respond(set_capacity_overflow(request))
+}
+
+fn set_capacity_overflow(request: &mut Request) -> Result<PencilResponse> {
+    misc::identity(request)?.authorize_admin()?;
+    let obj = misc::get_json_object(request)?;
+    let overflow: u64 = misc::get_unsigned(&obj, "capacity_overflow")?;
+
+    super::set_capacity_overflow(overflow);
+    for room in store().list_rooms()? {
+        publish(EventKind::Capacity, &room.id, None, None);
+    }
+
+    misc::json_response(200, &Overflow { capacity_overflow: overflow })
+}
+
+/// Imports the rooms of a `FenixEDU` space
+///
+/// Every room space under the `path` in the body, written as in
+/// `/api/path/`, becomes a room with its location and capacity taken from
+/// `FenixEDU`. The response reports each room as created, skipped or failed.
+/// Admins only.
+///
+/// # Arguments
+/// * `request` - The request sent by the client
+///
+/// # Output
+/// A Response with a JSON messsage and correct status code.
+pub fn import_handler(request: &mut Request) -> PencilResult {
+    misc::respond(import_rooms(request))
+}
+
+fn import_rooms(request: &mut Request) -> Result<PencilResponse> {
+    misc::identity(request)?.authorize_admin()?;
+    let obj = misc::get_json_object(request)?;
+    let path: &str = misc::get_str(&obj, "path")?;
+
+    let report: ImportReport = import::import(&*fenix(), &*store(), path)?;
+    for room_id in report.created_rooms() {
+        publish(EventKind::RoomCreated, &room_id, None, None);
+    }
+
+    misc::json_response(200, &report)
+}
+
+// /////////////////////////////////////////////////////////////////////////////
+// Webhooks
+// /////////////////////////////////////////////////////////////////////////////
+
+/// Registers a Webhook
+///
+/// The events are sent to the `url` in the body. The webhook follows the room
+/// `room_id`, every room under the `FenixEDU` `path` or, without either,
+/// every room. `events` lists the kinds sent, by default `check_in`,
+/// `check_out`, `room_full` and `room_created`. The deliveries are signed with
+/// `secret`, generated when missing and only shown in this response. Admins
+/// only.
+///
+/// # Arguments
+/// * `request` - The request sent by the client
+///
+/// # Output
+/// A Response with a JSON messsage and correct status code.
+pub fn create_webhook_handler(request: &mut Request) -> PencilResult {
+    misc::respond(create_webhook(request))
+}
+
+fn create_webhook(request: &mut Request) -> Result<PencilResponse> {
+    misc::identity(request)?.authorize_admin()?;
+    let obj = misc::get_json_object(request)?;
+
+    let room_id: Option<String> = match obj.get("room_id") {
+        Some(_) => Some(misc::get_str(&obj, "room_id")?.to_owned()),
+        None => None,
+    };
+    let path: Option<String> = match obj.get("path") {
+        Some(_) => Some(misc::get_str(&obj, "path")?.to_owned()),
+        None => None,
+    };
+    let secret: Option<String> = match obj.get("secret") {
+        Some(_) => Some(misc::get_str(&obj, "secret")?.to_owned()),
+        None => None,
+    };
 
-            Ok(misc::build_response(status_code, &buffer))
+    let mut kinds: Vec<EventKind> = Vec::new();
+    if let Some(value) = obj.get("events") {
+        let names = match value.as_array() {
+            Some(names) => names,
+            None => return Err(Error::Validation("events doesn't have correct type".to_owned())),
+        };
+        for name in names {
+            match name.as_str().and_then(EventKind::from_name) {
+                Some(kind) => kinds.push(kind),
+                None => return Err(Error::Validation(format!("Unknown event {}", name))),
+            }
         }
-        None => Ok(misc::build_response(400, "{\"error\": \"The room_id wasn't provided\"}")),
     }
+
+    let space_id: Option<String> = match (&room_id, &path) {
+        (&Some(_), &Some(_)) => {
+            return Err(Error::Validation("Only one of room_id and path can be provided"
+                .to_owned()))
+        }
+        (&Some(ref room_id), &None) => {
+            store().room(room_id)?;
+            None
+        }
+        (&None, &Some(ref path)) => Some(misc::space_at(path)?.id),
+        (&None, &None) => None,
+    };
+
+    let webhook: Webhook = webhooks().register(NewWebhook {
+            url: misc::get_str(&obj, "url")?.to_owned(),
+            room_id: room_id,
+            path: path,
+            space_id: space_id,
+            events: kinds,
+            secret: secret,
+        })?;
+    misc::json_response(200, &webhook)
+}
+
+/// Gets the registered webhooks, without their secrets. Admins only.
+///
+/// # Output
+/// A Response with a JSON messsage and correct status code.
+pub fn webhooks_handler(request: &mut Request) -> PencilResult {
+    misc::respond(misc::identity(request)
+        .and_then(|identity| identity.authorize_admin())
+        .and_then(|_| misc::json_response(200, &webhooks().list())))
+}
+
+/// Removes the webhook `webhook_id`. Admins only.
+///
+/// # Output
+/// A Response with a JSON messsage and correct status code.
+pub fn delete_webhook_handler(request: &mut Request) -> PencilResult {
+    misc::respond(delete_webhook(request))
+}
+
+fn delete_webhook(request: &mut Request) -> Result<PencilResponse> {
+    misc::identity(request)?.authorize_admin()?;
+    let id: String = misc::view_arg(request, "webhook_id")?;
+
+    let webhook: Webhook = webhooks().unregister(&id)?;
+    misc::json_response(200, &webhook)
+}
+
+/// Gets the log of the deliveries to the webhook `webhook_id`, latest
+/// first. Admins only.
+///
+/// # Output
+/// A Response with a JSON messsage and correct status code.
+pub fn webhook_deliveries_handler(request: &mut Request) -> PencilResult {
+    misc::respond(webhook_deliveries(request))
+}
+
+fn webhook_deliveries(request: &mut Request) -> Result<PencilResponse> {
+    misc::identity(request)?.authorize_admin()?;
+    let id: String = misc::view_arg(request, "webhook_id")?;
+
+    let deliveries: Vec<Delivery> = webhooks().deliveries(&id)?;
+    misc::json_response(200, &deliveries)
 }
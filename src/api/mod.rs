@@ -1,110 +1,310 @@
 //! REST API to interact with `FenixEDU` Spaces API
 //!
-//! The API has five handlers depending on the request. In `getters.rs` the
-//! information received is parsed and proecessed.
+//! The API has five handlers depending on the request. The requests to
+//! `FenixEDU` are performed by `fenix::FenixSpacesClient`.
 extern crate pencil;
 extern crate hyper;
 extern crate serde_json;
 
+use std::cmp;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT,
+                        ATOMIC_USIZE_INIT};
+use std::thread;
+
+use auth::Authenticator;
+use config::Config;
+use events::{Event, EventBus, EventKind};
+use fenix::FenixSpacesClient;
+use error::{Error, Result};
+use oauth::FenixOAuth;
+use reaper::{self, ExpiryPolicy};
+use search::SearchIndex;
+use snapshot::{self, Snapshot, SnapshotStore};
+use store::{self, RoomStore, HttpRoomStore, CheckOutReason};
+use utils;
+use webhooks::{self, Webhooks};
+
 // ///////////////////////////////////////////////////////////
 // Basic Structs
 // ///////////////////////////////////////////////////////////
-#[derive(Deserialize, Serialize, Default)]
-pub struct GenericSpace {
-    name: String,
-    #[serde(rename="containedSpaces")]
-    contained_spaces: Vec<ContainedSpace>,
-    #[serde(skip_serializing_if="Option::is_none")]
-    capacity: Option<Capacity>,
+pub use fenix::{Space, SpaceType, SpaceRef, ContainedSpace, Capacity, Blueprint};
+
+type Spaces = Vec<ContainedSpace>;
+
+// ///////////////////////////////////////////////////////////
+// Configuration
+// ///////////////////////////////////////////////////////////
+lazy_static! {
+    static ref CONFIG: RwLock<Arc<Config>> = RwLock::new(Arc::new(Config::default()));
+    static ref FENIX: RwLock<Arc<FenixSpacesClient>> =
+        RwLock::new(Arc::new(FenixSpacesClient::from_config(&Config::default())));
+    static ref STORE: RwLock<Arc<RoomStore>> =
+        RwLock::new(Arc::new(HttpRoomStore::from_config(&Config::default())) as Arc<RoomStore>);
+    static ref AUTH: RwLock<Arc<Authenticator>> =
+        RwLock::new(Arc::new(Authenticator::from_config(&Config::default())));
+    static ref OAUTH: RwLock<Option<Arc<FenixOAuth>>> = RwLock::new(None);
+    static ref EVENTS: Arc<EventBus> = Arc::new(EventBus::new());
+    static ref WEBHOOKS: RwLock<Arc<Webhooks>> = RwLock::new(Arc::new(Webhooks::new()));
+    static ref SEARCH_INDEX: RwLock<Option<Arc<SearchIndex>>> = RwLock::new(None);
+}
+
+/// True while a thread builds the search index
+static INDEXING: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Unix time of the last failure to build the search index, 0 when none
+static INDEXING_FAILED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Set the configuration used by every handler and open the store and the
+/// webhooks it selects. Must be called before the server starts, otherwise the
+/// defaults are used.
+///
+/// # Arguments
+/// * `config` => the validated configuration.
+///
+/// # Return Value
+/// Nothing or the error raised while opening the store or the webhooks.
+pub fn configure(config: Config) -> Result<()> {
+    let store: Arc<RoomStore> = store::open(&config)?;
+    let webhooks: Webhooks = if config.webhooks_path.is_empty() {
+        Webhooks::new()
+    } else {
+        Webhooks::open(&config.webhooks_path)?
+    };
+
+    let mut fenix = FenixSpacesClient::from_config(&config);
+    if !config.snapshot_path.is_empty() {
+        fenix = fenix.with_snapshot(Arc::new(SnapshotStore::open(&config.snapshot_path)));
+    }
+
+    *FENIX.write().unwrap() = Arc::new(fenix);
+    *AUTH.write().unwrap() = Arc::new(Authenticator::from_config(&config));
+    *OAUTH.write().unwrap() = FenixOAuth::from_config(&config).map(Arc::new);
+    *STORE.write().unwrap() = store;
+    *WEBHOOKS.write().unwrap() = Arc::new(webhooks);
+    *CONFIG.write().unwrap() = Arc::new(config);
+
+    Ok(())
+}
+
+/// Get the configuration currently in use
+pub fn config() -> Arc<Config> {
+    CONFIG.read().unwrap().clone()
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
-pub struct ContainedSpace {
-    id: String,
-    name: String,
+/// Change the percentage of users accepted in a room above its capacity. The
+/// rest of the configuration is kept.
+///
+/// # Arguments
+/// * `overflow` => the new percentage.
+pub fn set_capacity_overflow(overflow: u64) {
+    let mut config = CONFIG.write().unwrap();
+    let mut updated: Config = (**config).clone();
+    updated.capacity_overflow = overflow;
+    *config = Arc::new(updated);
 }
 
-#[derive(Default, Deserialize, Serialize)]
-pub struct Capacity {
-    normal: u64,
+/// Get the `FenixEDU` client built from the configuration
+pub fn fenix() -> Arc<FenixSpacesClient> {
+    FENIX.read().unwrap().clone()
 }
 
-type Space = Vec<ContainedSpace>;
+/// Get the authenticator issuing and verifying the tokens
+pub fn auth() -> Arc<Authenticator> {
+    AUTH.read().unwrap().clone()
+}
 
-// ///////////////////////////////////////////////////////////
-// Enumerators
-// ///////////////////////////////////////////////////////////
-/// Possible search results when searching for a name in the contained spaces parameter
+/// Get the `FenixEDU` OAuth client
 ///
-/// # Values
-/// * `Ok` is used when the desired search result was achieved;
-/// * `NotFound` is used when the result isn't found;
-/// * `Error` is used when an error is found that is out of the context of this
-/// application, e.g., a service crashing.
-pub enum SearchResult {
-    Ok(String),
-    NotFound(String),
-    Error(String),
+/// # Return Value
+/// The client or an `Error::NotFound` when the login isn't configured.
+pub fn oauth() -> Result<Arc<FenixOAuth>> {
+    match *OAUTH.read().unwrap() {
+        Some(ref oauth) => Ok(oauth.clone()),
+        None => Err(Error::NotFound("The FenixEDU login isn't configured".to_owned())),
+    }
 }
 
+/// Get the index of the space names
+///
+/// The index is built in the background, by one thread at a time, and the
+/// current one is served meanwhile. With a snapshot the index follows it.
+/// Without one, the space tree is crawled through `fenix()`, on the first
+/// search and on the first one after `Config::snapshot_refresh` seconds.
+/// After a failure, no build starts for `snapshot::RETRY_DELAY` seconds, or
+/// `Config::snapshot_refresh` when shorter.
+///
+/// # Return Value
+/// The index or an `Error::Unavailable` while the first one is being built.
+pub fn search_index() -> Result<Arc<SearchIndex>> {
+    let current: Option<Arc<SearchIndex>> = SEARCH_INDEX.read().unwrap().clone();
 
-// ///////////////////////////////////////////////////////////
-// Constants
-// ///////////////////////////////////////////////////////////
-const FENIX_BASE_URL: &'static str = "https://fenix.tecnico.ulisboa.pt/api/fenix/v1/spaces";
-const DB_BASE_URL: &'static str = "https://asint-project.herokuapp.com";
+    let stale: bool = match current {
+        Some(ref index) => {
+            match fenix().snapshot().and_then(|store| store.current()) {
+                Some(snapshot) => index.created() != snapshot.created,
+                None => index.created() + config().snapshot_refresh <= utils::unix_time(),
+            }
+        }
+        None => true,
+    };
+    if stale {
+        spawn_indexer();
+    }
+
+    current.ok_or_else(|| {
+        Error::Unavailable("The search index is being built, try again in a minute".to_owned())
+    })
+}
+
+/// Build the search index in the background, unless a thread already does or
+/// the last build failed too recently
+fn spawn_indexer() {
+    // Don't crawl again on every search when FenixEDU is down
+    let failed: u64 = INDEXING_FAILED.load(Ordering::SeqCst) as u64;
+    let delay: u64 = cmp::min(config().snapshot_refresh, snapshot::RETRY_DELAY);
+    if failed != 0 && failed.saturating_add(delay) > utils::unix_time() {
+        return;
+    }
+    if INDEXING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    thread::spawn(|| {
+        let snapshot: Option<Arc<Snapshot>> = fenix().snapshot().and_then(|store| store.current());
+        let result: Result<SearchIndex> = match snapshot {
+            Some(snapshot) => SearchIndex::from_snapshot(&snapshot),
+            None => SearchIndex::crawl(&fenix()),
+        };
+
+        match result {
+            Ok(index) => {
+                *SEARCH_INDEX.write().unwrap() = Some(Arc::new(index));
+                INDEXING_FAILED.store(0, Ordering::SeqCst);
+            }
+            Err(err) => {
+                println!("Indexing the spaces failed: {}", err);
+                INDEXING_FAILED.store(utils::unix_time() as usize, Ordering::SeqCst);
+            }
+        }
+        INDEXING.store(false, Ordering::SeqCst);
+    });
+}
+
+/// Start the background tasks: the delivery of the webhooks and, when the
+/// store or the configuration allow it, the expiry of the check-ins, the
+/// refresh of the space snapshot and the WebSocket server.
+pub fn start_workers() {
+    let config = config();
+
+    // The events lost by a late dispatcher are logged as failed deliveries
+    let lost: Arc<Webhooks> = webhooks();
+    let receiver = events().subscribe_with_overflow(
+        Box::new(|event: &Event| event.kind != EventKind::Occupancy),
+        Box::new(move |event: &Event| lost.record_lost(event)));
+    webhooks::spawn_dispatcher(webhooks(), store(), fenix(), receiver);
+
+    // The original database can't list the check-ins to expire
+    if config.has_extended_store() {
+        reaper::spawn_reaper(store(),
+                             ExpiryPolicy::from_config(&config),
+                             |check_in| {
+            publish(EventKind::CheckOut,
+                    &check_in.room_id,
+                    Some(&check_in.user_id),
+                    Some(CheckOutReason::Expired))
+        });
+    }
+
+    if let Some(store) = fenix().snapshot() {
+        let client = FenixSpacesClient::new(&config.fenix_base_url);
+        snapshot::spawn_refresher(store, client, config.snapshot_refresh);
+    }
+
+    if let Some(addr) = config.ws_addr() {
+        subscriptions::spawn_server(addr);
+    }
+}
+
+/// Replace the store used by the handlers, e.g. with a mock. Must be called
+/// after `configure()`, which installs the store selected by `store::open()`.
+///
+/// # Arguments
+/// * `store` => the new store.
+pub fn set_store(store: Arc<RoomStore>) {
+    *STORE.write().unwrap() = store;
+}
+
+/// Get the store of users, rooms and check-ins
+pub fn store() -> Arc<RoomStore> {
+    STORE.read().unwrap().clone()
+}
+
+/// Get the bus of the occupancy events
+pub fn events() -> Arc<EventBus> {
+    EVENTS.clone()
+}
+
+/// Get the registered webhooks
+pub fn webhooks() -> Arc<Webhooks> {
+    WEBHOOKS.read().unwrap().clone()
+}
+
+/// Publish the event `kind` for the room `room_id` with its current
+/// occupancy. A check in taking the last place of the room is followed by a
+/// `RoomFull` event. The event is dropped, and the failure logged, when the
+/// store can't be read.
+///
+/// # Arguments
+/// * `kind` => what happened;
+/// * `room_id` => id of the room;
+/// * `user_id` => user checked in or out, if any;
+/// * `reason` => why the user left, for check-outs.
+pub fn publish(kind: EventKind,
+               room_id: &str,
+               user_id: Option<&str>,
+               reason: Option<CheckOutReason>) {
+    let overflow = config().capacity_overflow;
+
+    match Event::build(&*store(), kind, room_id, user_id, reason, overflow) {
+        Ok(event) => {
+            let full = if kind == EventKind::CheckIn && event.is_full() {
+                Some(Event { kind: EventKind::RoomFull, ..event.clone() })
+            } else {
+                None
+            };
+
+            events().publish(event);
+            if let Some(full) = full {
+                events().publish(full);
+            }
+        }
+        Err(err) => println!("Event of the room {} dropped: {}", room_id, err),
+    }
+}
 
 // ///////////////////////////////////////////////////////////
 // Modules
 // ///////////////////////////////////////////////////////////
 pub mod handlers;
-mod getters;
+mod sse;
+pub mod subscriptions;
 mod misc {
-    use api::pencil::{Response as PencilResponse, UserError, Request};
-    use api::serde_json::Value;
-    use utils::{from_json_to_obj, read_response_body};
+    use api::pencil::{Response as PencilResponse, PencilResult, Request};
+    use api::serde_json::{Map, Value};
+    use error::{Error, Result};
+    use utils::{from_json_to_obj, from_obj_to_json};
+    use serde::Serialize;
 
-    use super::hyper::status::StatusCode;
     use super::hyper::header::ContentType;
-    use super:: hyper::header::{Headers, AccessControlAllowOrigin};
-    use super::{getters, GenericSpace};
+    use super:: hyper::header::{Headers, AccessControlAllowOrigin, Authorization, Bearer};
+    use super::{auth, fenix, store};
+    use auth::Identity;
+    use fenix::Space;
+    use store::Room;
 
     use std::io::Read;
-
-    /// Checks in the `FenixEDU` API if the space with id `id` exists. A space is
-    /// considered a room when the parameter `contained_spaces` is empty.
-    ///
-    /// # Arguments
-    /// * `id` => id of the space.
-    ///
-    /// # Return Value
-    /// If the room exists true, else false. If the `getters::get_spaces_from_id(<id>)`
-    /// returns an error that will be the error passed.
-    pub fn is_room(id: &str) -> Result<bool, UserError> {
-        // Get space with id `id` from FenixEDU
-        match getters::get_spaces_from_id(id) {
-            Ok(mut response) => {
-                let json: String = match read_response_body(&mut response) {
-                    Ok(body) => body,
-                    Err(err) => {
-                        return Err(UserError::new(err));
-                    }
-                };
-
-                let is_room: bool = match from_json_to_obj::<GenericSpace>(&json) {
-                    Ok(obj) => obj.contained_spaces.is_empty(),
-                    Err(_) => false,
-                };
-
-                if response.status == StatusCode::Ok && is_room {
-                    Ok(true)
-                } else {
-                    Ok(false)
-                }
-            }
-            Err(err) => Err(err),
-        }
-    }
+    use std::str;
 
     /// Build a Response from the provided message and status code
     ///
@@ -127,6 +327,46 @@ mod misc {
         response
     }
 
+    /// Build a Response serializing `obj` as JSON
+    ///
+    /// # Arguments
+    /// * `status_code` => the status code of the response
+    /// * `obj` => object to send
+    ///
+    /// # Return Value
+    /// The response or an error if `obj` can't be serialized
+    pub fn json_response<T>(status_code: u16, obj: &T) -> Result<PencilResponse>
+        where T: Serialize
+    {
+        Ok(build_response(status_code, &from_obj_to_json(obj)?))
+    }
+
+    /// Build the JSON Response matching an error
+    ///
+    /// # Arguments
+    /// * `err` => the error to report
+    ///
+    /// # Return Value
+    /// The response with the status code of the error and its JSON body
+    pub fn error_response(err: &Error) -> PencilResponse {
+        build_response(err.status_code(), &err.to_json())
+    }
+
+    /// Turn the result of a handler into a `PencilResult`, replacing errors by
+    /// their JSON response.
+    ///
+    /// # Arguments
+    /// * `result` => the response or the error of the handler
+    ///
+    /// # Return Value
+    /// Always a response
+    pub fn respond(result: Result<PencilResponse>) -> PencilResult {
+        match result {
+            Ok(response) => Ok(response),
+            Err(err) => Ok(error_response(&err)),
+        }
+    }
+
     /// Parses the incoming JSON request data.
     ///
     /// # Arguments
@@ -147,6 +387,201 @@ mod misc {
         }
     }
 
+    /// Parses the incoming request as a JSON object, checking its content-type
+    ///
+    /// # Arguments
+    /// * `request` => request made
+    ///
+    /// # Return Value
+    /// The JSON object or the error to report to the client
+    pub fn get_json_object(request: &mut Request) -> Result<Map<String, Value>> {
+        if !is_content_type_json(request.headers().clone()) {
+            return Err(Error::UnsupportedMediaType);
+        }
+
+        match get_json(request) {
+            Some(Value::Object(obj)) => Ok(obj),
+            Some(_) => Err(Error::Validation("JSON isn't an object".to_owned())),
+            None => Err(Error::Validation("Failed to parse JSON".to_owned())),
+        }
+    }
+
+    /// Get a non-empty string field from a JSON object
+    ///
+    /// # Arguments
+    /// * `obj` => JSON object sent by the client
+    /// * `key` => name of the field
+    ///
+    /// # Return Value
+    /// The string or an `Error::Validation` when the field is missing, isn't a
+    /// string or is empty.
+    pub fn get_str<'a>(obj: &'a Map<String, Value>, key: &str) -> Result<&'a str> {
+        match obj.get(key) {
+            Some(value) => {
+                match value.as_str() {
+                    Some(string) if !string.is_empty() => Ok(string),
+                    _ => Err(Error::Validation(format!("{} doesn't have correct type", key))),
+                }
+            }
+            None => Err(Error::Validation(format!("{} wasn't provided", key))),
+        }
+    }
+
+    /// Get a non-negative integer field from a JSON object
+    ///
+    /// # Arguments
+    /// * `obj` => JSON object sent by the client
+    /// * `key` => name of the field
+    ///
+    /// # Return Value
+    /// The number or an `Error::Validation` when the field is missing or isn't
+    /// a non-negative integer.
+    pub fn get_unsigned(obj: &Map<String, Value>, key: &str) -> Result<u64> {
+        match obj.get(key) {
+            Some(value) => {
+                match value.as_u64() {
+                    Some(number) => Ok(number),
+                    None => Err(Error::Validation(format!("{} doesn't have correct type", key))),
+                }
+            }
+            None => Err(Error::Validation(format!("{} wasn't provided", key))),
+        }
+    }
+
+    /// Get a query string argument
+    ///
+    /// # Arguments
+    /// * `request` => request made
+    /// * `key` => name of the argument
+    ///
+    /// # Return Value
+    /// The argument or `None` when it wasn't provided
+    pub fn query_arg(request: &mut Request, key: &str) -> Option<String> {
+        request.args().get(key).map(|value| value.to_owned())
+    }
+
+    /// Get a non-negative integer query string argument
+    ///
+    /// # Arguments
+    /// * `request` => request made
+    /// * `key` => name of the argument
+    ///
+    /// # Return Value
+    /// The number, `None` when it wasn't provided or an `Error::Validation`
+    /// when it isn't a non-negative integer
+    pub fn query_unsigned(request: &mut Request, key: &str) -> Result<Option<u64>> {
+        match query_arg(request, key) {
+            Some(value) => {
+                match value.parse() {
+                    Ok(number) => Ok(Some(number)),
+                    Err(_) => Err(Error::Validation(format!("{} doesn't have correct type", key))),
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Get an argument of the URL
+    ///
+    /// # Arguments
+    /// * `request` => request made
+    /// * `key` => name of the argument
+    ///
+    /// # Return Value
+    /// The argument or an `Error::Validation` when it wasn't provided
+    pub fn view_arg(request: &Request, key: &str) -> Result<String> {
+        match request.view_args.get(key) {
+            Some(value) => Ok(value.to_owned()),
+            None => Err(Error::Validation(format!("The {} wasn't provided", key))),
+        }
+    }
+
+    /// Resolve the caller of the request from its bearer token
+    ///
+    /// # Arguments
+    /// * `request` => request made
+    ///
+    /// # Return Value
+    /// The identity of the caller or an `Error::Unauthorized` when the token is
+    /// missing or invalid
+    pub fn identity(request: &Request) -> Result<Identity> {
+        match request.headers().get::<Authorization<Bearer>>() {
+            Some(header) => auth().verify(&header.0.token),
+            None => Err(Error::Unauthorized("A bearer token is required".to_owned())),
+        }
+    }
+
+    /// Get a cookie sent with the request
+    ///
+    /// # Arguments
+    /// * `request` => request made
+    /// * `name` => name of the cookie
+    ///
+    /// # Return Value
+    /// The value of the cookie or `None` when it wasn't sent
+    pub fn cookie(request: &Request, name: &str) -> Option<String> {
+        let headers = match request.headers().get_raw("Cookie") {
+            Some(headers) => headers,
+            None => return None,
+        };
+
+        headers.iter()
+            .filter_map(|header| str::from_utf8(header).ok())
+            .flat_map(|header| header.split(';'))
+            .filter_map(|pair| {
+                let mut parts = pair.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(key), Some(value)) if key.trim() == name => {
+                        Some(value.trim().to_owned())
+                    }
+                    _ => None,
+                }
+            })
+            .next()
+    }
+
+    /// Get the `FenixEDU` space at `path`
+    ///
+    /// # Arguments
+    /// * `path` => path as used by `/api/path/`
+    ///
+    /// # Return Value
+    /// The space, an `Error::NotFound` for an unknown path or the error of
+    /// `FenixEDU`
+    pub fn space_at(path: &str) -> Result<Space> {
+        let points: Vec<&str> = path.split('/').filter(|point| !point.is_empty()).collect();
+
+        fenix().resolve_path(&points)
+    }
+
+    /// Rooms of the store in the `FenixEDU` space `space_id`, at any depth
+    ///
+    /// The ancestry of each room is searched for `space_id`, rather than the
+    /// spaces inside `space_id` for the rooms, so the requests to `FenixEDU`
+    /// are bounded by the rooms of the store whatever the size of the space,
+    /// and the parents shared by the rooms are cached. Rooms unknown to
+    /// `FenixEDU` are left out.
+    ///
+    /// # Return Value
+    /// The rooms or the error of the store or of `FenixEDU`
+    pub fn rooms_under(space_id: &str) -> Result<Vec<Room>> {
+        let mut rooms: Vec<Room> = Vec::new();
+
+        for room in store().list_rooms()? {
+            match fenix().ancestry(&room.fenix_id) {
+                Ok(ancestry) => {
+                    if ancestry.iter().any(|space| space.id == space_id) {
+                        rooms.push(room);
+                    }
+                }
+                Err(Error::NotFound(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(rooms)
+    }
+
     /// Check if content-type is set to JSON
     ///
     /// # Arguments
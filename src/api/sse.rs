@@ -0,0 +1,113 @@
+//! Server-Sent Events responses
+//!
+//! The response body stays open and writes each `Event` received from the
+//! `EventBus` as an SSE message named after its kind, with the event as JSON
+//! data. A comment is sent when nothing happened for `HEARTBEAT` seconds, so
+//! proxies keep the connection open and a client that went away is noticed.
+//!
+//! A stream holds a worker thread of the server until the client leaves, so
+//! at most `Config::max_event_streams` are open at once and the others are
+//! refused with `503`. The server has 5 worker threads per CPU, and those not
+//! taken by a stream are left to the rest of the API.
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use api::pencil::{Response as PencilResponse, BodyWrite, ResponseBody};
+use api::hyper::header::{Headers, AccessControlAllowOrigin, CacheControl, CacheDirective,
+                         ContentType};
+use api::hyper::mime::{Mime, TopLevel, SubLevel};
+use api::config;
+use error::{Error, Result};
+use events::Event;
+use utils;
+
+/// Seconds between two heartbeats
+const HEARTBEAT: u64 = 15;
+
+/// Milliseconds the browser waits before reconnecting
+const RETRY: u64 = 3000;
+
+/// Number of streams open
+static OPEN_STREAMS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Place of an open stream, given back when dropped
+pub struct StreamSlot {
+    _private: (),
+}
+
+impl StreamSlot {
+    /// Take a place for a new stream
+    ///
+    /// # Return Value
+    /// The place or an `Error::Unavailable` when `Config::max_event_streams`
+    /// are open.
+    pub fn acquire() -> Result<StreamSlot> {
+        if OPEN_STREAMS.fetch_add(1, Ordering::SeqCst) >= config().max_event_streams {
+            OPEN_STREAMS.fetch_sub(1, Ordering::SeqCst);
+            return Err(Error::Unavailable("Too many event streams are open, try again later \
+                                           or use the WebSocket server"
+                .to_owned()));
+        }
+
+        Ok(StreamSlot { _private: () })
+    }
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        OPEN_STREAMS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Body writing the events of a subscription
+struct EventStream {
+    receiver: Receiver<Event>,
+    _slot: StreamSlot,
+}
+
+impl BodyWrite for EventStream {
+    fn write_body(&mut self, body: &mut ResponseBody) -> io::Result<()> {
+        write!(body, "retry: {}\n\n", RETRY)?;
+        body.flush()?;
+
+        loop {
+            match self.receiver.recv_timeout(Duration::from_secs(HEARTBEAT)) {
+                Ok(event) => {
+                    let data: String = utils::from_obj_to_json(&event)
+                        .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+                    write!(body, "event: {}\ndata: {}\n\n", event.kind.name(), data)?;
+                }
+                Err(RecvTimeoutError::Timeout) => write!(body, ": heartbeat\n\n")?,
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+            body.flush()?;
+        }
+    }
+}
+
+/// Build the response streaming the events of `receiver`
+///
+/// # Arguments
+/// * `slot` => place of the stream, kept until it ends
+/// * `receiver` => subscription to the `EventBus`
+///
+/// # Return Value
+/// The `text/event-stream` response
+pub fn response(slot: StreamSlot, receiver: Receiver<Event>) -> PencilResponse {
+    let mut headers = Headers::new();
+    headers.set(AccessControlAllowOrigin::Any);
+    let event_stream = Mime(TopLevel::Text, SubLevel::Ext("event-stream".to_owned()), vec![]);
+    headers.set(ContentType(event_stream));
+    headers.set(CacheControl(vec![CacheDirective::NoCache]));
+
+    let mut response = PencilResponse::new(EventStream {
+        receiver: receiver,
+        _slot: slot,
+    });
+    response.status_code = 200;
+    response.headers = headers;
+
+    response
+}
@@ -0,0 +1,529 @@
+//! WebSocket subscriptions to the occupancy of the rooms
+//!
+//! The WebSocket server listens on `Config::ws_port`. Clients send JSON
+//! messages with a `type`:
+//!
+//! * `subscribe` with `rooms` (store ids) and/or `path` (a path as used by
+//!   `/api/path/`, which selects the rooms of the store inside that space);
+//! * `unsubscribe` with `rooms` and/or `path`, or nothing to drop every room;
+//! * `resume` with the `subscription_id` received before, to get the same
+//!   rooms back after reconnecting.
+//!
+//! The server answers every message with `subscribed`, holding the
+//! `subscription_id`, the `rooms` and their current `occupancy`, or with
+//! `error`. Each change in a subscribed room is then sent as an `event`. A
+//! `heartbeat` message and a ping are sent every `HEARTBEAT` seconds; a
+//! connection silent for two heartbeats is closed. A closed connection keeps
+//! its subscription for `RESUME_GRACE` seconds.
+//!
+//! Answering a message may take requests to the store and to `FenixEDU`, so
+//! the messages are answered by `WORKERS` threads rather than by the event
+//! loop of the server. The messages of a connection are answered in order,
+//! one at a time, so a slow `path` only holds its own connection and one
+//! worker. At most `QUEUE` connections wait for a worker and `PENDING`
+//! messages of a connection wait for the previous answer; the others are
+//! answered with an `error`.
+extern crate rand;
+extern crate ws;
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use self::ws::{CloseCode, Frame, Handler, Handshake, Message, Sender};
+use self::ws::util::Token;
+
+use error::{Error, Result};
+use events::{Event, EventKind};
+use utils;
+use super::{config, events, store};
+use super::misc;
+
+/// Seconds between two heartbeats
+const HEARTBEAT: u64 = 30;
+
+/// Seconds a subscription is kept after its connection closed
+const RESUME_GRACE: u64 = 5 * 60;
+
+/// Timeout of the heartbeat of a connection
+const HEARTBEAT_TOKEN: Token = Token(1);
+
+/// Threads answering the messages
+const WORKERS: usize = 4;
+
+/// Most connections waiting for a worker
+const QUEUE: usize = 64;
+
+/// Most messages of a connection waiting for the answer to the previous one
+const PENDING: usize = 16;
+
+/// Rooms followed by a client
+///
+/// # Fields
+/// * `rooms` => ids of the rooms;
+/// * `sender` => connection of the client, `None` while it is away;
+/// * `detached_at` => UNIX time the connection closed.
+struct Session {
+    rooms: BTreeSet<String>,
+    sender: Option<Sender>,
+    detached_at: u64,
+}
+
+lazy_static! {
+    static ref SESSIONS: Mutex<HashMap<String, Session>> = Mutex::new(HashMap::new());
+}
+
+/// Message sent by a client
+#[derive(Deserialize)]
+struct ClientMessage {
+    #[serde(rename="type")]
+    kind: String,
+    rooms: Option<Vec<String>>,
+    path: Option<String>,
+    subscription_id: Option<String>,
+}
+
+/// Message sent to a client
+#[derive(Serialize, Default)]
+struct Reply {
+    #[serde(rename="type")]
+    kind: &'static str,
+    #[serde(skip_serializing_if="Option::is_none")]
+    subscription_id: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    rooms: Option<Vec<String>>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    occupancy: Option<Vec<Event>>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    event: Option<Event>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    time: Option<u64>,
+}
+
+/// Start the WebSocket server on `addr`, the thread forwarding the events
+/// to the subscribers and those answering the messages
+pub fn spawn_server(addr: String) -> JoinHandle<()> {
+    spawn_dispatcher();
+    let (jobs, receiver) = mpsc::sync_channel(QUEUE);
+    spawn_workers(receiver);
+
+    thread::spawn(move || {
+        let result = ws::listen(addr.as_str(), |out| {
+            Connection {
+                out: out,
+                state: Arc::new(Mutex::new(ConnectionState::default())),
+                jobs: jobs.clone(),
+                last_seen: utils::unix_time(),
+            }
+        });
+
+        if let Err(err) = result {
+            println!("WebSocket server failed: {}", err);
+        }
+    })
+}
+
+/// Forward every event to the sessions following its room and forget the
+/// sessions away for longer than `RESUME_GRACE`
+fn spawn_dispatcher() -> JoinHandle<()> {
+    let receiver = events().subscribe(Box::new(|_: &Event| true));
+
+    thread::spawn(move || {
+        loop {
+            match receiver.recv_timeout(Duration::from_secs(HEARTBEAT)) {
+                Ok(event) => dispatch(&event),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+
+            let now = utils::unix_time();
+            SESSIONS.lock().unwrap().retain(|_, session| {
+                session.sender.is_some() || session.detached_at + RESUME_GRACE > now
+            });
+        }
+    })
+}
+
+/// Spawn the `WORKERS` threads answering the messages of `jobs`
+fn spawn_workers(jobs: Receiver<Job>) {
+    let jobs = Arc::new(Mutex::new(jobs));
+
+    for _ in 0..WORKERS {
+        let jobs = jobs.clone();
+        thread::spawn(move || {
+            loop {
+                // The lock is only held while waiting for a job
+                let job = jobs.lock().unwrap().recv();
+                match job {
+                    Ok(job) => work(job),
+                    Err(_) => return,
+                }
+            }
+        });
+    }
+}
+
+/// Answer the message of `job`, then those its connection sent meanwhile
+fn work(job: Job) {
+    let Job { out, state, mut text } = job;
+
+    loop {
+        let reply: Reply = answer(&state, &out, &text);
+        // A closed connection is noticed by its handler
+        let _ = send(&out, &reply);
+
+        let mut state = state.lock().unwrap();
+        match state.pending.pop_front() {
+            Some(next) => text = next,
+            None => {
+                state.busy = false;
+                return;
+            }
+        }
+    }
+}
+
+/// Send `event` to the sessions following its room
+fn dispatch(event: &Event) {
+    let sessions = SESSIONS.lock().unwrap();
+
+    for (id, session) in sessions.iter() {
+        if !session.rooms.contains(&event.room_id) {
+            continue;
+        }
+        if let Some(ref sender) = session.sender {
+            let reply = Reply {
+                kind: "event",
+                subscription_id: Some(id.clone()),
+                event: Some(event.clone()),
+                ..Default::default()
+            };
+            // A closed connection is noticed by its handler
+            let _ = send(sender, &reply);
+        }
+    }
+}
+
+/// Serialize and send `reply`
+fn send(out: &Sender, reply: &Reply) -> ws::Result<()> {
+    match utils::from_obj_to_json(reply) {
+        Ok(json) => out.send(json),
+        Err(err) => {
+            println!("WebSocket message dropped: {}", err);
+            Ok(())
+        }
+    }
+}
+
+/// Ids of the rooms selected by the `rooms` and `path` of a message
+///
+/// # Return Value
+/// The ids, an `Error::NotFound` for an unknown room or path or the error of
+/// the store or of `FenixEDU`.
+fn selected_rooms(message: &ClientMessage) -> Result<BTreeSet<String>> {
+    let mut rooms: BTreeSet<String> = BTreeSet::new();
+
+    if let Some(ref ids) = message.rooms {
+        for id in ids {
+            store().room(id)?;
+            rooms.insert(id.clone());
+        }
+    }
+    if let Some(ref path) = message.path {
+        rooms.extend(rooms_under(path)?);
+    }
+
+    Ok(rooms)
+}
+
+/// Ids of the rooms of the store inside the space at `path`
+fn rooms_under(path: &str) -> Result<Vec<String>> {
+    let space_id: String = misc::space_at(path)?.id;
+
+    Ok(misc::rooms_under(&space_id)?.into_iter().map(|room| room.id).collect())
+}
+
+/// Current occupancy of `rooms`
+fn occupancy(rooms: &[String]) -> Result<Vec<Event>> {
+    let overflow = config().capacity_overflow;
+
+    rooms.iter()
+        .map(|room_id| Event::build(&*store(), EventKind::Occupancy, room_id, None, None, overflow))
+        .collect()
+}
+
+/// Id of a new subscription
+fn new_subscription_id() -> String {
+    format!("{:016x}{:016x}", rand::random::<u64>(), rand::random::<u64>())
+}
+
+/// Subscription of a connection, shared by its handler and the workers
+///
+/// # Fields
+/// * `subscription_id` => id of the subscription, once made;
+/// * `closed` => the connection is closed, so it must not be subscribed;
+/// * `busy` => a worker is answering a message of the connection;
+/// * `pending` => messages received meanwhile, answered by that worker.
+#[derive(Default)]
+struct ConnectionState {
+    subscription_id: Option<String>,
+    closed: bool,
+    busy: bool,
+    pending: VecDeque<String>,
+}
+
+/// A message waiting for a worker
+struct Job {
+    out: Sender,
+    state: Arc<Mutex<ConnectionState>>,
+    text: String,
+}
+
+/// A client connection
+struct Connection {
+    out: Sender,
+    state: Arc<Mutex<ConnectionState>>,
+    jobs: SyncSender<Job>,
+    last_seen: u64,
+}
+
+/// Reply with the error `msg`
+fn error_reply(msg: String) -> Reply {
+    Reply {
+        kind: "error",
+        error: Some(msg),
+        ..Default::default()
+    }
+}
+
+/// Answer a message of the client of `out`
+///
+/// The rooms are looked up before `state` is locked, so the handler of the
+/// connection is never kept waiting.
+fn answer(state: &Mutex<ConnectionState>, out: &Sender, text: &str) -> Reply {
+    let result = match utils::from_json_to_obj::<ClientMessage>(text) {
+        Ok(message) => {
+            match message.kind.as_str() {
+                "subscribe" => subscribe(state, out, &message),
+                "unsubscribe" => unsubscribe(state, &message),
+                "resume" => resume(state, out, &message),
+                kind => Err(Error::Validation(format!("unknown message type {}", kind))),
+            }
+        }
+        Err(_) => Err(Error::Validation("Failed to parse JSON".to_owned())),
+    };
+
+    result.unwrap_or_else(|err| error_reply(err.to_string()))
+}
+
+/// Error answered to the messages of a closed connection
+fn closed() -> Error {
+    Error::NotFound("The connection is closed".to_owned())
+}
+
+/// Add the selected rooms to the subscription, creating it if need be
+fn subscribe(state: &Mutex<ConnectionState>,
+             out: &Sender,
+             message: &ClientMessage)
+             -> Result<Reply> {
+    if message.rooms.is_none() && message.path.is_none() {
+        return Err(Error::Validation("rooms or path wasn't provided".to_owned()));
+    }
+    let selected: BTreeSet<String> = selected_rooms(message)?;
+
+    let (id, rooms): (String, Vec<String>) = {
+        let mut state = state.lock().unwrap();
+        if state.closed {
+            return Err(closed());
+        }
+        let id: String = state.subscription_id.clone().unwrap_or_else(new_subscription_id);
+
+        let mut sessions = SESSIONS.lock().unwrap();
+        let session = sessions.entry(id.clone()).or_insert_with(|| {
+            Session {
+                rooms: BTreeSet::new(),
+                sender: None,
+                detached_at: 0,
+            }
+        });
+        session.rooms.extend(selected);
+        session.sender = Some(out.clone());
+        state.subscription_id = Some(id.clone());
+
+        (id, session.rooms.iter().cloned().collect())
+    };
+
+    subscribed(id, rooms)
+}
+
+/// Remove the selected rooms, or every room, from the subscription
+fn unsubscribe(state: &Mutex<ConnectionState>, message: &ClientMessage) -> Result<Reply> {
+    let id: String = match state.lock().unwrap().subscription_id {
+        Some(ref id) => id.clone(),
+        None => return Err(Error::NotFound("There is no subscription".to_owned())),
+    };
+    let everything = message.rooms.is_none() && message.path.is_none();
+    let selected: BTreeSet<String> = selected_rooms(message)?;
+
+    let rooms: Vec<String> = {
+        let mut sessions = SESSIONS.lock().unwrap();
+        match sessions.get_mut(&id) {
+            Some(session) => {
+                if everything {
+                    session.rooms.clear();
+                } else {
+                    session.rooms = session.rooms.difference(&selected).cloned().collect();
+                }
+                session.rooms.iter().cloned().collect()
+            }
+            None => Vec::new(),
+        }
+    };
+
+    subscribed(id, rooms)
+}
+
+/// Attach the connection to a subscription made before
+fn resume(state: &Mutex<ConnectionState>, out: &Sender, message: &ClientMessage) -> Result<Reply> {
+    let id: String = match message.subscription_id {
+        Some(ref id) => id.clone(),
+        None => return Err(Error::Validation("subscription_id wasn't provided".to_owned())),
+    };
+
+    let (previous, rooms): (Option<String>, Vec<String>) = {
+        let mut state = state.lock().unwrap();
+        if state.closed {
+            return Err(closed());
+        }
+
+        let rooms: Vec<String> = {
+            let mut sessions = SESSIONS.lock().unwrap();
+            match sessions.get_mut(&id) {
+                Some(session) => {
+                    session.sender = Some(out.clone());
+                    session.rooms.iter().cloned().collect()
+                }
+                None => {
+                    return Err(Error::NotFound(format!("The subscription {} has expired", id)))
+                }
+            }
+        };
+
+        let previous: Option<String> = state.subscription_id.take();
+        state.subscription_id = Some(id.clone());
+        (previous, rooms)
+    };
+    if let Some(previous) = previous {
+        if previous != id {
+            detach(&previous);
+        }
+    }
+
+    subscribed(id, rooms)
+}
+
+/// Reply confirming the subscription `id` to `rooms`
+fn subscribed(id: String, rooms: Vec<String>) -> Result<Reply> {
+    let occupancy: Vec<Event> = occupancy(&rooms)?;
+
+    Ok(Reply {
+        kind: "subscribed",
+        subscription_id: Some(id),
+        rooms: Some(rooms),
+        occupancy: Some(occupancy),
+        ..Default::default()
+    })
+}
+
+/// Keep the subscription `id` for `RESUME_GRACE` seconds without connection
+fn detach(id: &str) {
+    if let Some(session) = SESSIONS.lock().unwrap().get_mut(id) {
+        session.sender = None;
+        session.detached_at = utils::unix_time();
+    }
+}
+
+impl Handler for Connection {
+    fn on_open(&mut self, _: Handshake) -> ws::Result<()> {
+        self.out.timeout(HEARTBEAT * 1000, HEARTBEAT_TOKEN)
+    }
+
+    fn on_message(&mut self, message: Message) -> ws::Result<()> {
+        self.last_seen = utils::unix_time();
+
+        let text: String = match message.as_text() {
+            Ok(text) => text.to_owned(),
+            Err(_) => return send(&self.out, &error_reply("Messages must be JSON text".to_owned())),
+        };
+
+        {
+            // The worker answering the previous message answers this one next
+            let mut state = self.state.lock().unwrap();
+            if state.busy {
+                if state.pending.len() >= PENDING {
+                    return send(&self.out,
+                                &error_reply("Too many messages are waiting, try again later"
+                                    .to_owned()));
+                }
+                state.pending.push_back(text);
+                return Ok(());
+            }
+            state.busy = true;
+        }
+
+        let job = Job {
+            out: self.out.clone(),
+            state: self.state.clone(),
+            text: text,
+        };
+        let error: &str = match self.jobs.try_send(job) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Full(_)) => "Too many messages are waiting, try again later",
+            Err(TrySendError::Disconnected(_)) => "The subscriptions are unavailable",
+        };
+        self.state.lock().unwrap().busy = false;
+        send(&self.out, &error_reply(error.to_owned()))
+    }
+
+    fn on_frame(&mut self, frame: Frame) -> ws::Result<Option<Frame>> {
+        // Pongs count as activity
+        self.last_seen = utils::unix_time();
+        Ok(Some(frame))
+    }
+
+    fn on_timeout(&mut self, token: Token) -> ws::Result<()> {
+        if token != HEARTBEAT_TOKEN {
+            return Ok(());
+        }
+
+        let now = utils::unix_time();
+        if now > self.last_seen + 2 * HEARTBEAT {
+            return self.out.close(CloseCode::Away);
+        }
+
+        self.out.ping(Vec::new())?;
+        send(&self.out,
+             &Reply {
+                 kind: "heartbeat",
+                 time: Some(now),
+                 ..Default::default()
+             })?;
+        self.out.timeout(HEARTBEAT * 1000, HEARTBEAT_TOKEN)
+    }
+
+    fn on_close(&mut self, _: CloseCode, _: &str) {
+        let id: Option<String> = {
+            let mut state = self.state.lock().unwrap();
+            state.closed = true;
+            state.pending.clear();
+            state.subscription_id.take()
+        };
+        if let Some(id) = id {
+            detach(&id);
+        }
+    }
+}
@@ -0,0 +1,480 @@
+//! Authentication of the API clients.
+//!
+//! Users receive a bearer token when they are created. The token is signed
+//! with HMAC-SHA256 and carries the user id, the role and the expiry time, so
+//! no session has to be stored. The role is decided when the token is issued:
+//! users listed in `Config::admin_users` are admins. Since anyone may pick a
+//! username, those names can only be registered with `Config::admin_secret`,
+//! see `Authenticator::authorize_registration()`.
+//!
+//! A token is `<payload>.<signature>`, both hex encoded, where the payload is
+//! `<user_id>:<role>:<expires>`. The same signature protects the `state` of
+//! the `FenixEDU` OAuth login against forged callbacks. The state is also
+//! kept in a cookie of the browser starting the login, and a callback whose
+//! state doesn't match that cookie is refused.
+extern crate crypto;
+extern crate rand;
+
+use std::fmt;
+use std::str;
+
+use self::crypto::hmac::Hmac;
+use self::crypto::mac::{Mac, MacResult};
+use self::crypto::sha2::Sha256;
+use self::crypto::util::fixed_time_eq;
+
+use config::Config;
+use error::{Error, Result};
+use store::User;
+use utils;
+
+/// Seconds a login `state` stays valid
+pub const STATE_TTL: u64 = 10 * 60;
+
+/// Role of an authenticated user
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum Role {
+    #[serde(rename="admin")]
+    Admin,
+    #[serde(rename="user")]
+    User,
+}
+
+impl Role {
+    fn from_name(name: &str) -> Option<Role> {
+        match name {
+            "admin" => Some(Role::Admin),
+            "user" => Some(Role::User),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Role::Admin => write!(f, "admin"),
+            Role::User => write!(f, "user"),
+        }
+    }
+}
+
+/// The caller of a request
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub user_id: String,
+    pub role: Role,
+    pub expires: u64,
+}
+
+impl Identity {
+    /// True for admins
+    pub fn is_admin(&self) -> bool {
+        self.role == Role::Admin
+    }
+
+    /// Check that the caller may act on behalf of `user_id`: admins may act
+    /// for everyone, users only for themselves.
+    ///
+    /// # Return Value
+    /// Nothing or an `Error::Forbidden`.
+    pub fn authorize_user(&self, user_id: &str) -> Result<()> {
+        if self.is_admin() || self.user_id == user_id {
+            Ok(())
+        } else {
+            Err(Error::Forbidden(format!("Only admins can act on behalf of the user {}",
+                                         user_id)))
+        }
+    }
+
+    /// Check that the caller is an admin
+    ///
+    /// # Return Value
+    /// Nothing or an `Error::Forbidden`.
+    pub fn authorize_admin(&self) -> Result<()> {
+        if self.is_admin() {
+            Ok(())
+        } else {
+            Err(Error::Forbidden("Only admins can perform this operation".to_owned()))
+        }
+    }
+}
+
+/// Issues and verifies tokens
+pub struct Authenticator {
+    secret: Vec<u8>,
+    ttl: u64,
+    admins: Vec<String>,
+    admin_secret: String,
+}
+
+impl Authenticator {
+    /// Create an authenticator
+    ///
+    /// # Arguments
+    /// * `secret` => key of the signatures;
+    /// * `ttl` => seconds a token stays valid;
+    /// * `admins` => usernames receiving the admin role.
+    pub fn new(secret: &[u8], ttl: u64, admins: Vec<String>) -> Authenticator {
+        Authenticator {
+            secret: secret.to_vec(),
+            ttl: ttl,
+            admins: admins,
+            admin_secret: String::new(),
+        }
+    }
+
+    /// Accept the registration of the admin usernames with `admin_secret`.
+    /// Without it they can't be registered.
+    pub fn with_admin_secret(mut self, admin_secret: &str) -> Authenticator {
+        self.admin_secret = admin_secret.to_owned();
+        self
+    }
+
+    /// Create the authenticator set in `config`
+    ///
+    /// `Config::validate()` refuses an empty `auth_secret`; the default
+    /// configuration, used before the loaded one is set, gets a random key.
+    pub fn from_config(config: &Config) -> Authenticator {
+        let secret: Vec<u8> = if config.auth_secret.is_empty() {
+            let random: [u8; 32] = rand::random();
+            random.to_vec()
+        } else {
+            config.auth_secret.as_bytes().to_vec()
+        };
+
+        Authenticator::new(&secret, config.token_ttl, config.admin_users.clone())
+            .with_admin_secret(&config.admin_secret)
+    }
+
+    /// Role given to `user`
+    pub fn role_of(&self, user: &User) -> Role {
+        if self.is_admin_username(&user.username) {
+            Role::Admin
+        } else {
+            Role::User
+        }
+    }
+
+    /// True when `username` receives the admin role
+    pub fn is_admin_username(&self, username: &str) -> bool {
+        self.admins.iter().any(|admin| admin == username)
+    }
+
+    /// Check that a client may register the username `username`
+    ///
+    /// An admin username would give its admin role to whoever registers it
+    /// first, so it needs the admin secret.
+    ///
+    /// # Arguments
+    /// * `username` => username to register;
+    /// * `admin_secret` => admin secret sent by the client, if any.
+    ///
+    /// # Return Value
+    /// Nothing or an `Error::Forbidden`.
+    pub fn authorize_registration(&self, username: &str, admin_secret: Option<&str>) -> Result<()> {
+        if !self.is_admin_username(username) {
+            return Ok(());
+        }
+
+        match admin_secret {
+            Some(secret) if !self.admin_secret.is_empty() &&
+                            fixed_time_eq(secret.as_bytes(), self.admin_secret.as_bytes()) => {
+                Ok(())
+            }
+            _ => {
+                Err(Error::Forbidden(format!("The username {} is reserved to an admin",
+                                             username)))
+            }
+        }
+    }
+
+    /// Issue a token for `user`
+    pub fn issue(&self, user: &User) -> String {
+        let expires = utils::unix_time() + self.ttl;
+
+        self.seal(&format!("{}:{}:{}", user.id, self.role_of(user), expires))
+    }
+
+    /// Verify `token`
+    ///
+    /// # Return Value
+    /// The identity in the token or an `Error::Unauthorized` if the token is
+    /// malformed, forged or expired.
+    pub fn verify(&self, token: &str) -> Result<Identity> {
+        let invalid = || Error::Unauthorized("Invalid token".to_owned());
+
+        let payload: String = self.open(token).ok_or_else(&invalid)?;
+        let fields: Vec<&str> = payload.rsplitn(3, ':').collect();
+        if fields.len() != 3 {
+            return Err(invalid());
+        }
+        let expires: u64 = fields[0].parse().map_err(|_| invalid())?;
+        let role: Role = Role::from_name(fields[1]).ok_or_else(&invalid)?;
+
+        if expires <= utils::unix_time() {
+            return Err(Error::Unauthorized("The token has expired".to_owned()));
+        }
+
+        Ok(Identity {
+            user_id: fields[2].to_owned(),
+            role: role,
+            expires: expires,
+        })
+    }
+
+    /// Issue the login key of `user`
+    ///
+    /// The key doesn't expire: it is exchanged for a new token when the
+    /// previous one expired or was lost, and only changing `auth_secret`
+    /// revokes it.
+    pub fn issue_key(&self, user: &User) -> String {
+        self.seal(&format!("login:{}:{}", user.id, user.username))
+    }
+
+    /// Verify the login `key`
+    ///
+    /// # Return Value
+    /// The user of the key or an `Error::Unauthorized` if the key is malformed
+    /// or forged.
+    pub fn verify_key(&self, key: &str) -> Result<User> {
+        let invalid = || Error::Unauthorized("Invalid login key".to_owned());
+
+        let payload: String = self.open(key).ok_or_else(&invalid)?;
+        let fields: Vec<&str> = payload.splitn(3, ':').collect();
+        if fields.len() != 3 || fields[0] != "login" {
+            return Err(invalid());
+        }
+
+        Ok(User {
+            id: fields[1].to_owned(),
+            username: fields[2].to_owned(),
+        })
+    }
+
+    /// Issue the `state` of an OAuth authorization request, valid for
+    /// `STATE_TTL` seconds
+    pub fn issue_state(&self) -> String {
+        let nonce: [u8; 16] = rand::random();
+
+        self.seal(&format!("{}:{}", to_hex(&nonce), utils::unix_time() + STATE_TTL))
+    }
+
+    /// Verify the `state` returned by an OAuth authorization
+    ///
+    /// # Arguments
+    /// * `state` => state sent back by the authorization server;
+    /// * `cookie` => state kept in the cookie of the browser, if any.
+    ///
+    /// # Return Value
+    /// Nothing or an `Error::Unauthorized` if the state is forged, expired or
+    /// wasn't issued to this browser.
+    pub fn verify_state(&self, state: &str, cookie: Option<&str>) -> Result<()> {
+        match cookie {
+            Some(cookie) if fixed_time_eq(state.as_bytes(), cookie.as_bytes()) => {}
+            _ => {
+                return Err(Error::Unauthorized("The login wasn't started by this browser"
+                    .to_owned()))
+            }
+        }
+
+        let expires: Option<u64> = self.open(state)
+            .and_then(|payload| payload.rsplit(':').next().and_then(|exp| exp.parse().ok()));
+
+        match expires {
+            Some(expires) if expires > utils::unix_time() => Ok(()),
+            _ => Err(Error::Unauthorized("Invalid or expired login state".to_owned())),
+        }
+    }
+
+    /// Sign `payload`
+    ///
+    /// # Return Value
+    /// `<payload>.<signature>`, both hex encoded.
+    fn seal(&self, payload: &str) -> String {
+        format!("{}.{}", to_hex(payload.as_bytes()), to_hex(self.sign(payload).code()))
+    }
+
+    /// Check the signature of a value sealed with `seal()`
+    ///
+    /// # Return Value
+    /// The payload or `None` if the value is malformed or forged.
+    fn open(&self, sealed: &str) -> Option<String> {
+        let mut parts = sealed.splitn(2, '.');
+        let payload: Vec<u8> = match parts.next().and_then(from_hex) {
+            Some(payload) => payload,
+            None => return None,
+        };
+        let signature: Vec<u8> = match parts.next().and_then(from_hex) {
+            Some(signature) => signature,
+            None => return None,
+        };
+        let payload: String = match String::from_utf8(payload) {
+            Ok(payload) => payload,
+            Err(_) => return None,
+        };
+
+        // MacResult compares in constant time
+        if self.sign(&payload) == MacResult::new(&signature) {
+            Some(payload)
+        } else {
+            None
+        }
+    }
+
+    fn sign(&self, payload: &str) -> MacResult {
+        let mut hmac = Hmac::new(Sha256::new(), &self.secret);
+        hmac.input(payload.as_bytes());
+        hmac.result()
+    }
+}
+
+/// Encode `bytes` in lowercase hexadecimal
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Decode a hexadecimal string
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            str::from_utf8(pair).ok().and_then(|pair| u8::from_str_radix(pair, 16).ok())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use error::Error;
+    use store::User;
+    use super::{Authenticator, Role};
+
+    fn user(id: &str, username: &str) -> User {
+        User {
+            id: id.to_owned(),
+            username: username.to_owned(),
+        }
+    }
+
+    fn authenticator(ttl: u64) -> Authenticator {
+        Authenticator::new(b"secret", ttl, vec!["admin".to_owned()])
+    }
+
+    #[test]
+    fn tokens_carry_the_user_and_its_role() {
+        let auth = authenticator(60);
+
+        let identity = auth.verify(&auth.issue(&user("1", "alice"))).unwrap();
+        assert_eq!(identity.user_id, "1");
+        assert_eq!(identity.role, Role::User);
+
+        let identity = auth.verify(&auth.issue(&user("2", "admin"))).unwrap();
+        assert_eq!(identity.user_id, "2");
+        assert_eq!(identity.role, Role::Admin);
+    }
+
+    #[test]
+    fn expired_tokens_are_refused() {
+        let auth = authenticator(0);
+        let token = auth.issue(&user("1", "alice"));
+
+        match auth.verify(&token) {
+            Err(Error::Unauthorized(message)) => assert!(message.contains("expired")),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn tampered_tokens_are_refused() {
+        let auth = authenticator(60);
+        let token = auth.issue(&user("1", "alice"));
+        let (payload, signature) = token.split_at(token.find('.').unwrap());
+
+        // The role of the payload turned into admin
+        let forged = String::from_utf8(super::from_hex(payload).unwrap())
+            .unwrap()
+            .replace(":user:", ":admin:");
+        assert!(auth.verify(&format!("{}{}", super::to_hex(forged.as_bytes()), signature))
+            .is_err());
+
+        // A signature of another key
+        let other = Authenticator::new(b"other", 60, Vec::new());
+        assert!(auth.verify(&other.issue(&user("1", "alice"))).is_err());
+
+        assert!(auth.verify(payload).is_err());
+        assert!(auth.verify(&format!("{}.00", payload)).is_err());
+        assert!(auth.verify("").is_err());
+    }
+
+    #[test]
+    fn login_keys_carry_the_user() {
+        let auth = authenticator(0);
+
+        let key: String = auth.issue_key(&user("1", "alice:smith"));
+        let found = auth.verify_key(&key).unwrap();
+        assert_eq!(found.id, "1");
+        assert_eq!(found.username, "alice:smith");
+    }
+
+    #[test]
+    fn tampered_login_keys_are_refused() {
+        let auth = authenticator(60);
+        let key: String = auth.issue_key(&user("1", "alice"));
+        let (payload, signature) = key.split_at(key.find('.').unwrap());
+
+        // The key turned into the key of another user
+        let forged = String::from_utf8(super::from_hex(payload).unwrap())
+            .unwrap()
+            .replace(":1:", ":2:");
+        assert!(auth.verify_key(&format!("{}{}", super::to_hex(forged.as_bytes()), signature))
+            .is_err());
+
+        // Tokens and login states aren't login keys
+        assert!(auth.verify_key(&auth.issue(&user("1", "alice"))).is_err());
+        assert!(auth.verify_key(&auth.issue_state()).is_err());
+
+        let other = Authenticator::new(b"other", 60, Vec::new());
+        assert!(auth.verify_key(&other.issue_key(&user("1", "alice"))).is_err());
+        assert!(auth.verify_key("").is_err());
+    }
+
+    #[test]
+    fn admin_usernames_need_the_admin_secret() {
+        let auth = authenticator(60);
+        assert!(auth.authorize_registration("alice", None).is_ok());
+        assert!(auth.authorize_registration("admin", None).is_err());
+        assert!(auth.authorize_registration("admin", Some("")).is_err());
+
+        let auth = authenticator(60).with_admin_secret("bootstrap");
+        assert!(auth.authorize_registration("admin", Some("bootstrap")).is_ok());
+        assert!(auth.authorize_registration("admin", Some("guess")).is_err());
+        assert!(auth.authorize_registration("admin", None).is_err());
+    }
+
+    #[test]
+    fn login_states_are_signed() {
+        let auth = authenticator(60);
+
+        let state: String = auth.issue_state();
+        assert!(auth.verify_state(&state, Some(&state)).is_ok());
+        assert!(auth.verify_state("00.00", Some("00.00")).is_err());
+
+        let other: String = Authenticator::new(b"other", 60, Vec::new()).issue_state();
+        assert!(auth.verify_state(&other, Some(&other)).is_err());
+    }
+
+    #[test]
+    fn login_states_are_bound_to_the_browser() {
+        let auth = authenticator(60);
+        let state: String = auth.issue_state();
+
+        assert!(auth.verify_state(&state, None).is_err());
+        assert!(auth.verify_state(&state, Some(&auth.issue_state())).is_err());
+        assert!(auth.verify_state(&state, Some("")).is_err());
+    }
+}
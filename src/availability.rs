@@ -0,0 +1,187 @@
+//! Rooms free to study in.
+//!
+//! A room is available when it has a known capacity, fewer users checked in
+//! than that capacity and no event scheduled by `FenixEDU` at the requested
+//! time. The users checked in are those of the store now, whatever the time
+//! asked; the schedules are those of that time. A store unable to list every
+//! check in is asked for the occupants of each room instead.
+//!
+//! The rooms are looked at from the most free seats to the least, and the
+//! search stops once `AvailabilityQuery::limit` rooms are found or
+//! `MAX_LOOKUPS` rooms were looked up, so a query costs a bounded number of
+//! requests. A room outside the space of the query is only told apart by
+//! looking up its ancestry, so it counts among the lookups.
+use std::collections::BTreeMap;
+
+use error::{Error, Result};
+use fenix::FenixSpacesClient;
+use schedule::{Day, ScheduledEvent};
+use store::{Room, RoomStore};
+
+/// Most rooms whose schedule, and occupancy when the store can't list the
+/// check-ins, are requested by one query
+pub const MAX_LOOKUPS: usize = 100;
+
+/// Rooms found when the query doesn't set a limit
+pub const DEFAULT_LIMIT: usize = 20;
+
+/// Most rooms found by one query
+pub const MAX_LIMIT: usize = 100;
+
+/// Rooms to look for
+///
+/// # Fields
+/// * `under` => id of the `FenixEDU` space the rooms must be in, at any
+///   depth, `None` for every room;
+/// * `min_capacity` => fewest seats a room must have;
+/// * `at` => UNIX time the rooms must be free at;
+/// * `limit` => most rooms found.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AvailabilityQuery {
+    pub under: Option<String>,
+    pub min_capacity: u64,
+    pub at: u64,
+    pub limit: usize,
+}
+
+/// A room with free seats
+///
+/// # Fields
+/// * `room_id` => id of the room in the store;
+/// * `fenix_id` => id of the space in `FenixEDU`;
+/// * `location` => location of the room;
+/// * `capacity` => number of seats;
+/// * `occupancy` => users checked in;
+/// * `free_seats` => seats left;
+/// * `free_until` => UNIX time the next event of the day starts, if any.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AvailableRoom {
+    pub room_id: String,
+    pub fenix_id: String,
+    pub location: String,
+    pub capacity: u64,
+    pub occupancy: u64,
+    pub free_seats: u64,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub free_until: Option<u64>,
+}
+
+/// A room big enough, before its schedule is known
+///
+/// # Fields
+/// * `room` => the room;
+/// * `capacity` => number of seats;
+/// * `users` => users checked in, `None` until asked to the store.
+struct Candidate {
+    room: Room,
+    capacity: u64,
+    users: Option<u64>,
+}
+
+impl Candidate {
+    /// Seats left, or every seat while the occupancy is unknown
+    fn free_seats(&self) -> u64 {
+        self.capacity - self.users.unwrap_or(0)
+    }
+}
+
+/// Find the rooms of the store matching `query`
+///
+/// One request is made to `FenixEDU` per room looked up, unless its schedule
+/// is cached. Rooms unknown to `FenixEDU` are left out. When the store can't
+/// list every check in, the rooms are looked up from the largest and the
+/// rooms found may not be those with the most free seats.
+///
+/// # Return Value
+/// At most `query.limit` rooms, most free seats first, or the error of the
+/// store or of `FenixEDU`.
+pub fn find(fenix: &FenixSpacesClient,
+            store: &RoomStore,
+            query: &AvailabilityQuery)
+            -> Result<Vec<AvailableRoom>> {
+    let occupancy: Option<BTreeMap<String, u64>> = match store.active_check_ins() {
+        Ok(check_ins) => {
+            let mut occupancy: BTreeMap<String, u64> = BTreeMap::new();
+            for check_in in check_ins {
+                *occupancy.entry(check_in.room_id).or_insert(0) += 1;
+            }
+            Some(occupancy)
+        }
+        Err(Error::Unsupported(_)) => None,
+        Err(err) => return Err(err),
+    };
+
+    let mut candidates: Vec<Candidate> = Vec::new();
+    for room in store.list_rooms()? {
+        let capacity: u64 = match room.capacity {
+            Some(capacity) if capacity >= query.min_capacity => capacity,
+            _ => continue,
+        };
+        let users: Option<u64> = occupancy.as_ref()
+            .map(|occupancy| occupancy.get(&room.id).cloned().unwrap_or(0));
+        if users.map_or(false, |users| users >= capacity) {
+            continue;
+        }
+
+        candidates.push(Candidate {
+            room: room,
+            capacity: capacity,
+            users: users,
+        });
+    }
+    candidates.sort_by(|a, b| {
+        (b.free_seats(), &a.room.location).cmp(&(a.free_seats(), &b.room.location))
+    });
+
+    let day: Day = Day::of(query.at);
+    let mut available: Vec<AvailableRoom> = Vec::new();
+
+    for candidate in candidates.into_iter().take(MAX_LOOKUPS) {
+        if available.len() >= query.limit {
+            break;
+        }
+
+        if let Some(ref space_id) = query.under {
+            match fenix.ancestry(&candidate.room.fenix_id) {
+                Ok(ref ancestry) if ancestry.iter().any(|space| space.id == *space_id) => {}
+                Ok(_) | Err(Error::NotFound(_)) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+
+        let users: u64 = match candidate.users {
+            Some(users) => users,
+            None => store.occupants(&candidate.room.id)?.len() as u64,
+        };
+        if users >= candidate.capacity {
+            continue;
+        }
+
+        let room: Room = candidate.room;
+        let events: Vec<ScheduledEvent> = match fenix.schedule(&room.fenix_id, day) {
+            Ok(events) => events,
+            Err(Error::NotFound(_)) => continue,
+            Err(err) => return Err(err),
+        };
+        if events.iter().any(|event| event.is_active(query.at)) {
+            continue;
+        }
+
+        available.push(AvailableRoom {
+            room_id: room.id,
+            fenix_id: room.fenix_id,
+            location: room.location,
+            capacity: candidate.capacity,
+            occupancy: users,
+            free_seats: candidate.capacity - users,
+            free_until: events.iter()
+                .map(|event| event.start)
+                .filter(|&start| start > query.at)
+                .min(),
+        });
+    }
+
+    available.sort_by(|a, b| (b.free_seats, &a.location).cmp(&(a.free_seats, &b.location)));
+
+    Ok(available)
+}
@@ -1,9 +1,12 @@
 //! Implementation of a server using Pencil and the FenixEDU API with Heroku
 //! support. There are seven routes defined for the API (`/api/`).
 //!
-//! For room management there are only three relevant fields from the FenixEDU
-//! API response: `name`, `containedSpaces` and `capacity`. A space is
-//! considered a room when `containedSpaces` has zero elements.
+//! For room management the relevant fields from the FenixEDU API response are
+//! `name`, `containedSpaces` and `capacity`; `type`, `parentSpace`,
+//! `topLevelSpace`, `blueprint` and `examCapacity` are sent when asked with
+//! the `fields` query argument. A space is considered a room when its `type`
+//! is `ROOM` or `ROOM_SUBDIVISION`, or, without a known type, when
+//! `containedSpaces` has zero elements.
 //!
 //! # REST API
 //!
@@ -11,42 +14,136 @@
 //! * `spaces` => Returns the top level spaces from the FenixEDU API;
 //! * `id/<id>` => Returns the list of contained spaces, name and capacity
 //!                when relevant inside each `id`;
+//! * `id/<id>/path` => Returns the path of a space, with the id, name and
+//!                     slug of every space from its campus;
+//! * `id/<id>/schedule?day=<dd/mm/yyyy>` => Returns the lessons, evaluations
+//!                                          and other events of a space
+//!                                          during a day, today by default;
 //! * `rooms` => Returns the rooms available to check-in and check-out of
 //!              in the DB;
 //! * `path/<my_path>` => Returns the contained spaces, name and capacity
 //!                       when applicable for the specified hierarchical
 //!                       path.
+//! * `path/<my_path>/schedule` => Same as `id/<id>/schedule` for a path;
 //! * `check_in/<room_id>` => Returns the users in the specified room_id.
+//! * `login` => Redirects to the `FenixEDU` login page;
+//! * `login/callback` => Ends the `FenixEDU` login and returns the user and
+//!                       its bearer token;
+//! * `search?q=<text>` => Returns the spaces named like `text`, ranked from
+//!                        the exact matches to those with typos, with their
+//!                        path, type and whether they are rooms;
+//! * `available?path=<path>` => Returns the rooms under a path with free
+//!                              seats and no scheduled event, most free seats
+//!                              first, filtered by `min_capacity` and at the
+//!                              time `at`, at most `limit` of them;
+//! * `stats/cache` => Returns the hit and miss counters of the `FenixEDU`
+//!                    space cache.
+//! * `admin/capacity_overflow` => Returns the percentage of users accepted in
+//!                                a room above its capacity;
+//! * `rooms/<room_id>/history` => Returns the visits of a room between the
+//!                                `from` and `to` query arguments. Admins
+//!                                only;
+//! * `stats/occupancy` => Returns the utilisation, peak occupancy and average
+//!                        stay of every room between `from` and `to`, split
+//!                        by `granularity` (`hour` or `day`). Admins only;
+//! * `reservations` => Returns the active reservations, selected by
+//!                     `room_id`, `user_id`, `from` and `to`;
+//! * `rooms/<room_id>/events` => Streams the check-ins, check-outs and
+//!                               capacity changes of a room as Server-Sent
+//!                               Events;
+//! * `events` => Streams the events of every room.
+//! * `admin/webhooks` => Returns the registered webhooks. Admins only;
+//! * `admin/webhooks/<webhook_id>/deliveries` => Returns the log of the
+//!                                               deliveries to a webhook.
+//!                                               Admins only.
 //!
 //! ## POST
-//! * `create_user` => Creates a user in the database;
+//! * `create_user` => Creates a user in the database and returns its bearer
+//!                    token. The admin usernames need the `admin_secret`.
+//!                    Refused once the `FenixEDU` login is configured;
+//! * `token` => Exchanges the login `key` returned with every token for a
+//!              new token;
 //! * `create_room` => Adds a room to the database. A room exists when
-//!                    the `contained_space` list is empty;
-//! * `check_in` => Adds a user to a specified room.
+//!                    the `contained_space` list is empty. Its location and
+//!                    capacity come from `FenixEDU` unless overridden with
+//!                    `override_location` or `override_capacity`. Admins
+//!                    only;
+//! * `check_in` => Adds a user to a specified room, optionally for an
+//!                 `expected_duration` in seconds. Refused with 409 when the
+//!                 room is full. A user in another room is moved out of it
+//!                 or refused, depending on `checkin_policy`. With
+//!                 `block_during_classes`, refused with 409 during a lesson
+//!                 or an evaluation;
+//! * `admin/capacity_overflow` => Changes the percentage of users accepted
+//!                                in a room above its capacity. Admins only;
+//! * `reservations` => Books a room from `start` to `end`;
+//! * `admin/import` => Creates a room for every room space under a `path`,
+//!                     with the location and capacity of `FenixEDU`, and
+//!                     reports each one as created, skipped or failed.
+//!                     Admins only;
+//! * `admin/webhooks` => Registers a URL receiving the signed events of a
+//!                       room, of the rooms under a path or of every room.
+//!                       Admins only.
 //!
 //! ## DELETE
-//! * `check_out` => Removes a user from a specified room.
+//! * `check_out` => Removes a user from a specified room. The check in is
+//!                  kept in the history;
+//! * `reservations/<reservation_id>` => Cancels a reservation;
+//! * `admin/webhooks/<webhook_id>` => Removes a webhook. Admins only.
+//!
+//! `create_room`, `check_in` and `check_out` require an
+//! `Authorization: Bearer <token>` header. Only admins can check other users
+//! in or out.
+//!
+//! # WebSocket
+//! When `ws_port` is set, clients can subscribe to the occupancy of a set of
+//! rooms or of every room under a path on that port. See
+//! `fenix_rooms::api::subscriptions` for the protocol.
+//!
+//! # Configuration
+//! The upstream endpoints and the listening address are read at startup by
+//! `fenix_rooms::config::Config::load()`. An invalid configuration aborts the
+//! server.
 extern crate fenix_rooms;
 extern crate pencil;
 extern crate hyper;
 extern crate unicase;
 
+use fenix_rooms::api;
 use fenix_rooms::api::handlers;
+use fenix_rooms::config::{Config, StoreBackend};
 use unicase::UniCase;
 use pencil::{Pencil, PencilResult, Request, Response};
 use pencil::method::Method::Options;
 use hyper::method::Method;
-use std::env;
+use std::process;
 use std::collections::BTreeMap;
 use hyper::header::{Headers, AccessControlAllowOrigin, AccessControlAllowHeaders,
                     AccessControlAllowMethods};
 
-fn get_server_port() -> u16 {
-    let port_str = env::var("PORT").unwrap_or(String::new());
-    port_str.parse().unwrap_or(8080)
-}
-
 fn main() {
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            println!("{}", err);
+            process::exit(1);
+        }
+    };
+    let ip = config.listen_addr();
+    if let Some(addr) = config.ws_addr() {
+        println!("WebSocket: {}", addr);
+    }
+    println!("Fenix API: {}", config.fenix_base_url);
+    match config.store_backend {
+        StoreBackend::Http => println!("Database: {}", config.db_base_url),
+        StoreBackend::File => println!("Database: {}", config.store_path),
+    }
+    if let Err(err) = api::configure(config) {
+        println!("{}", err);
+        process::exit(1);
+    }
+    api::start_workers();
+
     // Must use absolute paths
     let mut app = Pencil::new("./asint-js/");
     println!("Root Path: {}", app.root_path);
@@ -69,6 +166,8 @@ fn main() {
     // ///////////////////////////////////////////////////////
     // REST API
     // ///////////////////////////////////////////////////////
+    // Reject invalid bearer tokens before reaching the handlers
+    app.before_request(handlers::authenticate);
 
     // /////
     // OPTIONS
@@ -78,6 +177,11 @@ fn main() {
               &[Options],
               "create_user_options_handler",
               options_handler);
+    // Token
+    app.route("/api/token",
+              &[Options],
+              "token_options_handler",
+              options_handler);
     // Create Room
     app.route("/api/create_room",
               &[Options],
@@ -98,17 +202,63 @@ fn main() {
               &[Options],
               "check_in_get_options_handler",
               options_handler);
+    // Reservations
+    app.route("/api/reservations",
+              &[Options],
+              "reservations_options_handler",
+              options_handler);
+    app.route("/api/reservations/<reservation_id:int>",
+              &[Options],
+              "reservation_options_handler",
+              options_handler);
+    // History
+    app.route("/api/rooms/<room_id:int>/history",
+              &[Options],
+              "room_history_options_handler",
+              options_handler);
+    // Capacity overflow
+    app.route("/api/admin/capacity_overflow",
+              &[Options],
+              "capacity_overflow_options_handler",
+              options_handler);
+    // Import
+    app.route("/api/admin/import",
+              &[Options],
+              "import_options_handler",
+              options_handler);
+    // Webhooks
+    app.route("/api/admin/webhooks",
+              &[Options],
+              "webhooks_options_handler",
+              options_handler);
+    app.route("/api/admin/webhooks/<webhook_id:int>",
+              &[Options],
+              "webhook_options_handler",
+              options_handler);
+    app.route("/api/admin/webhooks/<webhook_id:int>/deliveries",
+              &[Options],
+              "webhook_deliveries_options_handler",
+              options_handler);
 
     // /////
     // GET
     // /////
     // ID
     app.get("/api/id/<id:int>", "id_handler", handlers::id_handler);
+    app.get("/api/id/<id:int>/path",
+            "id_path_handler",
+            handlers::id_path_handler);
+    app.get("/api/id/<id:int>/schedule",
+            "id_schedule_handler",
+            handlers::id_schedule_handler);
     // Spaces
     app.get("/api/spaces", "spaces_handler", handlers::spaces_handler);
     // Rooms
     app.get("/api/rooms", "rooms_handler", handlers::rooms_handler);
-    // Path
+    // Path, the schedule first so it isn't read as a space
+    app.get("/api/path/<my_path:path>/schedule",
+            "path_schedule_handler",
+            handlers::path_schedule_handler);
     app.get("/api/path/<my_path:path>",
             "path_handler",
             handlers::path_handler);
@@ -116,6 +266,49 @@ fn main() {
     app.get("/api/check_in/<room_id:int>",
             "check_in_get_handler",
             handlers::check_in_get_handler);
+    // FenixEDU login
+    app.get("/api/login", "login_handler", handlers::login_handler);
+    app.get("/api/login/callback",
+            "login_callback_handler",
+            handlers::login_callback_handler);
+    // Search
+    app.get("/api/search", "search_handler", handlers::search_handler);
+    // Available rooms
+    app.get("/api/available",
+            "available_handler",
+            handlers::available_handler);
+    // Cache statistics
+    app.get("/api/stats/cache",
+            "cache_stats_handler",
+            handlers::cache_stats_handler);
+    // Events
+    app.get("/api/rooms/<room_id:int>/events",
+            "room_events_handler",
+            handlers::room_events_handler);
+    app.get("/api/events", "events_handler", handlers::events_handler);
+    // Reservations
+    app.get("/api/reservations",
+            "reservations_handler",
+            handlers::reservations_handler);
+    // History
+    app.get("/api/rooms/<room_id:int>/history",
+            "room_history_handler",
+            handlers::room_history_handler);
+    // Occupancy statistics
+    app.get("/api/stats/occupancy",
+            "occupancy_stats_handler",
+            handlers::occupancy_stats_handler);
+    // Capacity overflow
+    app.get("/api/admin/capacity_overflow",
+            "capacity_overflow_handler",
+            handlers::capacity_overflow_handler);
+    // Webhooks
+    app.get("/api/admin/webhooks",
+            "webhooks_handler",
+            handlers::webhooks_handler);
+    app.get("/api/admin/webhooks/<webhook_id:int>/deliveries",
+            "webhook_deliveries_handler",
+            handlers::webhook_deliveries_handler);
 
     // /////
     // POST
@@ -124,6 +317,8 @@ fn main() {
     app.post("/api/create_user",
              "create_user_handler",
              handlers::create_user_handler);
+    // Token
+    app.post("/api/token", "token_handler", handlers::token_handler);
     // Create Room
     app.post("/api/create_room",
              "create_room_handler",
@@ -132,6 +327,20 @@ fn main() {
     app.post("/api/check_in",
              "check_in_handler",
              handlers::check_in_handler);
+    // Reservations
+    app.post("/api/reservations",
+             "create_reservation_handler",
+             handlers::create_reservation_handler);
+    // Capacity overflow
+    app.post("/api/admin/capacity_overflow",
+             "set_capacity_overflow_handler",
+             handlers::set_capacity_overflow_handler);
+    // Import
+    app.post("/api/admin/import", "import_handler", handlers::import_handler);
+    // Webhooks
+    app.post("/api/admin/webhooks",
+             "create_webhook_handler",
+             handlers::create_webhook_handler);
 
     // /////
     // DELETE
@@ -140,15 +349,16 @@ fn main() {
     app.delete("/api/check_out",
                "check_out_handler",
                handlers::check_out_handler);
+    // Cancel Reservation
+    app.delete("/api/reservations/<reservation_id:int>",
+               "cancel_reservation_handler",
+               handlers::cancel_reservation_handler);
+    // Remove Webhook
+    app.delete("/api/admin/webhooks/<webhook_id:int>",
+               "delete_webhook_handler",
+               handlers::delete_webhook_handler);
 
     // Run server
-    let listen_addr = if env::var("DYNO").is_ok() {
-        "0.0.0.0"
-    } else {
-        "127.0.0.1"
-    };
-
-    let ip = format!("{}:{}", listen_addr, get_server_port());
     // debug!("Running on {}", ip);
     app.run(ip.as_str());
 }
@@ -188,6 +398,7 @@ fn options_handler(_: &mut Request) -> PencilResult {
     headers.set(AccessControlAllowMethods(vec![Method::Delete, Method::Get, Method::Post]));
     headers.set(AccessControlAllowHeaders(vec![UniCase("Content-Type".to_owned()),
                                                UniCase("Access-Control-Allow-Origin".to_owned()),
+                                               UniCase("Authorization".to_owned()),
                                                UniCase("Origin".to_owned()),
                                                UniCase("Accept".to_owned())]));
     let mut response = Response::new("");
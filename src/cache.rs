@@ -0,0 +1,125 @@
+//! In-memory cache of `FenixEDU` space documents.
+//!
+//! The documents are kept as the raw JSON returned by `FenixEDU`, keyed by
+//! space id (followed by the query for the schedules, e.g. `123?day=...`), so
+//! they can be decoded into any type. Entries expire after a
+//! TTL and the oldest entries are evicted when the cache is full.
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// A cached document
+struct Entry {
+    body: String,
+    inserted: Instant,
+}
+
+/// Counters of the cache
+///
+/// # Fields
+/// * `hits` => lookups served from the cache;
+/// * `misses` => lookups that were absent or expired;
+/// * `size` => entries currently stored;
+/// * `capacity` => maximum number of entries.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    pub size: usize,
+    pub capacity: usize,
+}
+
+/// Thread-safe TTL cache of space documents
+pub struct SpaceCache {
+    ttl: Duration,
+    capacity: usize,
+    entries: Mutex<HashMap<String, Entry>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl SpaceCache {
+    /// Create a cache
+    ///
+    /// # Arguments
+    /// * `ttl` => time an entry stays valid;
+    /// * `capacity` => maximum number of entries.
+    pub fn new(ttl: Duration, capacity: usize) -> SpaceCache {
+        SpaceCache {
+            ttl: ttl,
+            capacity: capacity,
+            entries: Mutex::new(HashMap::new()),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    /// Get the document of the space `id` if it is cached and still valid
+    pub fn get(&self, id: &str) -> Option<String> {
+        let mut entries = self.entries.lock().unwrap();
+
+        let expired = match entries.get(id) {
+            Some(entry) if entry.inserted.elapsed() < self.ttl => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Some(entry.body.clone());
+            }
+            Some(_) => true,
+            None => false,
+        };
+
+        if expired {
+            entries.remove(id);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        None
+    }
+
+    /// Store the document of the space `id`
+    ///
+    /// When the cache is full the expired entries are dropped and, if that is
+    /// not enough, the oldest one.
+    pub fn insert(&self, id: &str, body: &str) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+
+        if !entries.contains_key(id) && entries.len() >= self.capacity {
+            let ttl = self.ttl;
+            entries.retain(|_, entry| entry.inserted.elapsed() < ttl);
+        }
+
+        if !entries.contains_key(id) && entries.len() >= self.capacity {
+            let oldest: Option<String> = entries.iter()
+                .min_by_key(|&(_, entry)| entry.inserted)
+                .map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+
+        entries.insert(id.to_owned(),
+                       Entry {
+                           body: body.to_owned(),
+                           inserted: Instant::now(),
+                       });
+    }
+
+    /// Drop every entry. The counters are kept.
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// Current counters
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            size: self.entries.lock().unwrap().len(),
+            capacity: self.capacity,
+        }
+    }
+}
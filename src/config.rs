@@ -0,0 +1,723 @@
+//! Runtime configuration of the server.
+//!
+//! The configuration is built in three layers, each one overriding the
+//! previous:
+//!
+//! 1. Built-in defaults (the public `FenixEDU` API and the Heroku database);
+//! 2. A TOML file, read from the path in `FENIX_ROOMS_CONFIG` or from
+//!    `./fenix_rooms.toml` when it exists;
+//! 3. Environment variables (`FENIX_BASE_URL`, `DB_BASE_URL`, `DB_EXTENDED_API`,
+//!    `STORE_BACKEND`, `STORE_PATH`, `CACHE_TTL`, `CACHE_SIZE`, `SNAPSHOT_PATH`,
+//!    `SNAPSHOT_REFRESH`, `AUTH_SECRET`, `TOKEN_TTL`, `ADMIN_USERS`,
+//!    `ADMIN_SECRET`, the
+//!    `OAUTH_*` variables, `CAPACITY_OVERFLOW`, `CHECKIN_TIMEOUT`,
+//!    `CHECKOUT_CUTOFF`, `CHECKIN_POLICY`, `REQUIRE_RESERVATION`,
+//!    `BLOCK_DURING_CLASSES`, `BLOCK_ON_SCHEDULE_ERROR`, `WEBHOOKS_PATH`,
+//!    `MAX_EVENT_STREAMS`, `PORT`, `WS_PORT` and `DYNO`).
+//!
+//! # Example file
+//!
+//! ```toml
+//! fenix_base_url = "http://localhost:3000/api/fenix/v1/spaces"
+//! db_base_url = "http://localhost:4000"
+//! db_extended_api = false
+//! store_backend = "file"
+//! store_path = "./fenix_rooms.db"
+//! cache_ttl = 300
+//! cache_size = 1000
+//! snapshot_path = "./spaces.json"
+//! snapshot_refresh = 86400
+//! auth_secret = "change me"
+//! token_ttl = 2592000
+//! admin_users = ["admin"]
+//! admin_secret = "change me too"
+//! oauth_client_id = "1234"
+//! oauth_client_secret = "secret"
+//! oauth_redirect_uri = "http://localhost:8080/api/login/callback"
+//! capacity_overflow = 10
+//! checkin_timeout = 14400
+//! checkout_cutoff = "23:00"
+//! checkin_policy = "move"
+//! require_reservation = false
+//! block_during_classes = false
+//! block_on_schedule_error = false
+//! webhooks_path = "./webhooks.json"
+//! max_event_streams = 2
+//! host = "127.0.0.1"
+//! port = 8080
+//! ws_port = 8081
+//! ```
+extern crate hyper;
+extern crate toml;
+
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use self::hyper::Url;
+use error::{Error, Result};
+
+// /////////////////////////////////////////////////////////////////////////////
+// Defaults
+// /////////////////////////////////////////////////////////////////////////////
+const DEFAULT_FENIX_BASE_URL: &'static str = "https://fenix.tecnico.ulisboa.pt/api/fenix/v1/spaces";
+const DEFAULT_DB_BASE_URL: &'static str = "https://asint-project.herokuapp.com";
+const DEFAULT_CONFIG_FILE: &'static str = "./fenix_rooms.toml";
+const DEFAULT_PORT: u16 = 8080;
+const DEFAULT_STORE_PATH: &'static str = "./fenix_rooms.db";
+const DEFAULT_CACHE_TTL: u64 = 300;
+const DEFAULT_CACHE_SIZE: usize = 1000;
+const DEFAULT_MAX_EVENT_STREAMS: usize = 2;
+const DEFAULT_SNAPSHOT_REFRESH: u64 = 24 * 60 * 60;
+const DEFAULT_TOKEN_TTL: u64 = 30 * 24 * 60 * 60;
+const DEFAULT_OAUTH_AUTHORIZE_URL: &'static str =
+    "https://fenix.tecnico.ulisboa.pt/oauth/userdialog";
+const DEFAULT_OAUTH_TOKEN_URL: &'static str =
+    "https://fenix.tecnico.ulisboa.pt/oauth/access_token";
+const DEFAULT_OAUTH_PERSON_URL: &'static str =
+    "https://fenix.tecnico.ulisboa.pt/api/fenix/v1/person";
+
+/// Backend storing users, rooms and check-ins
+///
+/// # Values
+/// * `Http` => the REST database at `db_base_url`;
+/// * `File` => the embedded append-only file at `store_path`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StoreBackend {
+    Http,
+    File,
+}
+
+impl StoreBackend {
+    /// Parse the name of a backend (`http` or `file`)
+    pub fn from_name(name: &str) -> Result<StoreBackend> {
+        match name {
+            "http" => Ok(StoreBackend::Http),
+            "file" => Ok(StoreBackend::File),
+            _ => Err(Error::Config(format!("unknown store backend {} (use http or file)", name))),
+        }
+    }
+}
+
+/// What happens when a user already checked in a room checks in another one
+///
+/// # Values
+/// * `Move` => the user is checked out of the previous room;
+/// * `Reject` => the check in is refused until the user checks out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheckInPolicy {
+    Move,
+    Reject,
+}
+
+impl CheckInPolicy {
+    /// Parse the name of a policy (`move` or `reject`)
+    pub fn from_name(name: &str) -> Result<CheckInPolicy> {
+        match name {
+            "move" => Ok(CheckInPolicy::Move),
+            "reject" => Ok(CheckInPolicy::Reject),
+            _ => {
+                Err(Error::Config(format!("unknown check-in policy {} (use move or reject)",
+                                          name)))
+            }
+        }
+    }
+}
+
+/// Server configuration
+///
+/// # Fields
+/// * `fenix_base_url` => base URL of the `FenixEDU` Spaces API;
+/// * `db_base_url` => base URL of the check-in database;
+/// * `db_extended_api` => the database at `db_base_url` has the collections
+///   added to its original schema, see `store::http`;
+/// * `store_backend` => where users, rooms and check-ins are kept;
+/// * `store_path` => file of the `File` backend;
+/// * `cache_ttl` => seconds a `FenixEDU` space stays cached;
+/// * `cache_size` => maximum number of cached spaces, 0 disables the cache;
+/// * `snapshot_path` => file of the space tree snapshot, empty disables it;
+/// * `snapshot_refresh` => seconds between two crawls of the space tree;
+/// * `auth_secret` => key signing the tokens and the login keys, required;
+/// * `token_ttl` => seconds a token stays valid;
+/// * `admin_users` => usernames with the admin role;
+/// * `admin_secret` => secret needed to register a username of
+///   `admin_users` with `/api/create_user`, empty to refuse it;
+/// * `oauth_client_id` => id of the `FenixEDU` application, empty disables
+///   the login;
+/// * `oauth_client_secret` => secret of the `FenixEDU` application;
+/// * `oauth_redirect_uri` => callback registered in the `FenixEDU` application;
+/// * `oauth_authorize_url` => `FenixEDU` login page;
+/// * `oauth_token_url` => `FenixEDU` token endpoint;
+/// * `oauth_person_url` => `FenixEDU` endpoint of the logged in person;
+/// * `capacity_overflow` => percentage of users accepted in a room above its
+///   capacity, 0 refuses check-ins as soon as the room is full;
+/// * `checkin_timeout` => seconds after which a check in without an expected
+///   duration is closed, 0 keeps it open;
+/// * `checkout_cutoff` => time of the day (UTC), in seconds after midnight,
+///   at which every check in is closed. Written `HH:MM`, empty disables it;
+/// * `checkin_policy` => how a check in is handled when the user is already in
+///   another room;
+/// * `require_reservation` => only accept check-ins during a reservation of
+///   the user for the room;
+/// * `block_during_classes` => refuse check-ins while `FenixEDU` schedules a
+///   lesson or an evaluation in the room;
+/// * `block_on_schedule_error` => with `block_during_classes`, refuse the
+///   check-ins when `FenixEDU` can't give the schedule of the room instead of
+///   accepting them;
+/// * `webhooks_path` => file of the registered webhooks, empty keeps them in
+///   memory only;
+/// * `max_event_streams` => most Server-Sent Events streams open at once, each
+///   one holding a worker thread of the server, 0 disables them;
+/// * `host` => address the server listens on;
+/// * `port` => port the server listens on;
+/// * `ws_port` => port of the WebSocket subscriptions, 0 disables them.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub fenix_base_url: String,
+    pub db_base_url: String,
+    pub db_extended_api: bool,
+    pub store_backend: StoreBackend,
+    pub store_path: String,
+    pub cache_ttl: u64,
+    pub cache_size: usize,
+    pub snapshot_path: String,
+    pub snapshot_refresh: u64,
+    pub auth_secret: String,
+    pub token_ttl: u64,
+    pub admin_users: Vec<String>,
+    pub admin_secret: String,
+    pub oauth_client_id: String,
+    pub oauth_client_secret: String,
+    pub oauth_redirect_uri: String,
+    pub oauth_authorize_url: String,
+    pub oauth_token_url: String,
+    pub oauth_person_url: String,
+    pub capacity_overflow: u64,
+    pub checkin_timeout: u64,
+    pub checkout_cutoff: Option<u64>,
+    pub checkin_policy: CheckInPolicy,
+    pub require_reservation: bool,
+    pub block_during_classes: bool,
+    pub block_on_schedule_error: bool,
+    pub webhooks_path: String,
+    pub max_event_streams: usize,
+    pub host: String,
+    pub port: u16,
+    pub ws_port: u16,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            fenix_base_url: DEFAULT_FENIX_BASE_URL.to_owned(),
+            db_base_url: DEFAULT_DB_BASE_URL.to_owned(),
+            db_extended_api: false,
+            store_backend: StoreBackend::Http,
+            store_path: DEFAULT_STORE_PATH.to_owned(),
+            cache_ttl: DEFAULT_CACHE_TTL,
+            cache_size: DEFAULT_CACHE_SIZE,
+            snapshot_path: String::new(),
+            snapshot_refresh: DEFAULT_SNAPSHOT_REFRESH,
+            auth_secret: String::new(),
+            token_ttl: DEFAULT_TOKEN_TTL,
+            admin_users: Vec::new(),
+            admin_secret: String::new(),
+            oauth_client_id: String::new(),
+            oauth_client_secret: String::new(),
+            oauth_redirect_uri: String::new(),
+            oauth_authorize_url: DEFAULT_OAUTH_AUTHORIZE_URL.to_owned(),
+            oauth_token_url: DEFAULT_OAUTH_TOKEN_URL.to_owned(),
+            oauth_person_url: DEFAULT_OAUTH_PERSON_URL.to_owned(),
+            capacity_overflow: 0,
+            checkin_timeout: 0,
+            checkout_cutoff: None,
+            checkin_policy: CheckInPolicy::Move,
+            require_reservation: false,
+            block_during_classes: false,
+            block_on_schedule_error: false,
+            webhooks_path: String::new(),
+            max_event_streams: DEFAULT_MAX_EVENT_STREAMS,
+            host: "127.0.0.1".to_owned(),
+            port: DEFAULT_PORT,
+            ws_port: 0,
+        }
+    }
+}
+
+impl Config {
+    /// Load the configuration from the file and the environment
+    ///
+    /// # Return Value
+    /// The validated configuration or an `Error::Config` describing every problem found.
+    pub fn load() -> Result<Config> {
+        let mut config = Config::default();
+
+        match env::var("FENIX_ROOMS_CONFIG") {
+            Ok(path) => config.merge_file(&path)?,
+            Err(_) => {
+                if Path::new(DEFAULT_CONFIG_FILE).exists() {
+                    config.merge_file(DEFAULT_CONFIG_FILE)?;
+                }
+            }
+        }
+
+        config.merge_env()?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Override the fields present in the TOML file at `path`
+    ///
+    /// # Arguments
+    /// * `path` => path of the TOML file.
+    ///
+    /// # Return Value
+    /// Nothing or an `Error::Config`.
+    pub fn merge_file(&mut self, path: &str) -> Result<()> {
+        let mut contents = String::new();
+        match File::open(path) {
+            Ok(mut file) => {
+                if let Err(err) = file.read_to_string(&mut contents) {
+                    return Err(Error::Config(format!("Unable to read {}: {}", path, err)));
+                }
+            }
+            Err(err) => return Err(Error::Config(format!("Unable to open {}: {}", path, err))),
+        }
+
+        self.merge_toml(&contents).map_err(|err| Error::Config(format!("{}: {}", path, err)))
+    }
+
+    /// Override the fields present in the TOML string `contents`
+    ///
+    /// # Arguments
+    /// * `contents` => TOML document.
+    ///
+    /// # Return Value
+    /// Nothing or an `Error::Config`.
+    pub fn merge_toml(&mut self, contents: &str) -> Result<()> {
+        let mut parser = toml::Parser::new(contents);
+        let table = match parser.parse() {
+            Some(table) => table,
+            None => {
+                let errors: Vec<String> = parser.errors
+                    .iter()
+                    .map(|err| err.desc.clone())
+                    .collect();
+                return Err(Error::Config(format!("invalid TOML: {}", errors.join("; "))));
+            }
+        };
+
+        if let Some(value) = table.get("fenix_base_url") {
+            self.fenix_base_url = toml_string("fenix_base_url", value)?;
+        }
+        if let Some(value) = table.get("db_base_url") {
+            self.db_base_url = toml_string("db_base_url", value)?;
+        }
+        if let Some(value) = table.get("db_extended_api") {
+            self.db_extended_api = toml_bool("db_extended_api", value)?;
+        }
+        if let Some(value) = table.get("store_backend") {
+            self.store_backend = StoreBackend::from_name(&toml_string("store_backend", value)?)?;
+        }
+        if let Some(value) = table.get("store_path") {
+            self.store_path = toml_string("store_path", value)?;
+        }
+        if let Some(value) = table.get("cache_ttl") {
+            self.cache_ttl = toml_unsigned("cache_ttl", value)?;
+        }
+        if let Some(value) = table.get("cache_size") {
+            self.cache_size = toml_unsigned("cache_size", value)? as usize;
+        }
+        if let Some(value) = table.get("snapshot_path") {
+            self.snapshot_path = toml_string("snapshot_path", value)?;
+        }
+        if let Some(value) = table.get("snapshot_refresh") {
+            self.snapshot_refresh = toml_unsigned("snapshot_refresh", value)?;
+        }
+        if let Some(value) = table.get("auth_secret") {
+            self.auth_secret = toml_string("auth_secret", value)?;
+        }
+        if let Some(value) = table.get("token_ttl") {
+            self.token_ttl = toml_unsigned("token_ttl", value)?;
+        }
+        if let Some(value) = table.get("admin_users") {
+            self.admin_users = toml_strings("admin_users", value)?;
+        }
+        if let Some(value) = table.get("admin_secret") {
+            self.admin_secret = toml_string("admin_secret", value)?;
+        }
+        if let Some(value) = table.get("oauth_client_id") {
+            self.oauth_client_id = toml_string("oauth_client_id", value)?;
+        }
+        if let Some(value) = table.get("oauth_client_secret") {
+            self.oauth_client_secret = toml_string("oauth_client_secret", value)?;
+        }
+        if let Some(value) = table.get("oauth_redirect_uri") {
+            self.oauth_redirect_uri = toml_string("oauth_redirect_uri", value)?;
+        }
+        if let Some(value) = table.get("oauth_authorize_url") {
+            self.oauth_authorize_url = toml_string("oauth_authorize_url", value)?;
+        }
+        if let Some(value) = table.get("oauth_token_url") {
+            self.oauth_token_url = toml_string("oauth_token_url", value)?;
+        }
+        if let Some(value) = table.get("oauth_person_url") {
+            self.oauth_person_url = toml_string("oauth_person_url", value)?;
+        }
+        if let Some(value) = table.get("capacity_overflow") {
+            self.capacity_overflow = toml_unsigned("capacity_overflow", value)?;
+        }
+        if let Some(value) = table.get("checkin_timeout") {
+            self.checkin_timeout = toml_unsigned("checkin_timeout", value)?;
+        }
+        if let Some(value) = table.get("checkout_cutoff") {
+            let cutoff: String = toml_string("checkout_cutoff", value)?;
+            self.checkout_cutoff = time_of_day("checkout_cutoff", &cutoff)?;
+        }
+        if let Some(value) = table.get("checkin_policy") {
+            self.checkin_policy = CheckInPolicy::from_name(&toml_string("checkin_policy", value)?)?;
+        }
+        if let Some(value) = table.get("require_reservation") {
+            self.require_reservation = toml_bool("require_reservation", value)?;
+        }
+        if let Some(value) = table.get("block_during_classes") {
+            self.block_during_classes = toml_bool("block_during_classes", value)?;
+        }
+        if let Some(value) = table.get("block_on_schedule_error") {
+            self.block_on_schedule_error = toml_bool("block_on_schedule_error", value)?;
+        }
+        if let Some(value) = table.get("webhooks_path") {
+            self.webhooks_path = toml_string("webhooks_path", value)?;
+        }
+        if let Some(value) = table.get("max_event_streams") {
+            self.max_event_streams = toml_unsigned("max_event_streams", value)? as usize;
+        }
+        if let Some(value) = table.get("host") {
+            self.host = toml_string("host", value)?;
+        }
+        if let Some(value) = table.get("port") {
+            self.port = match value.as_integer() {
+                Some(port) if port > 0 && port <= u16::max_value() as i64 => port as u16,
+                _ => {
+                    return Err(Error::Config("port must be an integer between 1 and 65535"
+                        .to_owned()))
+                }
+            };
+        }
+        if let Some(value) = table.get("ws_port") {
+            self.ws_port = match value.as_integer() {
+                Some(port) if port >= 0 && port <= u16::max_value() as i64 => port as u16,
+                _ => {
+                    return Err(Error::Config("ws_port must be an integer between 0 and 65535"
+                        .to_owned()))
+                }
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Override the fields set in the environment
+    ///
+    /// `DYNO` is set by Heroku, in which case the server must listen on every
+    /// interface.
+    ///
+    /// # Return Value
+    /// Nothing or an `Error::Config`.
+    pub fn merge_env(&mut self) -> Result<()> {
+        if let Ok(url) = env::var("FENIX_BASE_URL") {
+            self.fenix_base_url = url;
+        }
+        if let Ok(url) = env::var("DB_BASE_URL") {
+            self.db_base_url = url;
+        }
+        if let Ok(extended) = env::var("DB_EXTENDED_API") {
+            self.db_extended_api = env_bool("DB_EXTENDED_API", &extended)?;
+        }
+        if let Ok(backend) = env::var("STORE_BACKEND") {
+            self.store_backend = StoreBackend::from_name(&backend)?;
+        }
+        if let Ok(path) = env::var("STORE_PATH") {
+            self.store_path = path;
+        }
+        if let Ok(ttl) = env::var("CACHE_TTL") {
+            self.cache_ttl = env_unsigned("CACHE_TTL", &ttl)?;
+        }
+        if let Ok(size) = env::var("CACHE_SIZE") {
+            self.cache_size = env_unsigned("CACHE_SIZE", &size)? as usize;
+        }
+        if let Ok(path) = env::var("SNAPSHOT_PATH") {
+            self.snapshot_path = path;
+        }
+        if let Ok(refresh) = env::var("SNAPSHOT_REFRESH") {
+            self.snapshot_refresh = env_unsigned("SNAPSHOT_REFRESH", &refresh)?;
+        }
+        if let Ok(secret) = env::var("AUTH_SECRET") {
+            self.auth_secret = secret;
+        }
+        if let Ok(ttl) = env::var("TOKEN_TTL") {
+            self.token_ttl = env_unsigned("TOKEN_TTL", &ttl)?;
+        }
+        if let Ok(admins) = env::var("ADMIN_USERS") {
+            self.admin_users = admins.split(',')
+                .map(|admin| admin.trim().to_owned())
+                .filter(|admin| !admin.is_empty())
+                .collect();
+        }
+        if let Ok(secret) = env::var("ADMIN_SECRET") {
+            self.admin_secret = secret;
+        }
+        if let Ok(id) = env::var("OAUTH_CLIENT_ID") {
+            self.oauth_client_id = id;
+        }
+        if let Ok(secret) = env::var("OAUTH_CLIENT_SECRET") {
+            self.oauth_client_secret = secret;
+        }
+        if let Ok(uri) = env::var("OAUTH_REDIRECT_URI") {
+            self.oauth_redirect_uri = uri;
+        }
+        if let Ok(url) = env::var("OAUTH_AUTHORIZE_URL") {
+            self.oauth_authorize_url = url;
+        }
+        if let Ok(url) = env::var("OAUTH_TOKEN_URL") {
+            self.oauth_token_url = url;
+        }
+        if let Ok(url) = env::var("OAUTH_PERSON_URL") {
+            self.oauth_person_url = url;
+        }
+        if let Ok(overflow) = env::var("CAPACITY_OVERFLOW") {
+            self.capacity_overflow = env_unsigned("CAPACITY_OVERFLOW", &overflow)?;
+        }
+        if let Ok(timeout) = env::var("CHECKIN_TIMEOUT") {
+            self.checkin_timeout = env_unsigned("CHECKIN_TIMEOUT", &timeout)?;
+        }
+        if let Ok(cutoff) = env::var("CHECKOUT_CUTOFF") {
+            self.checkout_cutoff = time_of_day("CHECKOUT_CUTOFF", &cutoff)?;
+        }
+        if let Ok(policy) = env::var("CHECKIN_POLICY") {
+            self.checkin_policy = CheckInPolicy::from_name(&policy)?;
+        }
+        if let Ok(required) = env::var("REQUIRE_RESERVATION") {
+            self.require_reservation = env_bool("REQUIRE_RESERVATION", &required)?;
+        }
+        if let Ok(blocked) = env::var("BLOCK_DURING_CLASSES") {
+            self.block_during_classes = env_bool("BLOCK_DURING_CLASSES", &blocked)?;
+        }
+        if let Ok(blocked) = env::var("BLOCK_ON_SCHEDULE_ERROR") {
+            self.block_on_schedule_error = env_bool("BLOCK_ON_SCHEDULE_ERROR", &blocked)?;
+        }
+        if let Ok(path) = env::var("WEBHOOKS_PATH") {
+            self.webhooks_path = path;
+        }
+        if let Ok(streams) = env::var("MAX_EVENT_STREAMS") {
+            self.max_event_streams = env_unsigned("MAX_EVENT_STREAMS", &streams)? as usize;
+        }
+        if env::var("DYNO").is_ok() {
+            self.host = "0.0.0.0".to_owned();
+        }
+        if let Ok(port) = env::var("PORT") {
+            self.port = match port.parse() {
+                Ok(port) => port,
+                Err(_) => return Err(Error::Config(format!("PORT is not a valid port: {}", port))),
+            };
+        }
+        if let Ok(port) = env::var("WS_PORT") {
+            self.ws_port = match port.parse() {
+                Ok(port) => port,
+                Err(_) => {
+                    return Err(Error::Config(format!("WS_PORT is not a valid port: {}", port)))
+                }
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Check every field and normalize the URLs (no trailing slash)
+    ///
+    /// # Return Value
+    /// Nothing or an `Error::Config` with every problem found.
+    pub fn validate(&mut self) -> Result<()> {
+        let mut errors: Vec<String> = Vec::new();
+
+        match validate_url(&self.fenix_base_url) {
+            Ok(url) => self.fenix_base_url = url,
+            Err(err) => errors.push(format!("fenix_base_url: {}", err)),
+        }
+        match validate_url(&self.db_base_url) {
+            Ok(url) => self.db_base_url = url,
+            Err(err) => errors.push(format!("db_base_url: {}", err)),
+        }
+
+        if self.store_backend == StoreBackend::File && self.store_path.is_empty() {
+            errors.push("store_path must not be empty with the file backend".to_owned());
+        }
+        if !self.snapshot_path.is_empty() && self.snapshot_refresh == 0 {
+            errors.push("snapshot_refresh must not be 0".to_owned());
+        }
+        if !self.oauth_client_id.is_empty() {
+            if self.oauth_client_secret.is_empty() {
+                errors.push("oauth_client_secret is required with oauth_client_id".to_owned());
+            }
+            for &(name, url) in &[("oauth_redirect_uri", &self.oauth_redirect_uri),
+                                  ("oauth_authorize_url", &self.oauth_authorize_url),
+                                  ("oauth_token_url", &self.oauth_token_url),
+                                  ("oauth_person_url", &self.oauth_person_url)] {
+                if let Err(err) = validate_url(url) {
+                    errors.push(format!("{}: {}", name, err));
+                }
+            }
+            if !self.has_extended_store() {
+                errors.push("oauth_client_id needs the file backend or db_extended_api, the \
+                             original database can't look users up"
+                    .to_owned());
+            }
+        }
+        if (self.checkin_timeout > 0 || self.checkout_cutoff.is_some()) &&
+           !self.has_extended_store() {
+            errors.push("checkin_timeout and checkout_cutoff need the file backend or \
+                         db_extended_api, the original database can't list the check-ins"
+                .to_owned());
+        }
+        if self.require_reservation && !self.has_extended_store() {
+            errors.push("require_reservation needs the file backend or db_extended_api, the \
+                         original database has no reservations"
+                .to_owned());
+        }
+        if self.auth_secret.is_empty() {
+            errors.push("auth_secret must not be empty, the tokens wouldn't survive a restart"
+                .to_owned());
+        }
+        if self.token_ttl == 0 {
+            errors.push("token_ttl must not be 0".to_owned());
+        }
+        if self.host.is_empty() {
+            errors.push("host must not be empty".to_owned());
+        }
+        if self.port == 0 {
+            errors.push("port must not be 0".to_owned());
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Config(format!("Invalid configuration: {}", errors.join("; "))))
+        }
+    }
+
+    /// True when the store has every collection: the file backend or a
+    /// database with the extended API
+    pub fn has_extended_store(&self) -> bool {
+        self.store_backend == StoreBackend::File || self.db_extended_api
+    }
+
+    /// Address the server should bind to
+    pub fn listen_addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    /// Address the WebSocket server should bind to, if enabled
+    pub fn ws_addr(&self) -> Option<String> {
+        if self.ws_port == 0 {
+            None
+        } else {
+            Some(format!("{}:{}", self.host, self.ws_port))
+        }
+    }
+}
+
+/// Extract a string from a TOML value
+fn toml_string(name: &str, value: &toml::Value) -> Result<String> {
+    match value.as_str() {
+        Some(string) => Ok(string.to_owned()),
+        None => Err(Error::Config(format!("{} must be a string", name))),
+    }
+}
+
+/// Extract a list of strings from a TOML value
+fn toml_strings(name: &str, value: &toml::Value) -> Result<Vec<String>> {
+    let error = || Error::Config(format!("{} must be a list of strings", name));
+
+    match value.as_slice() {
+        Some(values) => {
+            values.iter()
+                .map(|value| value.as_str().map(|string| string.to_owned()).ok_or_else(&error))
+                .collect()
+        }
+        None => Err(error()),
+    }
+}
+
+/// Extract a non-negative integer from a TOML value
+fn toml_unsigned(name: &str, value: &toml::Value) -> Result<u64> {
+    match value.as_integer() {
+        Some(number) if number >= 0 => Ok(number as u64),
+        _ => Err(Error::Config(format!("{} must be a non-negative integer", name))),
+    }
+}
+
+/// Extract a boolean from a TOML value
+fn toml_bool(name: &str, value: &toml::Value) -> Result<bool> {
+    match value.as_bool() {
+        Some(boolean) => Ok(boolean),
+        None => Err(Error::Config(format!("{} must be true or false", name))),
+    }
+}
+
+/// Parse a boolean from an environment variable
+fn env_bool(name: &str, value: &str) -> Result<bool> {
+    match value {
+        "true" | "1" => Ok(true),
+        "false" | "0" => Ok(false),
+        _ => Err(Error::Config(format!("{} must be true or false: {}", name, value))),
+    }
+}
+
+/// Parse a non-negative integer from an environment variable
+fn env_unsigned(name: &str, value: &str) -> Result<u64> {
+    match value.parse() {
+        Ok(number) => Ok(number),
+        Err(_) => Err(Error::Config(format!("{} must be a non-negative integer: {}", name, value))),
+    }
+}
+
+/// Parse a time of the day written `HH:MM`
+///
+/// # Return Value
+/// The seconds after midnight, `None` for an empty value or an `Error::Config`.
+fn time_of_day(name: &str, value: &str) -> Result<Option<u64>> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+
+    let invalid = || Error::Config(format!("{} must be a time written HH:MM: {}", name, value));
+    let mut parts = value.splitn(2, ':');
+    let hours: u64 = parts.next().and_then(|hours| hours.parse().ok()).ok_or_else(&invalid)?;
+    let minutes: u64 = parts.next().and_then(|minutes| minutes.parse().ok()).ok_or_else(&invalid)?;
+
+    if hours < 24 && minutes < 60 {
+        Ok(Some(hours * 60 * 60 + minutes * 60))
+    } else {
+        Err(invalid())
+    }
+}
+
+/// Check that `url` is an absolute HTTP(S) URL
+///
+/// # Return Value
+/// The URL without trailing slashes or an `Error::Config`.
+fn validate_url(url: &str) -> Result<String> {
+    match Url::parse(url) {
+        Ok(parsed) => {
+            if parsed.scheme() == "http" || parsed.scheme() == "https" {
+                Ok(url.trim_right_matches('/').to_owned())
+            } else {
+                Err(Error::Config(format!("unsupported scheme in {}", url)))
+            }
+        }
+        Err(err) => Err(Error::Config(format!("{} is not a valid URL ({})", url, err))),
+    }
+}
@@ -0,0 +1,204 @@
+//! Errors of the crate.
+//!
+//! Every fallible function returns an `Error`. The handlers turn it into a
+//! JSON response with `Error::status_code()` and `Error::to_json()`, so the
+//! client always receives `{"error": "<message>"}` with a matching status.
+//! Errors the client may need to handle, such as a full room, add a
+//! `reason` and the fields explaining it.
+extern crate serde_json;
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::result;
+
+/// Result type used across the crate
+pub type Result<T> = result::Result<T, Error>;
+
+/// Possible errors
+///
+/// # Values
+/// * `Transport` => the request to an upstream service could not be performed;
+/// * `UpstreamStatus` => an upstream service (`FenixEDU` or the database)
+///   answered with an unexpected status;
+/// * `Decode` => a body could not be read or converted from JSON;
+/// * `NotFound` => the requested resource doesn't exist;
+/// * `Validation` => the request is malformed or misses arguments;
+/// * `Unauthorized` => the client isn't authenticated or its token is invalid;
+/// * `Forbidden` => the client is not allowed to perform the operation;
+/// * `Conflict` => the operation collides with the current state;
+/// * `RoomFull` => a check in was refused because the room `room_id` already
+///   has `occupancy` users and accepts at most `limit`;
+/// * `Unprocessable` => the request is well formed but was refused;
+/// * `UnsupportedMediaType` => the request isn't JSON;
+/// * `Unsupported` => the backend in use can't perform the operation;
+/// * `Unavailable` => the server can't serve the request right now;
+/// * `Config` => the configuration is invalid;
+/// * `Internal` => anything else that is our fault.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    Transport(String),
+    UpstreamStatus(String, u16),
+    Decode(String),
+    NotFound(String),
+    Validation(String),
+    Unauthorized(String),
+    Forbidden(String),
+    Conflict(String),
+    RoomFull {
+        room_id: String,
+        occupancy: u64,
+        limit: u64,
+    },
+    Unprocessable(String),
+    UnsupportedMediaType,
+    Unsupported(String),
+    Unavailable(String),
+    Config(String),
+    Internal(String),
+}
+
+/// Body of every error response. The optional fields are only sent by the
+/// errors that carry them.
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+    #[serde(skip_serializing_if="Option::is_none")]
+    reason: Option<&'static str>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    occupancy: Option<u64>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    limit: Option<u64>,
+}
+
+impl Error {
+    /// HTTP status code sent to the client for this error
+    pub fn status_code(&self) -> u16 {
+        match *self {
+            Error::Transport(_) => 502,
+            Error::UpstreamStatus(..) => 503,
+            Error::Decode(_) => 500,
+            Error::NotFound(_) => 404,
+            Error::Validation(_) => 400,
+            Error::Unauthorized(_) => 401,
+            Error::Forbidden(_) => 403,
+            Error::Conflict(_) => 409,
+            Error::RoomFull { .. } => 409,
+            Error::Unprocessable(_) => 422,
+            Error::UnsupportedMediaType => 415,
+            Error::Unsupported(_) => 501,
+            Error::Unavailable(_) => 503,
+            Error::Config(_) => 500,
+            Error::Internal(_) => 500,
+        }
+    }
+
+    /// Machine readable reason of the error, for the errors the client may
+    /// want to tell apart from others with the same status code
+    pub fn reason(&self) -> Option<&'static str> {
+        match *self {
+            Error::RoomFull { .. } => Some("room_full"),
+            _ => None,
+        }
+    }
+
+    /// Serialize the error as the JSON body sent to the client
+    pub fn to_json(&self) -> String {
+        let (occupancy, limit) = match *self {
+            Error::RoomFull { occupancy, limit, .. } => (Some(occupancy), Some(limit)),
+            _ => (None, None),
+        };
+        let body = ErrorBody {
+            error: self.to_string(),
+            reason: self.reason(),
+            occupancy: occupancy,
+            limit: limit,
+        };
+
+        // Serializing a single string can't fail, but never panic on an error path
+        serde_json::to_string(&body)
+            .unwrap_or_else(|_| "{\"error\": \"Internal error\"}".to_owned())
+    }
+
+    /// Build the error matching a status code returned by an upstream service
+    ///
+    /// Client errors are forwarded with their message, everything else is
+    /// reported as a failure of `service`.
+    ///
+    /// # Arguments
+    /// * `service` => name of the upstream service;
+    /// * `status` => status code received;
+    /// * `body` => body received. When it is a JSON object with an `error`
+    ///   field, only that field is kept.
+    pub fn from_upstream(service: &str, status: u16, body: &str) -> Error {
+        let message = match serde_json::from_str::<serde_json::Value>(body) {
+            Ok(json) => {
+                match json.find("error").and_then(|err| err.as_str()) {
+                    Some(err) => err.to_owned(),
+                    None => body.to_owned(),
+                }
+            }
+            Err(_) => body.to_owned(),
+        };
+
+        match status {
+            400 => Error::Validation(message),
+            403 => Error::Forbidden(message),
+            404 => Error::NotFound(message),
+            409 => Error::Conflict(message),
+            422 => Error::Unprocessable(message),
+            _ => Error::UpstreamStatus(service.to_owned(), status),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Transport(ref msg) |
+            Error::Decode(ref msg) |
+            Error::NotFound(ref msg) |
+            Error::Validation(ref msg) |
+            Error::Unauthorized(ref msg) |
+            Error::Forbidden(ref msg) |
+            Error::Conflict(ref msg) |
+            Error::Unprocessable(ref msg) |
+            Error::Unsupported(ref msg) |
+            Error::Unavailable(ref msg) |
+            Error::Config(ref msg) |
+            Error::Internal(ref msg) => write!(f, "{}", msg),
+            Error::RoomFull { ref room_id, occupancy, limit } => {
+                write!(f,
+                       "The room {} is full ({} of {} places taken)",
+                       room_id,
+                       occupancy,
+                       limit)
+            }
+            Error::UpstreamStatus(ref service, status) => {
+                write!(f, "{} had an error (status {})", service, status)
+            }
+            Error::UnsupportedMediaType => write!(f, "Wrong content-type used"),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Transport(_) => "transport error",
+            Error::UpstreamStatus(..) => "upstream service error",
+            Error::Decode(_) => "decode error",
+            Error::NotFound(_) => "not found",
+            Error::Validation(_) => "validation error",
+            Error::Unauthorized(_) => "unauthorized",
+            Error::Forbidden(_) => "forbidden",
+            Error::Conflict(_) => "conflict",
+            Error::RoomFull { .. } => "room full",
+            Error::Unprocessable(_) => "unprocessable entity",
+            Error::UnsupportedMediaType => "unsupported media type",
+            Error::Unsupported(_) => "unsupported operation",
+            Error::Unavailable(_) => "service unavailable",
+            Error::Config(_) => "configuration error",
+            Error::Internal(_) => "internal error",
+        }
+    }
+}
@@ -0,0 +1,213 @@
+//! Occupancy events.
+//!
+//! Every check in, check out, creation of a room, room becoming full and
+//! change of the capacity of a room is published as an `Event` on an
+//! `EventBus`. Subscribers receive the events
+//! matching their filter through a bounded channel; a subscriber too slow to
+//! keep up loses the events that don't fit, handed to its `Overflow` when it
+//! has one, and one that went away is dropped on the next publication.
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+
+use error::Result;
+use store::{RoomStore, CheckOutReason};
+use utils;
+
+/// Events kept for a subscriber that doesn't read them
+const BUFFER: usize = 64;
+
+/// Kind of an event
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum EventKind {
+    #[serde(rename="check_in")]
+    CheckIn,
+    #[serde(rename="check_out")]
+    CheckOut,
+    #[serde(rename="capacity")]
+    Capacity,
+    /// A check in took the last place of the room
+    #[serde(rename="room_full")]
+    RoomFull,
+    #[serde(rename="room_created")]
+    RoomCreated,
+    /// Current state of a room, sent to new subscribers
+    #[serde(rename="occupancy")]
+    Occupancy,
+}
+
+impl EventKind {
+    /// Name of the kind, as serialized
+    pub fn name(&self) -> &'static str {
+        match *self {
+            EventKind::CheckIn => "check_in",
+            EventKind::CheckOut => "check_out",
+            EventKind::Capacity => "capacity",
+            EventKind::RoomFull => "room_full",
+            EventKind::RoomCreated => "room_created",
+            EventKind::Occupancy => "occupancy",
+        }
+    }
+
+    /// Kind named `name`, as serialized
+    pub fn from_name(name: &str) -> Option<EventKind> {
+        match name {
+            "check_in" => Some(EventKind::CheckIn),
+            "check_out" => Some(EventKind::CheckOut),
+            "capacity" => Some(EventKind::Capacity),
+            "room_full" => Some(EventKind::RoomFull),
+            "room_created" => Some(EventKind::RoomCreated),
+            "occupancy" => Some(EventKind::Occupancy),
+            _ => None,
+        }
+    }
+}
+
+/// Something that happened in a room
+///
+/// # Fields
+/// * `kind` => what happened;
+/// * `room_id` => id of the room;
+/// * `user_id` => user checked in, out or taking the last place, `None` for
+///   the other events;
+/// * `reason` => why the user left, for check-outs;
+/// * `occupancy` => users in the room after the event;
+/// * `limit` => users the room accepts, `None` when it has no limit;
+/// * `time` => UNIX time of the event.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Event {
+    pub kind: EventKind,
+    pub room_id: String,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub user_id: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub reason: Option<CheckOutReason>,
+    pub occupancy: u64,
+    pub limit: Option<u64>,
+    pub time: u64,
+}
+
+impl Event {
+    /// Build the event `kind` for the room `room_id`, reading its occupancy and
+    /// limit from `store`
+    ///
+    /// # Arguments
+    /// * `store` => store of the room;
+    /// * `kind` => what happened;
+    /// * `room_id` => id of the room;
+    /// * `user_id` => user checked in or out, if any;
+    /// * `reason` => why the user left, for check-outs;
+    /// * `overflow` => the `Config::capacity_overflow` in use.
+    pub fn build(store: &RoomStore,
+                 kind: EventKind,
+                 room_id: &str,
+                 user_id: Option<&str>,
+                 reason: Option<CheckOutReason>,
+                 overflow: u64)
+                 -> Result<Event> {
+        let limit: Option<u64> = store.room(room_id)?.capacity_limit(overflow);
+        let occupancy = store.occupants(room_id)?.len() as u64;
+
+        Ok(Event {
+            kind: kind,
+            room_id: room_id.to_owned(),
+            user_id: user_id.map(|user_id| user_id.to_owned()),
+            reason: reason,
+            occupancy: occupancy,
+            limit: limit,
+            time: utils::unix_time(),
+        })
+    }
+
+    /// True when the room holds as many users as it accepts
+    pub fn is_full(&self) -> bool {
+        match self.limit {
+            Some(limit) => self.occupancy >= limit,
+            None => false,
+        }
+    }
+}
+
+/// Filter of the events wanted by a subscriber
+pub type Filter = Box<Fn(&Event) -> bool + Send>;
+
+/// Called, by the publisher, with each event lost by a subscriber whose
+/// channel is full. It must not block.
+pub type Overflow = Box<Fn(&Event) + Send>;
+
+struct Subscriber {
+    filter: Filter,
+    sender: SyncSender<Event>,
+    overflow: Option<Overflow>,
+}
+
+/// Dispatches the events to the subscribers
+pub struct EventBus {
+    subscribers: Mutex<Vec<Subscriber>>,
+}
+
+impl EventBus {
+    /// Create a bus without subscribers
+    pub fn new() -> EventBus {
+        EventBus { subscribers: Mutex::new(Vec::new()) }
+    }
+
+    /// Subscribe to the events accepted by `filter`
+    ///
+    /// # Return Value
+    /// The channel receiving the events. Dropping it ends the subscription.
+    pub fn subscribe(&self, filter: Filter) -> Receiver<Event> {
+        self.add_subscriber(filter, None)
+    }
+
+    /// Subscribe to the events accepted by `filter`, handing to `overflow`
+    /// the events lost when the channel is full
+    ///
+    /// # Return Value
+    /// The channel receiving the events. Dropping it ends the subscription.
+    pub fn subscribe_with_overflow(&self, filter: Filter, overflow: Overflow) -> Receiver<Event> {
+        self.add_subscriber(filter, Some(overflow))
+    }
+
+    fn add_subscriber(&self, filter: Filter, overflow: Option<Overflow>) -> Receiver<Event> {
+        let (sender, receiver) = mpsc::sync_channel(BUFFER);
+        self.subscribers.lock().unwrap().push(Subscriber {
+            filter: filter,
+            sender: sender,
+            overflow: overflow,
+        });
+
+        receiver
+    }
+
+    /// Send `event` to every subscriber accepting it
+    pub fn publish(&self, event: Event) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+
+        subscribers.retain(|subscriber| {
+            if !(subscriber.filter)(&event) {
+                return true;
+            }
+            match subscriber.sender.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(event)) => {
+                    if let Some(ref overflow) = subscriber.overflow {
+                        overflow(&event);
+                    }
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+
+    /// Number of subscribers
+    pub fn subscribers(&self) -> usize {
+        self.subscribers.lock().unwrap().len()
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> EventBus {
+        EventBus::new()
+    }
+}
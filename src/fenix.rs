@@ -0,0 +1,558 @@
+//! Typed client for the `FenixEDU` Spaces API.
+//!
+//! `FenixSpacesClient` performs the GET requests to the Spaces API and converts
+//! the responses into `Space` and `ContainedSpace` values, and the schedules
+//! of the spaces into `ScheduledEvent` values, so it can be used without going
+//! through the HTTP server. A `SnapshotStore` and a
+//! `SpaceCache` can be attached to the client to avoid repeating the same
+//! requests: documents are looked up in the snapshot, then in the cache and
+//! only then requested to `FenixEDU`.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use fenix_rooms::fenix::FenixSpacesClient;
+//!
+//! let client = FenixSpacesClient::new("https://fenix.tecnico.ulisboa.pt/api/fenix/v1/spaces");
+//! let space = client.resolve_path(&["alameda", "pavilhao-central"]).unwrap();
+//! println!("{} has {} spaces", space.name, space.contained_spaces.len());
+//! ```
+extern crate hyper;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use self::hyper::status::StatusCode;
+use self::hyper::client::Response as HyperResponse;
+
+use cache::SpaceCache;
+use config::Config;
+use error::{Error, Result};
+use schedule::{self, Day, ScheduledEvent};
+use snapshot::SnapshotStore;
+use utils;
+
+// /////////////////////////////////////////////////////////////////////////////
+// Basic Structs
+// /////////////////////////////////////////////////////////////////////////////
+/// A space as returned by `FenixEDU`
+///
+/// # Fields
+/// * `id` => id of the space;
+/// * `name` => name of the space;
+/// * `kind` => type of the space, e.g. `BUILDING`;
+/// * `contained_spaces` => spaces directly inside this one;
+/// * `parent_space` => space containing this one, `None` for the campi;
+/// * `top_level_space` => campus of the space;
+/// * `capacity` => seats of the space, for rooms;
+/// * `blueprint` => blueprint of the space, if any.
+#[derive(Deserialize, Serialize, Default, Debug, Clone)]
+pub struct Space {
+    #[serde(default, skip_serializing_if="String::is_empty")]
+    pub id: String,
+    pub name: String,
+    #[serde(rename="type", default, skip_serializing_if="Option::is_none")]
+    pub kind: Option<SpaceType>,
+    #[serde(rename="containedSpaces")]
+    pub contained_spaces: Vec<ContainedSpace>,
+    #[serde(rename="parentSpace", default, skip_serializing_if="Option::is_none")]
+    pub parent_space: Option<SpaceRef>,
+    #[serde(rename="topLevelSpace", default, skip_serializing_if="Option::is_none")]
+    pub top_level_space: Option<SpaceRef>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub capacity: Option<Capacity>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub blueprint: Option<Blueprint>,
+}
+
+impl Space {
+    /// True when the space is a room or a part of one. Spaces of an unknown
+    /// type are rooms when they contain no other space.
+    pub fn is_room(&self) -> bool {
+        match self.kind {
+            Some(SpaceType::Other(_)) | None => self.contained_spaces.is_empty(),
+            Some(ref kind) => kind.is_room(),
+        }
+    }
+}
+
+/// Type of a space
+#[derive(Debug, Clone, PartialEq)]
+pub enum SpaceType {
+    Campus,
+    Building,
+    Floor,
+    Room,
+    RoomSubdivision,
+    /// A type unknown to this crate, with its name
+    Other(String),
+}
+
+impl SpaceType {
+    /// Type named `name` by `FenixEDU`
+    pub fn from_name(name: &str) -> SpaceType {
+        match name {
+            "CAMPUS" => SpaceType::Campus,
+            "BUILDING" => SpaceType::Building,
+            "FLOOR" => SpaceType::Floor,
+            "ROOM" => SpaceType::Room,
+            "ROOM_SUBDIVISION" => SpaceType::RoomSubdivision,
+            _ => SpaceType::Other(name.to_owned()),
+        }
+    }
+
+    /// Name of the type in `FenixEDU`
+    pub fn name(&self) -> &str {
+        match *self {
+            SpaceType::Campus => "CAMPUS",
+            SpaceType::Building => "BUILDING",
+            SpaceType::Floor => "FLOOR",
+            SpaceType::Room => "ROOM",
+            SpaceType::RoomSubdivision => "ROOM_SUBDIVISION",
+            SpaceType::Other(ref name) => name,
+        }
+    }
+
+    /// True for the rooms and their subdivisions
+    pub fn is_room(&self) -> bool {
+        *self == SpaceType::Room || *self == SpaceType::RoomSubdivision
+    }
+}
+
+impl Serialize for SpaceType {
+    fn serialize<S>(&self, serializer: &mut S) -> ::std::result::Result<(), S::Error>
+        where S: Serializer
+    {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl Deserialize for SpaceType {
+    fn deserialize<D>(deserializer: &mut D) -> ::std::result::Result<SpaceType, D::Error>
+        where D: Deserializer
+    {
+        String::deserialize(deserializer).map(|name| SpaceType::from_name(&name))
+    }
+}
+
+/// Reference to a space inside another one
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ContainedSpace {
+    pub id: String,
+    pub name: String,
+}
+
+/// Reference to the parent or the campus of a space
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SpaceRef {
+    pub id: String,
+    pub name: String,
+    #[serde(rename="type", default, skip_serializing_if="Option::is_none")]
+    pub kind: Option<SpaceType>,
+}
+
+/// A space of the path leading to another one
+///
+/// # Fields
+/// * `id` => id of the space;
+/// * `name` => name of the space;
+/// * `slug` => name of the space as written in `/api/path/`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PathSegment {
+    pub id: String,
+    pub name: String,
+    pub slug: String,
+}
+
+/// Capacity of a space
+///
+/// # Fields
+/// * `normal` => seats in normal use;
+/// * `exam` => seats during exams, when known.
+#[derive(Default, Deserialize, Serialize, Debug, Clone)]
+pub struct Capacity {
+    pub normal: u64,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub exam: Option<u64>,
+}
+
+/// Blueprint of a space
+///
+/// # Fields
+/// * `format` => format of the image, e.g. `jpeg`;
+/// * `link` => URL of the image in the Spaces API.
+#[derive(Default, Deserialize, Serialize, Debug, Clone)]
+pub struct Blueprint {
+    #[serde(default)]
+    pub format: String,
+    pub link: String,
+}
+
+/// Position of the space named `name` among the siblings `spaces`
+///
+/// `name` is compared with the slugs of the siblings. A segment that isn't a
+/// slug, e.g. `sala-0.08`, is first compared with their `utils::legacy_slug()`
+/// so that the paths of the first releases keep their space.
+fn slug_position(name: &str, spaces: &[ContainedSpace]) -> Option<usize> {
+    let slug: String = utils::slugify(name);
+    if slug.is_empty() {
+        return None;
+    }
+
+    if slug != name {
+        let legacy: String = name.to_lowercase();
+        let position = spaces.iter().position(|space| utils::legacy_slug(&space.name) == legacy);
+        if position.is_some() {
+            return position;
+        }
+    }
+
+    let names: Vec<&str> = spaces.iter().map(|space| space.name.as_str()).collect();
+    utils::sibling_slugs(&names).iter().position(|other| *other == slug)
+}
+
+// /////////////////////////////////////////////////////////////////////////////
+// Client
+// /////////////////////////////////////////////////////////////////////////////
+/// Name of `FenixEDU` in error messages
+const FENIX_SERVICE: &'static str = "Fenix";
+
+/// Most spaces between a space and its campus, or inside a space
+const MAX_DEPTH: usize = 32;
+
+/// Client of the `FenixEDU` Spaces API
+#[derive(Clone)]
+pub struct FenixSpacesClient {
+    base_url: String,
+    cache: Option<Arc<SpaceCache>>,
+    snapshot: Option<Arc<SnapshotStore>>,
+}
+
+impl FenixSpacesClient {
+    /// Create a client for the Spaces API at `base_url`
+    ///
+    /// # Arguments
+    /// * `base_url` => URL of the spaces endpoint, e.g.
+    ///   `https://fenix.tecnico.ulisboa.pt/api/fenix/v1/spaces`.
+    pub fn new(base_url: &str) -> FenixSpacesClient {
+        FenixSpacesClient {
+            base_url: base_url.trim_right_matches('/').to_owned(),
+            cache: None,
+            snapshot: None,
+        }
+    }
+
+    /// Create a client for the Spaces API set in `config`, with a cache when
+    /// `config.cache_size` isn't 0.
+    pub fn from_config(config: &Config) -> FenixSpacesClient {
+        let client = FenixSpacesClient::new(&config.fenix_base_url);
+
+        if config.cache_size > 0 {
+            let ttl = Duration::from_secs(config.cache_ttl);
+            client.with_cache(Arc::new(SpaceCache::new(ttl, config.cache_size)))
+        } else {
+            client
+        }
+    }
+
+    /// Attach `cache` to the client. Clients sharing a cache share its entries.
+    pub fn with_cache(mut self, cache: Arc<SpaceCache>) -> FenixSpacesClient {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Cache used by the client, if any
+    pub fn cache(&self) -> Option<&SpaceCache> {
+        self.cache.as_ref().map(|cache| &**cache)
+    }
+
+    /// Serve the spaces from `snapshot` before asking `FenixEDU`
+    pub fn with_snapshot(mut self, snapshot: Arc<SnapshotStore>) -> FenixSpacesClient {
+        self.snapshot = Some(snapshot);
+        self
+    }
+
+    /// Snapshot used by the client, if any
+    pub fn snapshot(&self) -> Option<Arc<SnapshotStore>> {
+        self.snapshot.clone()
+    }
+
+    /// URL of the spaces endpoint
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Get the top level spaces (the campi)
+    pub fn top_level(&self) -> Result<Vec<ContainedSpace>> {
+        self.get("")
+    }
+
+    /// Get the space with the specified `id`
+    ///
+    /// # Return Value
+    /// The space or an `Error::NotFound` if the id doesn't exist.
+    pub fn space(&self, id: &str) -> Result<Space> {
+        self.get(id)
+    }
+
+    /// Get the space with the specified `id` and convert it into an object of
+    /// type `T`. Any field missing from `T` is dropped.
+    ///
+    /// # Arguments
+    /// * `id` => space id. The empty id returns the top level spaces.
+    ///
+    /// # Return Value
+    /// The object, an `Error::NotFound` if the id doesn't exist or the error of
+    /// the request.
+    pub fn get<T>(&self, id: &str) -> Result<T>
+        where T: Deserialize
+    {
+        utils::from_json_to_obj(&self.document(id)?)
+    }
+
+    /// Get the JSON document of the space `id`, from the snapshot or the cache
+    /// when possible
+    ///
+    /// # Return Value
+    /// The document, an `Error::NotFound` if the id doesn't exist or the error
+    /// of the request.
+    pub fn document(&self, id: &str) -> Result<String> {
+        if let Some(body) = self.snapshot.as_ref().and_then(|snapshot| snapshot.document(id)) {
+            return Ok(body);
+        }
+
+        self.fetch(id, "")
+    }
+
+    /// Get the events scheduled in the space `id` during `day`
+    ///
+    /// The snapshot has no events, so only the cache can spare the request.
+    ///
+    /// # Return Value
+    /// The events, first to start first, an `Error::NotFound` if the id
+    /// doesn't exist or the error of the request.
+    pub fn schedule(&self, id: &str, day: Day) -> Result<Vec<ScheduledEvent>> {
+        schedule::events(&self.fetch(id, &format!("?day={}", day))?)
+    }
+
+    /// Get the JSON document of the space `id` with the query `query`, from
+    /// the cache when possible
+    fn fetch(&self, id: &str, query: &str) -> Result<String> {
+        let key: String = format!("{}{}", id, query);
+        if let Some(body) = self.cache().and_then(|cache| cache.get(&key)) {
+            return Ok(body);
+        }
+
+        let mut response: HyperResponse = self.request(&key)?;
+
+        if response.status == StatusCode::Ok {
+            let body: String = utils::read_response_body(&mut response)?;
+            if let Some(cache) = self.cache() {
+                cache.insert(&key, &body);
+            }
+            Ok(body)
+        } else if response.status == StatusCode::NotFound {
+            Err(Error::NotFound(format!("The id: {} was not found", id)))
+        } else {
+            Err(Error::UpstreamStatus(FENIX_SERVICE.to_owned(), response.status.to_u16()))
+        }
+    }
+
+    /// Search for the space called `name` in `contained_spaces` and get it
+    ///
+    /// `name` is compared with the slugs of `contained_spaces` given by
+    /// `utils::sibling_slugs()`, after going through `utils::slugify()` itself,
+    /// so it may be a path segment as used in `/api/path/` or the name of the
+    /// space. Segments of the first releases, see `utils::legacy_slug()`, are
+    /// accepted too.
+    ///
+    /// # Arguments
+    /// * `name` => slug or name of the space;
+    /// * `contained_spaces` => spaces to search in.
+    ///
+    /// # Return Value
+    /// The space found or an `Error::NotFound` when no space matches `name`.
+    pub fn find_child(&self,
+                      name: &str,
+                      contained_spaces: &[ContainedSpace])
+                      -> Result<Space> {
+        let found = slug_position(name, contained_spaces).map(|index| &contained_spaces[index]);
+
+        match found {
+            Some(contained) => {
+                let mut space: Space = self.space(&contained.id)?;
+                space.id = contained.id.clone();
+                Ok(space)
+            }
+            None => Err(Error::NotFound(format!("{} was not found", name))),
+        }
+    }
+
+    /// Walk the hierarchy from the top level spaces following `path`
+    ///
+    /// Each segment of `path` is the slug of a space contained in the previous
+    /// one. One request is made per segment.
+    ///
+    /// # Arguments
+    /// * `path` => segments of the path, e.g. `["alameda", "pavilhao-central"]`.
+    ///
+    /// # Return Value
+    /// The last space of the path, an `Error::NotFound` for the first segment
+    /// that doesn't exist or an `Error::Validation` if `path` is empty.
+    pub fn resolve_path(&self, path: &[&str]) -> Result<Space> {
+        let mut trail: Vec<Space> = self.resolve_trail(path)?;
+
+        Ok(trail.pop().unwrap_or_default())
+    }
+
+    /// Walk the hierarchy from the top level spaces following `path`, keeping
+    /// every space met
+    ///
+    /// # Arguments
+    /// * `path` => segments of the path, e.g. `["alameda", "pavilhao-central"]`.
+    ///
+    /// # Return Value
+    /// The spaces of the path, top level space first, or the errors of
+    /// `resolve_path()`.
+    pub fn resolve_trail(&self, path: &[&str]) -> Result<Vec<Space>> {
+        if path.is_empty() {
+            return Err(Error::Validation("No path provided".to_owned()));
+        }
+
+        let mut contained_spaces: Vec<ContainedSpace> = self.top_level()?;
+        let mut trail: Vec<Space> = Vec::with_capacity(path.len());
+
+        for point in path {
+            let space: Space = self.find_child(point, &contained_spaces)?;
+            contained_spaces = space.contained_spaces.clone();
+            trail.push(space);
+        }
+
+        Ok(trail)
+    }
+
+    /// Get the spaces from the campus to the space `id`, following their
+    /// `parentSpace`
+    ///
+    /// One request is made per space missing from the snapshot and the cache.
+    ///
+    /// # Return Value
+    /// The spaces, campus first and the space `id` last, an
+    /// `Error::NotFound` if the id doesn't exist, an `Error::Decode` when the
+    /// parents loop or are more than `MAX_DEPTH`, or the error of the
+    /// requests.
+    pub fn ancestry(&self, id: &str) -> Result<Vec<ContainedSpace>> {
+        let mut ancestry: Vec<ContainedSpace> = Vec::new();
+        let mut next: Option<String> = Some(id.to_owned());
+
+        while let Some(id) = next {
+            if ancestry.len() == MAX_DEPTH || ancestry.iter().any(|space| space.id == id) {
+                return Err(Error::Decode(format!("The parents of the space {} loop or are \
+                                                  too deep",
+                                                 id)));
+            }
+
+            let space: Space = self.space(&id)?;
+
+            ancestry.push(ContainedSpace {
+                id: id,
+                name: space.name,
+            });
+            next = space.parent_space.map(|parent| parent.id);
+        }
+        ancestry.reverse();
+
+        Ok(ancestry)
+    }
+
+    /// Get the path leading to the space `id`, the reverse of
+    /// `resolve_path()`
+    ///
+    /// # Return Value
+    /// The spaces from the campus to the space `id` or the errors of
+    /// `ancestry()`.
+    pub fn path_of(&self, id: &str) -> Result<Vec<PathSegment>> {
+        let ancestry: Vec<ContainedSpace> = self.ancestry(id)?;
+        let depth: usize = ancestry.len();
+        let mut siblings: Vec<ContainedSpace> = self.top_level()?;
+        let mut path: Vec<PathSegment> = Vec::with_capacity(depth);
+
+        for space in ancestry {
+            // Slugs are unique among the siblings of the space
+            let slug: String = {
+                let names: Vec<&str> =
+                    siblings.iter().map(|sibling| sibling.name.as_str()).collect();
+                match siblings.iter().position(|sibling| sibling.id == space.id) {
+                    Some(index) => utils::sibling_slugs(&names).swap_remove(index),
+                    None => utils::slugify(&space.name),
+                }
+            };
+
+            if path.len() + 1 < depth {
+                siblings = self.space(&space.id)?.contained_spaces;
+            }
+            path.push(PathSegment {
+                id: space.id,
+                name: space.name,
+                slug: slug,
+            });
+        }
+
+        Ok(path)
+    }
+
+    /// Checks if the space with id `id` exists and is a room, as decided by
+    /// `Space::is_room()`.
+    ///
+    /// # Return Value
+    /// If the room exists true, else false. If `FenixEDU` can't be reached or
+    /// answers with an error that will be the error passed.
+    pub fn is_room(&self, id: &str) -> Result<bool> {
+        match self.space(id) {
+            Ok(space) => Ok(space.is_room()),
+            Err(Error::NotFound(_)) |
+            Err(Error::Decode(_)) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Send a GET request to `FenixEDU` with the specified space `id`, which
+    /// may be followed by a query
+    fn request(&self, id: &str) -> Result<HyperResponse> {
+        let url: String = format!("{}/{}", self.base_url, id);
+
+        utils::get_request(&url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{slug_position, ContainedSpace};
+
+    fn spaces(names: &[&str]) -> Vec<ContainedSpace> {
+        names.iter()
+            .enumerate()
+            .map(|(id, name)| {
+                ContainedSpace {
+                    id: id.to_string(),
+                    name: (*name).to_owned(),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn finds_children_by_slug_and_legacy_segment() {
+        let spaces = spaces(&["Sala 0-08", "Sala 0.08", "Piso 1/2"]);
+
+        assert_eq!(slug_position("sala-0-08", &spaces), Some(0));
+        assert_eq!(slug_position("sala-0-08-2", &spaces), Some(1));
+        assert_eq!(slug_position("Sala 0-08", &spaces), Some(0));
+        assert_eq!(slug_position("sala-0.08", &spaces), Some(1));
+        assert_eq!(slug_position("piso-1_2", &spaces), Some(2));
+        assert_eq!(slug_position("piso-1-2", &spaces), Some(2));
+        assert_eq!(slug_position("sala-0.09", &spaces), None);
+        assert_eq!(slug_position("...", &spaces), None);
+    }
+}
@@ -0,0 +1,348 @@
+//! Rooms built from the `FenixEDU` spaces.
+//!
+//! The location of a room is the names of the spaces from the campus to the
+//! room, joined by `LOCATION_SEPARATOR`, and its capacity is the normal
+//! capacity in `FenixEDU`. An admin may override both with `RoomOverrides`.
+//!
+//! `import()` walks every space under a path, as used by `/api/path/`, and
+//! creates a room in the store for each space that `Space::is_room()`
+//! accepts. Each room, and each space without rooms inside, is reported as
+//! created, skipped (already in the store, without a capacity or not a room)
+//! or failed. At most `MAX_SPACES` spaces are requested to `FenixEDU` by one
+//! import; past them the report is truncated.
+use std::collections::BTreeMap;
+
+use error::{Error, Result};
+use fenix::{FenixSpacesClient, Space, ContainedSpace};
+use store::{RoomStore, Room, NewRoom};
+
+/// Separator of the names of a location
+const LOCATION_SEPARATOR: &'static str = " / ";
+
+/// Most spaces requested to `FenixEDU` by one import
+pub const MAX_SPACES: usize = 2000;
+
+/// Values set by an admin instead of those of `FenixEDU`
+///
+/// # Fields
+/// * `location` => location of the room, if overridden;
+/// * `capacity` => number of seats, if overridden.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RoomOverrides {
+    pub location: Option<String>,
+    pub capacity: Option<u64>,
+}
+
+/// Build the room of the space `fenix_id` from `FenixEDU`
+///
+/// # Arguments
+/// * `fenix` => client of the Spaces API;
+/// * `fenix_id` => id of the space;
+/// * `overrides` => values replacing those of `FenixEDU`.
+///
+/// # Return Value
+/// The room to create, an `Error::NotFound` when the space doesn't exist or
+/// isn't a room, an `Error::Validation` when `FenixEDU` has no capacity for
+/// it and none was given, or the error of `FenixEDU`.
+pub fn describe(fenix: &FenixSpacesClient,
+                fenix_id: &str,
+                overrides: &RoomOverrides)
+                -> Result<NewRoom> {
+    let space: Space = match fenix.space(fenix_id) {
+        Ok(ref space) if !space.is_room() => return Err(not_a_room()),
+        Ok(space) => space,
+        Err(Error::NotFound(_)) |
+        Err(Error::Decode(_)) => return Err(not_a_room()),
+        Err(err) => return Err(err),
+    };
+
+    let location: String = match overrides.location {
+        Some(ref location) => location.clone(),
+        None => self::location(&fenix.ancestry(fenix_id)?),
+    };
+    let capacity: u64 = match (overrides.capacity, space.capacity) {
+        (Some(capacity), _) => capacity,
+        (None, Some(capacity)) => capacity.normal,
+        (None, None) => {
+            return Err(Error::Validation(format!("FenixEDU has no capacity for the space {}, \
+                                                  it must be overridden",
+                                                 fenix_id)))
+        }
+    };
+
+    Ok(NewRoom {
+        fenix_id: fenix_id.to_owned(),
+        location: location,
+        capacity: capacity,
+    })
+}
+
+/// Location of a room made of the spaces from its campus to itself
+pub fn location(spaces: &[ContainedSpace]) -> String {
+    let names: Vec<&str> = spaces.iter().map(|space| space.name.as_str()).collect();
+
+    names.join(LOCATION_SEPARATOR)
+}
+
+fn not_a_room() -> Error {
+    Error::NotFound("The provided fenix_id does not match a space or room in FenixEDU".to_owned())
+}
+
+/// What happened to a space
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum ImportStatus {
+    #[serde(rename="created")]
+    Created,
+    #[serde(rename="skipped")]
+    Skipped,
+    #[serde(rename="failed")]
+    Failed,
+}
+
+/// A room, or a space without rooms, met by the import
+///
+/// # Fields
+/// * `fenix_id` => id of the space in `FenixEDU`;
+/// * `location` => names of the spaces from the campus to the space;
+/// * `status` => what happened to the space;
+/// * `room_id` => id of the room in the store, when created or already there;
+/// * `capacity` => number of seats, when known;
+/// * `reason` => why the space was skipped or failed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ImportedRoom {
+    pub fenix_id: String,
+    pub location: String,
+    pub status: ImportStatus,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub room_id: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub capacity: Option<u64>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl ImportedRoom {
+    fn new(fenix_id: &str, location: String, status: ImportStatus) -> ImportedRoom {
+        ImportedRoom {
+            fenix_id: fenix_id.to_owned(),
+            location: location,
+            status: status,
+            room_id: None,
+            capacity: None,
+            reason: None,
+        }
+    }
+
+    fn with_reason(mut self, reason: String) -> ImportedRoom {
+        self.reason = Some(reason);
+        self
+    }
+}
+
+/// Outcome of an import
+///
+/// # Fields
+/// * `path` => path of the space imported;
+/// * `created`, `skipped`, `failed` => number of rooms in each state;
+/// * `truncated` => true when the walk stopped after `MAX_SPACES` spaces;
+/// * `rooms` => every room and space without rooms met, in the order of
+///   `FenixEDU`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ImportReport {
+    pub path: String,
+    pub created: u64,
+    pub skipped: u64,
+    pub failed: u64,
+    pub truncated: bool,
+    pub rooms: Vec<ImportedRoom>,
+}
+
+impl ImportReport {
+    fn new(path: &str) -> ImportReport {
+        ImportReport {
+            path: path.to_owned(),
+            created: 0,
+            skipped: 0,
+            failed: 0,
+            truncated: false,
+            rooms: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, room: ImportedRoom) {
+        match room.status {
+            ImportStatus::Created => self.created += 1,
+            ImportStatus::Skipped => self.skipped += 1,
+            ImportStatus::Failed => self.failed += 1,
+        }
+        self.rooms.push(room);
+    }
+
+    /// Ids of the rooms created
+    pub fn created_rooms(&self) -> Vec<String> {
+        self.rooms
+            .iter()
+            .filter(|room| room.status == ImportStatus::Created)
+            .filter_map(|room| room.room_id.clone())
+            .collect()
+    }
+}
+
+/// Create a room for every room space under `path`
+///
+/// A space that can't be read from `FenixEDU` is reported as failed and the
+/// walk goes on with the next one. The walk stops, and the report is marked
+/// as truncated, once `MAX_SPACES` spaces were requested; a smaller path then
+/// imports the rest.
+///
+/// # Arguments
+/// * `fenix` => client of the Spaces API;
+/// * `store` => store receiving the rooms;
+/// * `path` => path of the space to import, e.g. `alameda/pavilhao-central`.
+///
+/// # Return Value
+/// The report, an `Error::NotFound` for an unknown path or the error of
+/// `FenixEDU` or of the store before the walk started.
+pub fn import(fenix: &FenixSpacesClient, store: &RoomStore, path: &str) -> Result<ImportReport> {
+    let points: Vec<&str> = path.split('/').filter(|point| !point.is_empty()).collect();
+    let mut trail: Vec<Space> = fenix.resolve_trail(&points)?;
+    let names: Vec<String> = trail.iter().map(|space| space.name.clone()).collect();
+    let root: Space = trail.pop().unwrap_or_default();
+
+    let existing: BTreeMap<String, String> = store.list_rooms()?
+        .into_iter()
+        .map(|room| (room.fenix_id, room.id))
+        .collect();
+
+    let mut report = ImportReport::new(path);
+    let mut pending: Vec<(Space, Vec<String>)> = vec![(root, names)];
+    let mut requested: usize = 0;
+
+    'walk: while let Some((space, names)) = pending.pop() {
+        if space.is_room() {
+            report.add(import_room(store, &existing, space, names.join(LOCATION_SEPARATOR)));
+            continue;
+        }
+        if space.contained_spaces.is_empty() {
+            let kind: String = space.kind.as_ref().map_or("space", |kind| kind.name()).to_owned();
+            let skipped = ImportedRoom::new(&space.id,
+                                            names.join(LOCATION_SEPARATOR),
+                                            ImportStatus::Skipped);
+            report.add(skipped.with_reason(format!("The {} isn't a room", kind)));
+            continue;
+        }
+
+        // Reversed, so the spaces are popped in the order of FenixEDU
+        for contained in space.contained_spaces.iter().rev() {
+            if requested >= MAX_SPACES {
+                report.truncated = true;
+                break 'walk;
+            }
+            requested += 1;
+
+            let mut location: Vec<String> = names.clone();
+            location.push(contained.name.clone());
+
+            match fenix.space(&contained.id) {
+                Ok(mut child) => {
+                    child.id = contained.id.clone();
+                    pending.push((child, location));
+                }
+                Err(err) => {
+                    let failed = ImportedRoom::new(&contained.id,
+                                                   location.join(LOCATION_SEPARATOR),
+                                                   ImportStatus::Failed);
+                    report.add(failed.with_reason(err.to_string()));
+                }
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Create the room of the room space `space`
+///
+/// # Arguments
+/// * `store` => store receiving the room;
+/// * `existing` => ids of the rooms of the store by `FenixEDU` id;
+/// * `space` => the room space;
+/// * `location` => names of the spaces from the campus to `space`.
+fn import_room(store: &RoomStore,
+               existing: &BTreeMap<String, String>,
+               space: Space,
+               location: String)
+               -> ImportedRoom {
+    let mut imported = ImportedRoom::new(&space.id, location, ImportStatus::Skipped);
+
+    if let Some(room_id) = existing.get(&space.id) {
+        imported.room_id = Some(room_id.clone());
+        return imported.with_reason("The room already exists".to_owned());
+    }
+    let capacity: u64 = match space.capacity {
+        Some(capacity) => capacity.normal,
+        None => return imported.with_reason("FenixEDU has no capacity for the space".to_owned()),
+    };
+    imported.capacity = Some(capacity);
+
+    let new_room = NewRoom {
+        fenix_id: space.id,
+        location: imported.location.clone(),
+        capacity: capacity,
+    };
+    match store.create_room(&new_room) {
+        Ok(Room { id, .. }) => {
+            imported.status = ImportStatus::Created;
+            imported.room_id = Some(id);
+            imported
+        }
+        Err(Error::Conflict(reason)) => imported.with_reason(reason),
+        Err(err) => {
+            imported.status = ImportStatus::Failed;
+            imported.with_reason(err.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fenix::ContainedSpace;
+    use super::{location, ImportReport, ImportStatus, ImportedRoom};
+
+    fn space(name: &str) -> ContainedSpace {
+        ContainedSpace {
+            id: name.to_lowercase(),
+            name: name.to_owned(),
+        }
+    }
+
+    fn room(fenix_id: &str, status: ImportStatus, room_id: Option<&str>) -> ImportedRoom {
+        let mut room = ImportedRoom::new(fenix_id, String::new(), status);
+        room.room_id = room_id.map(str::to_owned);
+        room
+    }
+
+    #[test]
+    fn joins_the_names_of_the_location() {
+        let spaces = vec![space("Alameda"), space("Pavilhão Central"), space("Sala 0.08")];
+
+        assert_eq!(location(&spaces), "Alameda / Pavilhão Central / Sala 0.08");
+        assert_eq!(location(&spaces[..1]), "Alameda");
+        assert_eq!(location(&[]), "");
+    }
+
+    #[test]
+    fn counts_the_rooms_of_the_report() {
+        let mut report = ImportReport::new("alameda");
+        report.add(room("1", ImportStatus::Created, Some("10")));
+        report.add(room("2", ImportStatus::Skipped, Some("11")));
+        report.add(room("3", ImportStatus::Created, Some("12")));
+        report.add(room("4", ImportStatus::Failed, None));
+        report.add(room("5", ImportStatus::Skipped, None));
+
+        assert_eq!((report.created, report.skipped, report.failed), (2, 2, 1));
+        assert_eq!(report.rooms.len(), 5);
+        assert!(!report.truncated);
+        assert_eq!(report.created_rooms(), vec!["10", "12"]);
+    }
+}
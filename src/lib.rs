@@ -1,6 +1,28 @@
 #[macro_use]
 extern crate serde_derive;
 extern crate serde;
+#[macro_use]
+extern crate lazy_static;
 
+pub mod error;
+pub mod auth;
+pub mod oauth;
+pub mod config;
 pub mod utils;
+pub mod cache;
+pub mod fenix;
+pub mod schedule;
+pub mod snapshot;
+pub mod search;
+pub mod store;
+pub mod reaper;
+pub mod stats;
+pub mod reservations;
+pub mod availability;
+pub mod import;
+pub mod events;
+pub mod webhooks;
 pub mod api;
+
+pub use error::{Error, Result};
+pub use fenix::FenixSpacesClient;
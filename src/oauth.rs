@@ -0,0 +1,134 @@
+//! `FenixEDU` OAuth2 login.
+//!
+//! The authorization-code flow is:
+//!
+//! 1. The user is redirected to `authorize_url()` and logs in at `FenixEDU`;
+//! 2. `FenixEDU` redirects back to `Config::oauth_redirect_uri` with a `code`;
+//! 3. `exchange_code()` trades the code for an access token at the token
+//!    endpoint;
+//! 4. `person()` gets the `FenixEDU` person owning the access token.
+//!
+//! Every endpoint is configurable so the flow can run against a local
+//! stand-in of `FenixEDU`.
+extern crate hyper;
+
+use self::hyper::Url;
+use self::hyper::status::StatusCode;
+use self::hyper::client::Response as HyperResponse;
+
+use config::Config;
+use error::{Error, Result};
+use utils;
+
+/// Name of the `FenixEDU` OAuth endpoints in error messages
+const OAUTH_SERVICE: &'static str = "Fenix OAuth";
+
+/// Tokens returned by the token endpoint
+#[derive(Deserialize, Debug, Clone)]
+pub struct AccessToken {
+    pub access_token: String,
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+}
+
+/// The `FenixEDU` person owning an access token
+#[derive(Deserialize, Debug, Clone)]
+pub struct Person {
+    pub username: String,
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+/// Client of the `FenixEDU` OAuth endpoints
+#[derive(Debug, Clone)]
+pub struct FenixOAuth {
+    client_id: String,
+    client_secret: String,
+    redirect_uri: String,
+    authorize_url: String,
+    token_url: String,
+    person_url: String,
+}
+
+impl FenixOAuth {
+    /// Create the client set in `config`
+    ///
+    /// # Return Value
+    /// `None` when `oauth_client_id` isn't set, the login is then disabled.
+    pub fn from_config(config: &Config) -> Option<FenixOAuth> {
+        if config.oauth_client_id.is_empty() {
+            return None;
+        }
+
+        Some(FenixOAuth {
+            client_id: config.oauth_client_id.clone(),
+            client_secret: config.oauth_client_secret.clone(),
+            redirect_uri: config.oauth_redirect_uri.clone(),
+            authorize_url: config.oauth_authorize_url.clone(),
+            token_url: config.oauth_token_url.clone(),
+            person_url: config.oauth_person_url.clone(),
+        })
+    }
+
+    /// URL of the `FenixEDU` login page
+    ///
+    /// # Arguments
+    /// * `state` => opaque value returned untouched to the callback.
+    pub fn authorize_url(&self, state: &str) -> Result<String> {
+        let mut url = parse_url(&self.authorize_url)?;
+        url.query_pairs_mut()
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", &self.redirect_uri)
+            .append_pair("state", state);
+
+        Ok(url.to_string())
+    }
+
+    /// Trade the authorization `code` for an access token
+    ///
+    /// # Return Value
+    /// The tokens or an `Error::Unauthorized` if `FenixEDU` refuses the code.
+    pub fn exchange_code(&self, code: &str) -> Result<AccessToken> {
+        let mut url = parse_url(&self.token_url)?;
+        url.query_pairs_mut()
+            .append_pair("client_id", &self.client_id)
+            .append_pair("client_secret", &self.client_secret)
+            .append_pair("redirect_uri", &self.redirect_uri)
+            .append_pair("code", code)
+            .append_pair("grant_type", "authorization_code");
+
+        let mut response: HyperResponse = utils::post_request(url.as_str(), "")?;
+        read_json(&mut response, "The authorization code was refused")
+    }
+
+    /// Get the person owning `access_token`
+    pub fn person(&self, access_token: &str) -> Result<Person> {
+        let mut url = parse_url(&self.person_url)?;
+        url.query_pairs_mut().append_pair("access_token", access_token);
+
+        let mut response: HyperResponse = utils::get_request(url.as_str())?;
+        read_json(&mut response, "The access token was refused")
+    }
+}
+
+/// Parse a configured URL
+fn parse_url(url: &str) -> Result<Url> {
+    Url::parse(url).map_err(|err| Error::Config(format!("{} is not a valid URL ({})", url, err)))
+}
+
+/// Decode a successful response, or report a client error as `refused`
+fn read_json<T>(response: &mut HyperResponse, refused: &str) -> Result<T>
+    where T: ::serde::Deserialize
+{
+    if response.status == StatusCode::Ok {
+        let body: String = utils::read_response_body(response)?;
+        utils::from_json_to_obj(&body)
+    } else if response.status == StatusCode::BadRequest ||
+              response.status == StatusCode::Unauthorized {
+        Err(Error::Unauthorized(refused.to_owned()))
+    } else {
+        Err(Error::UpstreamStatus(OAUTH_SERVICE.to_owned(), response.status.to_u16()))
+    }
+}
@@ -0,0 +1,214 @@
+//! Automatic check-out of stale check-ins.
+//!
+//! Users often leave without checking out. An `ExpiryPolicy` decides when a
+//! check in is over: after its `expected_duration`, after
+//! `Config::checkin_timeout` when it has none, and in any case at the next
+//! `Config::checkout_cutoff`. `spawn_reaper()` closes the expired check-ins
+//! in the background with the reason `expired`. It needs a store able to list
+//! the open check-ins, so `Config::validate()` refuses a timeout or a cutoff
+//! with the original database, and the reaper stops if the store can't.
+use std::cmp;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use config::Config;
+use error::{Error, Result};
+use store::{RoomStore, CheckIn, CheckOut, CheckOutReason};
+use utils;
+
+/// Seconds between two passes of the reaper
+const REAP_INTERVAL: u64 = 60;
+
+/// Seconds in a day
+const DAY: u64 = 24 * 60 * 60;
+
+/// Longest `expected_duration` of a check in, a week
+pub const MAX_DURATION: u64 = 7 * DAY;
+
+/// When check-ins expire
+///
+/// # Fields
+/// * `timeout` => seconds a check in without an expected duration lasts, 0
+///   for no limit;
+/// * `cutoff` => seconds after midnight (UTC) at which every check in ends.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExpiryPolicy {
+    pub timeout: u64,
+    pub cutoff: Option<u64>,
+}
+
+impl ExpiryPolicy {
+    /// Create the policy set in `config`
+    pub fn from_config(config: &Config) -> ExpiryPolicy {
+        ExpiryPolicy {
+            timeout: config.checkin_timeout,
+            cutoff: config.checkout_cutoff,
+        }
+    }
+
+    /// UNIX time at which `check_in` expires
+    ///
+    /// # Return Value
+    /// The time or `None` when the check in never expires.
+    pub fn deadline(&self, check_in: &CheckIn) -> Option<u64> {
+        let duration: Option<u64> = match check_in.expected_duration {
+            Some(duration) => Some(duration),
+            None if self.timeout > 0 => Some(self.timeout),
+            None => None,
+        };
+        let timeout = duration.map(|duration| check_in.checked_in_at.saturating_add(duration));
+
+        // First cutoff strictly after the check in
+        let cutoff = self.cutoff.map(|cutoff| {
+            let midnight = check_in.checked_in_at - check_in.checked_in_at % DAY;
+            if midnight + cutoff > check_in.checked_in_at {
+                midnight + cutoff
+            } else {
+                midnight + DAY + cutoff
+            }
+        });
+
+        match (timeout, cutoff) {
+            (Some(timeout), Some(cutoff)) => Some(cmp::min(timeout, cutoff)),
+            (timeout, cutoff) => timeout.or(cutoff),
+        }
+    }
+
+    /// True when `check_in` is over at the UNIX time `now`
+    pub fn is_expired(&self, check_in: &CheckIn, now: u64) -> bool {
+        self.deadline(check_in).map_or(false, |deadline| deadline <= now)
+    }
+}
+
+/// Check out every check in of `store` expired at the UNIX time `now`
+///
+/// # Return Value
+/// The check-ins closed or the error of the store. A check in closed by
+/// someone else in the meantime is skipped.
+pub fn reap(store: &RoomStore, policy: &ExpiryPolicy, now: u64) -> Result<Vec<CheckIn>> {
+    let mut expired: Vec<CheckIn> = Vec::new();
+
+    for check_in in store.active_check_ins()? {
+        if !policy.is_expired(&check_in, now) {
+            continue;
+        }
+
+        let check_out = CheckOut {
+            user_id: check_in.user_id.clone(),
+            room_id: check_in.room_id.clone(),
+            checked_out_at: now,
+            reason: CheckOutReason::Expired,
+        };
+        match store.check_out(&check_out) {
+            Ok(()) => expired.push(check_in),
+            Err(Error::NotFound(_)) => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(expired)
+}
+
+/// Spawn a thread closing the expired check-ins of `store` every
+/// `REAP_INTERVAL` seconds
+///
+/// # Arguments
+/// * `store` => store of the check-ins;
+/// * `policy` => when check-ins expire;
+/// * `on_expired` => called with every check in closed.
+pub fn spawn_reaper<F>(store: Arc<RoomStore>, policy: ExpiryPolicy, on_expired: F) -> JoinHandle<()>
+    where F: Fn(&CheckIn) + Send + 'static
+{
+    thread::spawn(move || {
+        loop {
+            match reap(&*store, &policy, utils::unix_time()) {
+                Ok(ref expired) if expired.is_empty() => {}
+                Ok(expired) => {
+                    println!("{} check-ins expired", expired.len());
+                    for check_in in &expired {
+                        on_expired(check_in);
+                    }
+                }
+                Err(Error::Unsupported(msg)) => {
+                    println!("Check-in expiry stopped: {}", msg);
+                    return;
+                }
+                Err(err) => println!("Check-in expiry failed: {}", err),
+            }
+
+            thread::sleep(Duration::from_secs(REAP_INTERVAL));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use store::CheckIn;
+    use super::{ExpiryPolicy, DAY};
+
+    /// Midnight (UTC) of 2017-11-20
+    const MIDNIGHT: u64 = 1511136000;
+
+    fn check_in(checked_in_at: u64, expected_duration: Option<u64>) -> CheckIn {
+        CheckIn {
+            user_id: "1".to_owned(),
+            room_id: "1".to_owned(),
+            checked_in_at: checked_in_at,
+            expected_duration: expected_duration,
+        }
+    }
+
+    fn policy(timeout: u64, cutoff: Option<u64>) -> ExpiryPolicy {
+        ExpiryPolicy {
+            timeout: timeout,
+            cutoff: cutoff,
+        }
+    }
+
+    #[test]
+    fn check_ins_without_limits_never_expire() {
+        let policy = policy(0, None);
+
+        assert_eq!(policy.deadline(&check_in(MIDNIGHT, None)), None);
+        assert!(!policy.is_expired(&check_in(MIDNIGHT, None), u64::max_value()));
+    }
+
+    #[test]
+    fn the_expected_duration_overrides_the_timeout() {
+        let policy = policy(3600, None);
+
+        assert_eq!(policy.deadline(&check_in(MIDNIGHT, None)), Some(MIDNIGHT + 3600));
+        assert_eq!(policy.deadline(&check_in(MIDNIGHT, Some(60))), Some(MIDNIGHT + 60));
+        assert_eq!(policy.deadline(&check_in(MIDNIGHT, Some(u64::max_value()))),
+                   Some(u64::max_value()));
+    }
+
+    #[test]
+    fn the_cutoff_is_the_next_one_after_the_check_in() {
+        let policy = policy(0, Some(23 * 3600));
+
+        // Before the cutoff of the day
+        assert_eq!(policy.deadline(&check_in(MIDNIGHT + 3600, None)),
+                   Some(MIDNIGHT + 23 * 3600));
+        // At and after it, the cutoff of the next day
+        assert_eq!(policy.deadline(&check_in(MIDNIGHT + 23 * 3600, None)),
+                   Some(MIDNIGHT + DAY + 23 * 3600));
+        assert_eq!(policy.deadline(&check_in(MIDNIGHT + 23 * 3600 + 1, None)),
+                   Some(MIDNIGHT + DAY + 23 * 3600));
+    }
+
+    #[test]
+    fn the_earliest_of_the_timeout_and_the_cutoff_wins() {
+        let policy = policy(2 * 3600, Some(23 * 3600));
+
+        assert_eq!(policy.deadline(&check_in(MIDNIGHT + 3600, None)),
+                   Some(MIDNIGHT + 3 * 3600));
+        assert_eq!(policy.deadline(&check_in(MIDNIGHT + 22 * 3600, None)),
+                   Some(MIDNIGHT + 23 * 3600));
+        assert_eq!(policy.deadline(&check_in(MIDNIGHT + 22 * 3600, Some(DAY))),
+                   Some(MIDNIGHT + 23 * 3600));
+        assert!(policy.is_expired(&check_in(MIDNIGHT + 3600, None), MIDNIGHT + 3 * 3600));
+        assert!(!policy.is_expired(&check_in(MIDNIGHT + 3600, None), MIDNIGHT + 3 * 3600 - 1));
+    }
+}
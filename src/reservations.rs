@@ -0,0 +1,192 @@
+//! Rules of the room reservations.
+//!
+//! A reservation books one place of a room for a time window. It is refused
+//! when the window is empty or already over, when the user has another
+//! reservation at the same time, or when the room would hold more reservations
+//! at some point than its capacity. When `Config::require_reservation` is set,
+//! users can only check in a room during one of their reservations.
+use std::cmp;
+
+use error::{Error, Result};
+use store::{RoomStore, Reservation, NewReservation, ReservationQuery};
+
+/// Check that `reservation` can be booked
+///
+/// The stores call it while holding the lock of their bookings, see
+/// `RoomStore::create_reservation_checked()`.
+///
+/// # Arguments
+/// * `reservation` => reservation to book;
+/// * `overlapping` => active reservations overlapping its window, at least
+///   those of its user and of its room;
+/// * `limit` => most reservations the room holds at the same time, `None`
+///   without a limit.
+///
+/// # Return Value
+/// Nothing, an `Error::Validation` for an invalid window, an
+/// `Error::Conflict` when the user has another reservation at the same time
+/// or an `Error::RoomFull` when the room is fully booked during the window.
+pub fn check_booking(reservation: &NewReservation,
+                     overlapping: &[Reservation],
+                     limit: Option<u64>)
+                     -> Result<()> {
+    if reservation.start >= reservation.end {
+        return Err(Error::Validation("start must be before end".to_owned()));
+    }
+    if reservation.end <= reservation.created_at {
+        return Err(Error::Validation("The reservation is already over".to_owned()));
+    }
+
+    let overlapping: Vec<&Reservation> = overlapping.iter()
+        .filter(|other| other.is_active(reservation.start, reservation.end))
+        .collect();
+
+    let own = overlapping.iter().find(|other| other.user_id == reservation.user_id);
+    if let Some(other) = own {
+        return Err(Error::Conflict(format!("The user {} already has the reservation {} in the \
+                                            room {} from {} to {}",
+                                           reservation.user_id,
+                                           other.id,
+                                           other.room_id,
+                                           other.start,
+                                           other.end)));
+    }
+
+    if let Some(limit) = limit {
+        let booked: Vec<Reservation> = overlapping.into_iter()
+            .filter(|other| other.room_id == reservation.room_id)
+            .cloned()
+            .collect();
+        let occupancy = peak(&booked, reservation.start, reservation.end);
+        if occupancy >= limit {
+            return Err(Error::RoomFull {
+                room_id: reservation.room_id.clone(),
+                occupancy: occupancy,
+                limit: limit,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Most reservations active at the same time during `[from, to[`
+pub fn peak(reservations: &[Reservation], from: u64, to: u64) -> u64 {
+    // Reservations starting (+1) and ending (-1)
+    let mut events: Vec<(u64, i64)> = Vec::new();
+    for reservation in reservations.iter().filter(|reservation| reservation.is_active(from, to)) {
+        events.push((cmp::max(reservation.start, from), 1));
+        events.push((cmp::min(reservation.end, to), -1));
+    }
+
+    // A reservation ending sorts before one starting at the same time
+    events.sort();
+    let mut current: i64 = 0;
+    let mut peak: i64 = 0;
+    for &(_, change) in &events {
+        current += change;
+        peak = cmp::max(peak, current);
+    }
+
+    peak as u64
+}
+
+/// Get the reservation of the user `user_id` for the room `room_id` covering
+/// the UNIX time `now`
+///
+/// # Return Value
+/// The reservation, an `Error::Forbidden` when there is none or the error of
+/// the store.
+pub fn current(store: &RoomStore, user_id: &str, room_id: &str, now: u64) -> Result<Reservation> {
+    let query = ReservationQuery {
+        room_id: Some(room_id.to_owned()),
+        user_id: Some(user_id.to_owned()),
+        from: now,
+        to: now + 1,
+    };
+
+    match store.reservations(&query)?.into_iter().next() {
+        Some(reservation) => Ok(reservation),
+        None => {
+            Err(Error::Forbidden(format!("The user {} has no reservation for the room {} now",
+                                         user_id,
+                                         room_id)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use error::Error;
+    use store::{Reservation, NewReservation};
+    use super::{check_booking, peak};
+
+    fn reservation(id: &str, user_id: &str, start: u64, end: u64) -> Reservation {
+        Reservation {
+            id: id.to_owned(),
+            user_id: user_id.to_owned(),
+            room_id: "1".to_owned(),
+            start: start,
+            end: end,
+            created_at: 0,
+            cancelled_at: None,
+        }
+    }
+
+    fn booking(user_id: &str, start: u64, end: u64) -> NewReservation {
+        NewReservation {
+            user_id: user_id.to_owned(),
+            room_id: "1".to_owned(),
+            start: start,
+            end: end,
+            created_at: 0,
+        }
+    }
+
+    #[test]
+    fn back_to_back_reservations_dont_overlap() {
+        let reservations = vec![reservation("1", "a", 10, 20),
+                                reservation("2", "b", 20, 30),
+                                reservation("3", "c", 30, 40)];
+
+        assert_eq!(peak(&reservations, 0, 100), 1);
+        assert_eq!(peak(&reservations, 20, 30), 1);
+    }
+
+    #[test]
+    fn nested_reservations_add_up() {
+        let reservations = vec![reservation("1", "a", 10, 100),
+                                reservation("2", "b", 20, 80),
+                                reservation("3", "c", 30, 40),
+                                reservation("4", "d", 50, 60)];
+
+        assert_eq!(peak(&reservations, 0, 100), 3);
+        assert_eq!(peak(&reservations, 40, 50), 2);
+        assert_eq!(peak(&reservations, 100, 200), 0);
+    }
+
+    #[test]
+    fn cancelled_reservations_are_ignored() {
+        let mut cancelled = reservation("2", "b", 10, 20);
+        cancelled.cancelled_at = Some(5);
+
+        assert_eq!(peak(&[reservation("1", "a", 10, 20), cancelled], 0, 100), 1);
+    }
+
+    #[test]
+    fn bookings_are_checked_against_the_overlapping_reservations() {
+        let booked = vec![reservation("1", "a", 10, 20), reservation("2", "b", 15, 30)];
+
+        match check_booking(&booking("a", 18, 25), &booked, None) {
+            Err(Error::Conflict(_)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        match check_booking(&booking("c", 18, 25), &booked, Some(2)) {
+            Err(Error::RoomFull { occupancy: 2, limit: 2, .. }) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(check_booking(&booking("c", 20, 25), &booked, Some(2)).is_ok());
+        assert!(check_booking(&booking("c", 18, 25), &booked, None).is_ok());
+        assert!(check_booking(&booking("c", 25, 25), &booked, None).is_err());
+    }
+}
@@ -0,0 +1,414 @@
+//! Events scheduled in the spaces by `FenixEDU`.
+//!
+//! Asked with `?day=dd/mm/yyyy`, the Spaces API lists the events of a space
+//! during that day: lessons, evaluations (exams and tests) and generic
+//! events. `FenixEDU` writes their times in Lisbon time, `dd/mm/yyyy HH:MM`;
+//! they are turned into UNIX times here, following the Portuguese summer time
+//! (from the last Sunday of March to the last Sunday of October, 01:00 UTC).
+use std::fmt;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use error::{Error, Result};
+use utils;
+
+/// Seconds in a day
+const DAY: u64 = 24 * 60 * 60;
+
+/// Seconds in an hour
+const HOUR: u64 = 60 * 60;
+
+// /////////////////////////////////////////////////////////////////////////////
+// Days
+// /////////////////////////////////////////////////////////////////////////////
+/// A day of the calendar, written `dd/mm/yyyy`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Day {
+    pub year: u64,
+    pub month: u64,
+    pub day: u64,
+}
+
+impl Day {
+    /// Parse a day written `dd/mm/yyyy`
+    ///
+    /// # Return Value
+    /// The day or an `Error::Validation` when it isn't a valid date from 1970
+    /// on.
+    pub fn parse(text: &str) -> Result<Day> {
+        let invalid = || Error::Validation(format!("{} isn't a day written dd/mm/yyyy", text));
+
+        let parts: Vec<u64> = text.trim()
+            .split('/')
+            .map(|part| part.parse().ok())
+            .collect::<Option<Vec<u64>>>()
+            .ok_or_else(&invalid)?;
+        if parts.len() != 3 {
+            return Err(invalid());
+        }
+
+        let day = Day {
+            year: parts[2],
+            month: parts[1],
+            day: parts[0],
+        };
+        if day.year < 1970 || day.month < 1 || day.month > 12 || day.day < 1 ||
+           day.day > days_in_month(day.year, day.month) {
+            return Err(invalid());
+        }
+
+        Ok(day)
+    }
+
+    /// Day of the UNIX time `time` in Lisbon
+    pub fn of(time: u64) -> Day {
+        from_days((time + utc_offset(time)) / DAY)
+    }
+
+    /// Today in Lisbon
+    pub fn today() -> Day {
+        Day::of(utils::unix_time())
+    }
+
+    /// UNIX time of the Lisbon time `seconds` after the start of the day
+    pub fn at(&self, seconds: u64) -> u64 {
+        let local: u64 = days(self.year, self.month, self.day) * DAY + seconds;
+
+        local - utc_offset(local.saturating_sub(HOUR))
+    }
+}
+
+impl fmt::Display for Day {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02}/{:02}/{:04}", self.day, self.month, self.year)
+    }
+}
+
+impl Serialize for Day {
+    fn serialize<S>(&self, serializer: &mut S) -> ::std::result::Result<(), S::Error>
+        where S: Serializer
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+fn is_leap_year(year: u64) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+fn days_in_month(year: u64, month: u64) -> u64 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days from the UNIX epoch to the day `day` of `month` of `year`
+fn days(year: u64, month: u64, day: u64) -> u64 {
+    // Years start in March, so the leap day ends them
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+/// Day reached `days` after the UNIX epoch, the reverse of `days()`
+fn from_days(days: u64) -> Day {
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 -
+                       day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    Day {
+        year: year,
+        month: month,
+        day: day,
+    }
+}
+
+/// Days from the UNIX epoch to the last Sunday of `month`, which has 31 days
+fn last_sunday(year: u64, month: u64) -> u64 {
+    let last: u64 = days(year, month, 31);
+
+    // The UNIX epoch was a Thursday
+    last - (last + 4) % 7
+}
+
+/// Seconds Lisbon is ahead of UTC at the UNIX time `time`
+fn utc_offset(time: u64) -> u64 {
+    let year: u64 = from_days(time / DAY).year;
+    let start: u64 = last_sunday(year, 3) * DAY + HOUR;
+    let end: u64 = last_sunday(year, 10) * DAY + HOUR;
+
+    if start <= time && time < end { HOUR } else { 0 }
+}
+
+/// Parse a time written `dd/mm/yyyy HH:MM` by `FenixEDU`
+///
+/// `24:00` is the end of the day, the only time with the hour 24.
+///
+/// # Return Value
+/// The UNIX time or an `Error::Decode`.
+fn parse_time(text: &str) -> Result<u64> {
+    let invalid = || Error::Decode(format!("{} isn't a time written dd/mm/yyyy HH:MM", text));
+
+    let mut parts = text.split_whitespace();
+    let day: Day = parts.next().and_then(|day| Day::parse(day).ok()).ok_or_else(&invalid)?;
+    let mut clock = parts.next().ok_or_else(&invalid)?.splitn(2, ':');
+    let hours: u64 = clock.next().and_then(|hours| hours.parse().ok()).ok_or_else(&invalid)?;
+    let minutes: u64 = clock.next().and_then(|minutes| minutes.parse().ok()).ok_or_else(&invalid)?;
+
+    if (hours < 24 && minutes < 60) || (hours == 24 && minutes == 0) {
+        Ok(day.at(hours * HOUR + minutes * 60))
+    } else {
+        Err(invalid())
+    }
+}
+
+// /////////////////////////////////////////////////////////////////////////////
+// Events
+// /////////////////////////////////////////////////////////////////////////////
+/// Type of a scheduled event
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventType {
+    Lesson,
+    Evaluation,
+    Generic,
+    /// A type unknown to this crate, with its name
+    Other(String),
+}
+
+impl EventType {
+    /// Type named `name` by `FenixEDU`
+    pub fn from_name(name: &str) -> EventType {
+        match name {
+            "LESSON" => EventType::Lesson,
+            "EVALUATION" => EventType::Evaluation,
+            "GENERIC" => EventType::Generic,
+            _ => EventType::Other(name.to_owned()),
+        }
+    }
+
+    /// Name of the type in `FenixEDU`
+    pub fn name(&self) -> &str {
+        match *self {
+            EventType::Lesson => "LESSON",
+            EventType::Evaluation => "EVALUATION",
+            EventType::Generic => "GENERIC",
+            EventType::Other(ref name) => name,
+        }
+    }
+
+    /// True for the lessons and evaluations, during which the room is taken
+    /// by a class
+    pub fn is_class(&self) -> bool {
+        *self == EventType::Lesson || *self == EventType::Evaluation
+    }
+}
+
+impl Serialize for EventType {
+    fn serialize<S>(&self, serializer: &mut S) -> ::std::result::Result<(), S::Error>
+        where S: Serializer
+    {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl Deserialize for EventType {
+    fn deserialize<D>(deserializer: &mut D) -> ::std::result::Result<EventType, D::Error>
+        where D: Deserializer
+    {
+        String::deserialize(deserializer).map(|name| EventType::from_name(&name))
+    }
+}
+
+/// Course of a lesson or an evaluation
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq)]
+pub struct Course {
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub acronym: String,
+    #[serde(default)]
+    pub name: String,
+}
+
+/// An event scheduled in a space
+///
+/// # Fields
+/// * `type` => type of the event;
+/// * `start` => UNIX time at which the event starts;
+/// * `end` => UNIX time at which the event ends;
+/// * `title` => title of an evaluation or a generic event;
+/// * `description` => description of a generic event;
+/// * `info` => kind of lesson, e.g. `T` for theory;
+/// * `courses` => courses of a lesson or an evaluation.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ScheduledEvent {
+    #[serde(rename="type")]
+    pub kind: EventType,
+    pub start: u64,
+    pub end: u64,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub info: Option<String>,
+    #[serde(skip_serializing_if="Vec::is_empty")]
+    pub courses: Vec<Course>,
+}
+
+impl ScheduledEvent {
+    /// True when the event is taking place at the UNIX time `time`
+    pub fn is_active(&self, time: u64) -> bool {
+        self.start <= time && time < self.end
+    }
+}
+
+/// An event as written by `FenixEDU`: lessons have a `course`, evaluations
+/// `courses`
+#[derive(Deserialize)]
+struct FenixEvent {
+    #[serde(rename="type")]
+    kind: EventType,
+    start: String,
+    end: String,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    info: Option<String>,
+    #[serde(default)]
+    course: Option<Course>,
+    #[serde(default)]
+    courses: Vec<Course>,
+}
+
+/// A space document with its events
+#[derive(Deserialize)]
+struct ScheduleDocument {
+    #[serde(default)]
+    events: Vec<FenixEvent>,
+}
+
+/// Read the events of a space document requested with `?day=`
+///
+/// # Return Value
+/// The events, first to start first, or an `Error::Decode` for a malformed
+/// document.
+pub fn events(document: &str) -> Result<Vec<ScheduledEvent>> {
+    let document: ScheduleDocument = utils::from_json_to_obj(document)?;
+
+    let mut events: Vec<ScheduledEvent> = Vec::with_capacity(document.events.len());
+    for event in document.events {
+        let mut courses: Vec<Course> = event.courses;
+        courses.extend(event.course);
+
+        events.push(ScheduledEvent {
+            kind: event.kind,
+            start: parse_time(&event.start)?,
+            end: parse_time(&event.end)?,
+            title: event.title,
+            description: event.description,
+            info: event.info,
+            courses: courses,
+        });
+    }
+    events.sort_by_key(|event| (event.start, event.end));
+
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Day, DAY, HOUR, days, from_days, is_leap_year, last_sunday, parse_time};
+
+    fn day(year: u64, month: u64, day: u64) -> Day {
+        Day {
+            year: year,
+            month: month,
+            day: day,
+        }
+    }
+
+    #[test]
+    fn leap_years() {
+        assert!(is_leap_year(2016));
+        assert!(is_leap_year(2000));
+        assert!(!is_leap_year(2017));
+        assert!(!is_leap_year(1900));
+
+        assert_eq!(Day::parse("29/02/2016").unwrap(), day(2016, 2, 29));
+        assert_eq!(Day::parse("29/02/2000").unwrap(), day(2000, 2, 29));
+        assert!(Day::parse("29/02/2017").is_err());
+        assert!(Day::parse("29/02/1900").is_err());
+        assert_eq!(days(2016, 2, 29), 16860);
+        assert_eq!(days(2016, 3, 1), 16861);
+    }
+
+    #[test]
+    fn days_and_from_days_are_reverse() {
+        assert_eq!(days(1970, 1, 1), 0);
+        assert_eq!(from_days(0), day(1970, 1, 1));
+        assert_eq!(from_days(11016), day(2000, 2, 29));
+
+        // Every day from 1970 to 2100
+        for count in 0..47482 {
+            let found: Day = from_days(count);
+            assert_eq!(days(found.year, found.month, found.day), count);
+        }
+    }
+
+    #[test]
+    fn summer_time_starts_and_ends_on_the_last_sundays() {
+        assert_eq!(last_sunday(2017, 3), days(2017, 3, 26));
+        assert_eq!(last_sunday(2017, 10), days(2017, 10, 29));
+        assert_eq!(last_sunday(2018, 3), days(2018, 3, 25));
+        assert_eq!(last_sunday(2018, 10), days(2018, 10, 28));
+        assert_eq!(last_sunday(2020, 3), days(2020, 3, 29));
+        assert_eq!(last_sunday(2020, 10), days(2020, 10, 25));
+
+        // Noon in Lisbon is 11:00 UTC in the summer, 12:00 in the winter
+        let start: u64 = days(2017, 3, 26) * DAY;
+        assert_eq!(day(2017, 3, 26).at(0), start);
+        assert_eq!(day(2017, 3, 26).at(12 * HOUR), start + 11 * HOUR);
+        let end: u64 = days(2017, 10, 29) * DAY;
+        assert_eq!(day(2017, 10, 28).at(12 * HOUR), end - DAY + 11 * HOUR);
+        assert_eq!(day(2017, 10, 29).at(12 * HOUR), end + 12 * HOUR);
+
+        // 23:30 UTC is already the next day in the summer
+        assert_eq!(Day::of(start + 23 * HOUR + 30 * 60), day(2017, 3, 27));
+        assert_eq!(Day::of(end + 23 * HOUR + 30 * 60), day(2017, 10, 29));
+    }
+
+    #[test]
+    fn times_of_fenix() {
+        let midnight: u64 = days(2017, 11, 20) * DAY;
+
+        assert_eq!(parse_time("20/11/2017 10:30").unwrap(), midnight + 10 * HOUR + 30 * 60);
+        assert_eq!(parse_time("20/11/2017 00:00").unwrap(), midnight);
+        assert_eq!(parse_time("20/11/2017 24:00").unwrap(), midnight + DAY);
+        assert_eq!(parse_time("01/07/2017 10:00").unwrap(), 1498867200 + 9 * HOUR);
+
+        assert!(parse_time("20/11/2017 24:01").is_err());
+        assert!(parse_time("20/11/2017 24:59").is_err());
+        assert!(parse_time("20/11/2017 25:00").is_err());
+        assert!(parse_time("20/11/2017 10:60").is_err());
+        assert!(parse_time("20/11/2017 10").is_err());
+        assert!(parse_time("20/11/2017").is_err());
+        assert!(parse_time("10:30").is_err());
+    }
+}
@@ -0,0 +1,323 @@
+//! Search of the spaces by name.
+//!
+//! A `SearchIndex` holds the name, path and type of every space of a
+//! `Snapshot` of the space tree. Names and queries are compared through
+//! `utils::slugify()`, so case, accents and punctuation don't matter. A space
+//! matches when its name equals the query, starts with it, has a word
+//! starting with it, contains it or, failing that, when every word of the
+//! query is close to a word of the name (a few typos, see `typos_allowed()`).
+//! The hits are ranked in that order, shorter names first.
+extern crate serde_json;
+
+use std::cmp;
+use std::collections::VecDeque;
+
+use error::{Error, Result};
+use fenix::{ContainedSpace, FenixSpacesClient, SpaceType};
+use snapshot::{self, Snapshot};
+use utils;
+
+/// Hits returned when the client doesn't ask for a number
+pub const DEFAULT_LIMIT: usize = 20;
+
+/// Most hits returned
+pub const MAX_LIMIT: usize = 100;
+
+/// How a space matched the query
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum MatchKind {
+    #[serde(rename="exact")]
+    Exact,
+    #[serde(rename="prefix")]
+    Prefix,
+    #[serde(rename="word_prefix")]
+    WordPrefix,
+    #[serde(rename="substring")]
+    Substring,
+    #[serde(rename="fuzzy")]
+    Fuzzy,
+}
+
+/// A space found by a search
+///
+/// # Fields
+/// * `id` => id of the space;
+/// * `name` => name of the space;
+/// * `path` => path of the space, as used by `/api/path/`;
+/// * `type` => type of the space in `FenixEDU`, when known;
+/// * `is_room` => true when the space is a room, see `Space::is_room()`;
+/// * `matched` => how the name matched the query;
+/// * `typos` => letters to change in the query to match the name, for fuzzy
+///   matches.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Hit {
+    pub id: String,
+    pub name: String,
+    pub path: String,
+    #[serde(rename="type", skip_serializing_if="Option::is_none")]
+    pub kind: Option<SpaceType>,
+    pub is_room: bool,
+    pub matched: MatchKind,
+    pub typos: usize,
+}
+
+/// A space of the index
+struct Entry {
+    id: String,
+    name: String,
+    slug: String,
+    path: String,
+    kind: Option<SpaceType>,
+    is_room: bool,
+}
+
+/// Every space of the tree, ready to be searched
+pub struct SearchIndex {
+    created: u64,
+    entries: Vec<Entry>,
+}
+
+impl SearchIndex {
+    /// Index the spaces of `snapshot`, walking it from the top level spaces
+    ///
+    /// # Return Value
+    /// The index or an `Error::Decode` for a malformed document.
+    pub fn from_snapshot(snapshot: &Snapshot) -> Result<SearchIndex> {
+        let mut entries: Vec<Entry> = Vec::new();
+        // Spaces to visit with the path of their parent
+        let mut pending: VecDeque<(String, String)> = VecDeque::new();
+        pending.push_back((String::new(), String::new()));
+
+        while let Some((id, path)) = pending.pop_front() {
+            let document: &serde_json::Value = match snapshot.documents.get(&id) {
+                Some(document) => document,
+                None => continue,
+            };
+            let children: Vec<ContainedSpace> = snapshot::children(&id, document)?;
+
+            let names: Vec<&str> = children.iter().map(|child| child.name.as_str()).collect();
+            for (child, slug) in children.iter().zip(utils::sibling_slugs(&names)) {
+                // Spaces without a usable name can't be part of a path
+                if slug.is_empty() {
+                    continue;
+                }
+                let path: String = if path.is_empty() {
+                    slug.clone()
+                } else {
+                    format!("{}/{}", path, slug)
+                };
+
+                if let Some(document) = snapshot.documents.get(&child.id) {
+                    let kind: Option<SpaceType> = document.find("type")
+                        .and_then(|kind| kind.as_str())
+                        .map(SpaceType::from_name);
+                    let is_room: bool = match kind {
+                        Some(SpaceType::Other(_)) | None => {
+                            snapshot::children(&child.id, document)?.is_empty()
+                        }
+                        Some(ref kind) => kind.is_room(),
+                    };
+
+                    entries.push(Entry {
+                        id: child.id.clone(),
+                        name: child.name.clone(),
+                        slug: utils::slugify(&child.name),
+                        path: path.clone(),
+                        kind: kind,
+                        is_room: is_room,
+                    });
+                    pending.push_back((child.id.clone(), path));
+                }
+            }
+        }
+
+        Ok(SearchIndex {
+            created: snapshot.created,
+            entries: entries,
+        })
+    }
+
+    /// Crawl the space tree with `client` and index it
+    ///
+    /// # Return Value
+    /// The index or the error that aborted the crawl.
+    pub fn crawl(client: &FenixSpacesClient) -> Result<SearchIndex> {
+        SearchIndex::from_snapshot(&Snapshot::crawl(client)?)
+    }
+
+    /// Unix time of the snapshot indexed
+    pub fn created(&self) -> u64 {
+        self.created
+    }
+
+    /// Number of spaces indexed
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// True when no space is indexed
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Search the spaces named like `query`
+    ///
+    /// # Arguments
+    /// * `query` => text to search;
+    /// * `limit` => most hits returned.
+    ///
+    /// # Return Value
+    /// The best `limit` hits, best first, or an `Error::Validation` when the
+    /// query has no letters or digits.
+    pub fn search(&self, query: &str, limit: usize) -> Result<Vec<Hit>> {
+        let query: String = utils::slugify(query);
+        if query.is_empty() {
+            return Err(Error::Validation("q must have letters or digits".to_owned()));
+        }
+        let words: Vec<&str> = query.split('-').collect();
+
+        let mut hits: Vec<(MatchKind, usize, &Entry)> = self.entries
+            .iter()
+            .filter_map(|entry| {
+                matches(&query, &words, &entry.slug).map(|(kind, typos)| (kind, typos, entry))
+            })
+            .collect();
+        hits.sort_by(|a, b| {
+            (a.0, a.1, a.2.name.len(), &a.2.path).cmp(&(b.0, b.1, b.2.name.len(), &b.2.path))
+        });
+
+        Ok(hits.into_iter()
+            .take(limit)
+            .map(|(kind, typos, entry)| {
+                Hit {
+                    id: entry.id.clone(),
+                    name: entry.name.clone(),
+                    path: entry.path.clone(),
+                    kind: entry.kind.clone(),
+                    is_room: entry.is_room,
+                    matched: kind,
+                    typos: typos,
+                }
+            })
+            .collect())
+    }
+}
+
+/// How the slug `slug` matches the slugified query `query` made of `words`
+///
+/// # Return Value
+/// The kind of match and the number of typos, or `None` when it doesn't
+/// match.
+fn matches(query: &str, words: &[&str], slug: &str) -> Option<(MatchKind, usize)> {
+    if slug == query {
+        return Some((MatchKind::Exact, 0));
+    }
+    if slug.starts_with(query) {
+        return Some((MatchKind::Prefix, 0));
+    }
+    if slug.split('-').any(|word| word.starts_with(query)) ||
+       slug.contains(&format!("-{}", query)) {
+        return Some((MatchKind::WordPrefix, 0));
+    }
+    if slug.contains(query) {
+        return Some((MatchKind::Substring, 0));
+    }
+
+    // Every word of the query must be close to a word of the name
+    let names: Vec<&str> = slug.split('-').collect();
+    let mut typos = 0;
+    for word in words {
+        let closest: Option<usize> = names.iter()
+            .map(|name| word_distance(word, name))
+            .filter(|&distance| distance <= typos_allowed(word))
+            .min();
+        match closest {
+            Some(distance) => typos += distance,
+            None => return None,
+        }
+    }
+
+    Some((MatchKind::Fuzzy, typos))
+}
+
+/// Typos tolerated in a word of the query: none up to 3 letters, one up to 7
+/// letters and two for longer words
+fn typos_allowed(word: &str) -> usize {
+    match word.chars().count() {
+        0...3 => 0,
+        4...7 => 1,
+        _ => 2,
+    }
+}
+
+/// Edit distance between the word of the query `word` and the word of a name
+/// `name`. The end of `name` is ignored beyond the length of `word`, so a
+/// prefix with typos also matches.
+fn word_distance(word: &str, name: &str) -> usize {
+    let word: Vec<char> = word.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    // Levenshtein distance of `word` with every prefix of `name`
+    let mut previous: Vec<usize> = (0..name.len() + 1).collect();
+    for (i, &a) in word.iter().enumerate() {
+        let mut current: Vec<usize> = vec![i + 1];
+        for (j, &b) in name.iter().enumerate() {
+            let substitution = previous[j] + if a == b { 0 } else { 1 };
+            current.push(cmp::min(substitution, cmp::min(previous[j + 1], current[j]) + 1));
+        }
+        previous = current;
+    }
+
+    previous.into_iter().min().unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{matches, typos_allowed, word_distance, MatchKind};
+
+    const SLUG: &'static str = "anfiteatro-abreu-faro";
+
+    fn search(query: &str) -> Option<(MatchKind, usize)> {
+        let words: Vec<&str> = query.split('-').collect();
+        matches(query, &words, SLUG)
+    }
+
+    #[test]
+    fn matches_from_the_best_kind() {
+        assert_eq!(search(SLUG), Some((MatchKind::Exact, 0)));
+        assert_eq!(search("anfi"), Some((MatchKind::Prefix, 0)));
+        assert_eq!(search("abreu"), Some((MatchKind::WordPrefix, 0)));
+        assert_eq!(search("abreu-fa"), Some((MatchKind::WordPrefix, 0)));
+        assert_eq!(search("teatro"), Some((MatchKind::Substring, 0)));
+        assert_eq!(search("anfitaetro"), Some((MatchKind::Fuzzy, 2)));
+        assert_eq!(search("biblioteca"), None);
+    }
+
+    #[test]
+    fn kinds_are_ordered_from_the_best() {
+        assert!(MatchKind::Exact < MatchKind::Prefix);
+        assert!(MatchKind::Prefix < MatchKind::WordPrefix);
+        assert!(MatchKind::WordPrefix < MatchKind::Substring);
+        assert!(MatchKind::Substring < MatchKind::Fuzzy);
+    }
+
+    #[test]
+    fn prefixes_with_typos_match() {
+        assert_eq!(word_distance("anfitw", "anfiteatro"), 1);
+        assert_eq!(search("anfitw"), Some((MatchKind::Fuzzy, 1)));
+        assert_eq!(search("abru-faro"), Some((MatchKind::Fuzzy, 1)));
+    }
+
+    #[test]
+    fn typos_are_limited_by_the_length() {
+        assert_eq!(typos_allowed("ga1"), 0);
+        assert_eq!(typos_allowed("sala"), 1);
+        assert_eq!(typos_allowed("anfitea"), 1);
+        assert_eq!(typos_allowed("anfiteat"), 2);
+
+        assert_eq!(search("afr"), None);
+        assert_eq!(search("anfitwx"), None);
+        assert_eq!(search("anfitwxt"), Some((MatchKind::Fuzzy, 2)));
+        assert_eq!(search("anfitwxy"), None);
+    }
+}
@@ -0,0 +1,208 @@
+//! Offline snapshot of the `FenixEDU` space tree.
+//!
+//! A crawler walks the hierarchy from the top level spaces through every
+//! `containedSpaces` and keeps each space document, keyed by id, in a
+//! `Snapshot`. The snapshot is saved to a JSON file and reloaded on startup.
+//! A `FenixSpacesClient` with a `SnapshotStore` attached serves the spaces
+//! from it and only asks `FenixEDU` for the ids it doesn't have.
+//!
+//! `spawn_refresher()` crawls again in the background on a fixed interval.
+extern crate serde_json;
+
+use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use error::{Error, Result};
+use fenix::{ContainedSpace, FenixSpacesClient};
+use utils;
+
+/// Seconds to wait before crawling again after a failure
+pub const RETRY_DELAY: u64 = 300;
+
+/// The whole space tree at a point in time
+///
+/// # Fields
+/// * `created` => Unix time of the crawl;
+/// * `documents` => JSON document of every space by id. The top level spaces
+///   are kept under the empty id, as in the `FenixEDU` API.
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct Snapshot {
+    pub created: u64,
+    pub documents: BTreeMap<String, serde_json::Value>,
+}
+
+impl Snapshot {
+    /// Crawl the space tree with `client`
+    ///
+    /// Spaces that disappear during the crawl are skipped. Any other error
+    /// aborts it.
+    ///
+    /// # Return Value
+    /// The snapshot or the error that aborted the crawl.
+    pub fn crawl(client: &FenixSpacesClient) -> Result<Snapshot> {
+        let mut documents = BTreeMap::new();
+        let mut pending: VecDeque<String> = VecDeque::new();
+        pending.push_back(String::new());
+
+        while let Some(id) = pending.pop_front() {
+            if documents.contains_key(&id) {
+                continue;
+            }
+
+            let body: String = match client.document(&id) {
+                Ok(body) => body,
+                Err(Error::NotFound(_)) => continue,
+                Err(err) => return Err(err),
+            };
+            let document: serde_json::Value = utils::from_json_to_obj(&body)?;
+
+            for child in children(&id, &document)? {
+                pending.push_back(child.id);
+            }
+            documents.insert(id, document);
+        }
+
+        Ok(Snapshot {
+            created: utils::unix_time(),
+            documents: documents,
+        })
+    }
+
+    /// Load a snapshot saved with `save()`
+    pub fn load(path: &Path) -> Result<Snapshot> {
+        let mut contents = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut contents))
+            .map_err(|err| Error::Internal(format!("{}: {}", path.display(), err)))?;
+
+        utils::from_json_to_obj(&contents)
+    }
+
+    /// Save the snapshot to `path`, replacing the previous file atomically
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp: PathBuf = path.with_extension("tmp");
+        let contents: String = utils::from_obj_to_json(self)?;
+
+        File::create(&tmp)
+            .and_then(|mut file| file.write_all(contents.as_bytes()).and_then(|_| file.sync_all()))
+            .and_then(|_| fs::rename(&tmp, path))
+            .map_err(|err| Error::Internal(format!("{}: {}", path.display(), err)))
+    }
+}
+
+/// Spaces contained in a document: the document itself for the top level
+/// spaces, its `containedSpaces` otherwise.
+pub fn children(id: &str, document: &serde_json::Value) -> Result<Vec<ContainedSpace>> {
+    let contained = if id.is_empty() {
+        Some(document.clone())
+    } else {
+        document.find("containedSpaces").cloned()
+    };
+
+    match contained {
+        Some(contained) => {
+            serde_json::from_value(contained).map_err(|err| Error::Decode(format!("{}", err)))
+        }
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Shared, replaceable snapshot backed by a file
+pub struct SnapshotStore {
+    path: PathBuf,
+    current: RwLock<Option<Arc<Snapshot>>>,
+}
+
+impl SnapshotStore {
+    /// Create a store for the snapshot file at `path`, loading it if it exists
+    ///
+    /// A file that can't be read is reported and ignored, it is replaced by
+    /// the next crawl.
+    pub fn open<P: AsRef<Path>>(path: P) -> SnapshotStore {
+        let path: PathBuf = path.as_ref().to_path_buf();
+
+        let current = if path.exists() {
+            match Snapshot::load(&path) {
+                Ok(snapshot) => Some(Arc::new(snapshot)),
+                Err(err) => {
+                    println!("Ignoring the space snapshot: {}", err);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        SnapshotStore {
+            path: path,
+            current: RwLock::new(current),
+        }
+    }
+
+    /// Current snapshot, if any
+    pub fn current(&self) -> Option<Arc<Snapshot>> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Document of the space `id` in the current snapshot
+    pub fn document(&self, id: &str) -> Option<String> {
+        self.current()
+            .and_then(|snapshot| snapshot.documents.get(id).map(|document| document.to_string()))
+    }
+
+    /// Crawl again with `client`, save the result and make it current
+    pub fn refresh(&self, client: &FenixSpacesClient) -> Result<()> {
+        let snapshot = Snapshot::crawl(client)?;
+        snapshot.save(&self.path)?;
+
+        *self.current.write().unwrap() = Some(Arc::new(snapshot));
+        Ok(())
+    }
+
+    /// Seconds until the current snapshot is older than `interval`
+    fn due_in(&self, interval: u64) -> u64 {
+        match self.current() {
+            Some(snapshot) => (snapshot.created + interval).saturating_sub(utils::unix_time()),
+            None => 0,
+        }
+    }
+}
+
+/// Refresh `store` every `interval` seconds in a background thread
+///
+/// The first crawl happens right away when there is no snapshot or when it is
+/// already older than `interval`.
+///
+/// # Arguments
+/// * `store` => the snapshot to refresh;
+/// * `client` => client used for the crawl. It should not share the cache of
+///   the handlers, the crawl would evict every entry;
+/// * `interval` => seconds between crawls.
+pub fn spawn_refresher(store: Arc<SnapshotStore>,
+                       client: FenixSpacesClient,
+                       interval: u64)
+                       -> JoinHandle<()> {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(Duration::from_secs(store.due_in(interval)));
+
+            match store.refresh(&client) {
+                Ok(()) => {
+                    let size = store.current().map(|snapshot| snapshot.documents.len());
+                    println!("Space snapshot refreshed ({} spaces)", size.unwrap_or(0));
+                }
+                Err(err) => {
+                    println!("Space snapshot refresh failed: {}", err);
+                    // Don't retry in a tight loop when FenixEDU is down
+                    thread::sleep(Duration::from_secs(cmp::min(interval, RETRY_DELAY)));
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,285 @@
+//! Occupancy analytics of the rooms.
+//!
+//! The figures are computed from the visits kept by the store, clipped to the
+//! requested period. A visit still open counts until `now`.
+use std::cmp;
+
+use error::{Error, Result};
+use store::{Room, Visit};
+
+/// Most periods a report may be split into
+pub const MAX_PERIODS: u64 = 24 * 366;
+
+/// Length of the periods a report is split into
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum Granularity {
+    #[serde(rename="hour")]
+    Hour,
+    #[serde(rename="day")]
+    Day,
+}
+
+impl Granularity {
+    /// Parse the name of a granularity (`hour` or `day`)
+    pub fn from_name(name: &str) -> Result<Granularity> {
+        match name {
+            "hour" => Ok(Granularity::Hour),
+            "day" => Ok(Granularity::Day),
+            _ => Err(Error::Validation(format!("unknown granularity {} (use hour or day)", name))),
+        }
+    }
+
+    /// Seconds in a period
+    pub fn seconds(&self) -> u64 {
+        match *self {
+            Granularity::Hour => 60 * 60,
+            Granularity::Day => 24 * 60 * 60,
+        }
+    }
+}
+
+/// Occupancy of a room during `[from, to[`
+///
+/// # Fields
+/// * `visits` => number of visits overlapping the period;
+/// * `peak_occupancy` => most users in the room at the same time;
+/// * `average_occupancy` => users in the room on average;
+/// * `utilisation` => `average_occupancy` over the capacity, when known;
+/// * `average_stay` => seconds the closed visits lasted on average, when any.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Occupancy {
+    pub from: u64,
+    pub to: u64,
+    pub visits: usize,
+    pub peak_occupancy: u64,
+    pub average_occupancy: f64,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub utilisation: Option<f64>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub average_stay: Option<u64>,
+}
+
+/// Occupancy of a room over the whole period and in each sub-period
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RoomOccupancy {
+    pub room_id: String,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub capacity: Option<u64>,
+    pub total: Occupancy,
+    #[serde(skip_serializing_if="Vec::is_empty")]
+    pub periods: Vec<Occupancy>,
+}
+
+/// Occupancy of every room
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct OccupancyReport {
+    pub from: u64,
+    pub to: u64,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub granularity: Option<Granularity>,
+    pub rooms: Vec<RoomOccupancy>,
+}
+
+/// Compute the occupancy of `rooms` during `[from, to[`
+///
+/// # Arguments
+/// * `rooms` => rooms to report on;
+/// * `visits` => visits of the rooms, others are ignored;
+/// * `from` => UNIX time of the start of the period;
+/// * `to` => UNIX time of the end of the period;
+/// * `granularity` => length of the sub-periods, `None` for the total only.
+///   The sub-periods are aligned on UTC hours or days;
+/// * `now` => UNIX time at which the open visits end.
+///
+/// # Return Value
+/// The report or an `Error::Validation` when the period is empty or has more
+/// than `MAX_PERIODS` sub-periods.
+pub fn report(rooms: &[Room],
+              visits: &[Visit],
+              from: u64,
+              to: u64,
+              granularity: Option<Granularity>,
+              now: u64)
+              -> Result<OccupancyReport> {
+    if from >= to {
+        return Err(Error::Validation("from must be before to".to_owned()));
+    }
+    let periods: Vec<(u64, u64)> = match granularity {
+        Some(granularity) => split(from, to, granularity)?,
+        None => Vec::new(),
+    };
+
+    let rooms: Vec<RoomOccupancy> = rooms.iter()
+        .map(|room| {
+            let capacity: Option<u64> = room.capacity_limit(0);
+            let visits: Vec<&Visit> = visits.iter()
+                .filter(|visit| visit.room_id == room.id)
+                .collect();
+
+            RoomOccupancy {
+                room_id: room.id.clone(),
+                capacity: capacity,
+                total: occupancy(&visits, capacity, from, to, now),
+                periods: periods.iter()
+                    .map(|&(start, end)| occupancy(&visits, capacity, start, end, now))
+                    .collect(),
+            }
+        })
+        .collect();
+
+    Ok(OccupancyReport {
+        from: from,
+        to: to,
+        granularity: granularity,
+        rooms: rooms,
+    })
+}
+
+/// Compute the occupancy during `[from, to[` from the visits of a room
+pub fn occupancy(visits: &[&Visit],
+                 capacity: Option<u64>,
+                 from: u64,
+                 to: u64,
+                 now: u64)
+                 -> Occupancy {
+    let mut count: usize = 0;
+    let mut seconds: u64 = 0;
+    let mut stays: Vec<u64> = Vec::new();
+    // Users entering (+1) and leaving (-1) the room
+    let mut events: Vec<(u64, i64)> = Vec::new();
+
+    for visit in visits {
+        let start = cmp::max(visit.checked_in_at, from);
+        let end = cmp::min(visit.checked_out_at.unwrap_or(now), to);
+        if end <= start {
+            continue;
+        }
+
+        count += 1;
+        seconds += end - start;
+        events.push((start, 1));
+        events.push((end, -1));
+        if let Some(out) = visit.checked_out_at {
+            stays.push(out.saturating_sub(visit.checked_in_at));
+        }
+    }
+
+    // A user leaving sorts before one entering at the same time
+    events.sort();
+    let mut current: i64 = 0;
+    let mut peak: i64 = 0;
+    for &(_, change) in &events {
+        current += change;
+        peak = cmp::max(peak, current);
+    }
+
+    let average = seconds as f64 / (to - from) as f64;
+
+    Occupancy {
+        from: from,
+        to: to,
+        visits: count,
+        peak_occupancy: peak as u64,
+        average_occupancy: average,
+        utilisation: capacity.and_then(|capacity| if capacity > 0 {
+            Some(average / capacity as f64)
+        } else {
+            None
+        }),
+        average_stay: if stays.is_empty() {
+            None
+        } else {
+            Some(stays.iter().sum::<u64>() / stays.len() as u64)
+        },
+    }
+}
+
+/// Split `[from, to[` at the boundaries of `granularity`
+fn split(from: u64, to: u64, granularity: Granularity) -> Result<Vec<(u64, u64)>> {
+    let length = granularity.seconds();
+    let first = from - from % length;
+    let span = to - first;
+    let count = span / length + if span % length == 0 { 0 } else { 1 };
+
+    if count > MAX_PERIODS {
+        return Err(Error::Validation(format!("The period can't be split in more than {} parts",
+                                             MAX_PERIODS)));
+    }
+
+    let mut periods: Vec<(u64, u64)> = Vec::new();
+    let mut start = first;
+    while start < to {
+        // The last period may end before its boundary, which could overflow
+        let end = cmp::min(start.saturating_add(length), to);
+        periods.push((cmp::max(start, from), end));
+        start = end;
+    }
+
+    Ok(periods)
+}
+
+#[cfg(test)]
+mod tests {
+    use store::{Visit, CheckOutReason};
+    use super::{Granularity, occupancy, split};
+
+    const HOUR: u64 = 60 * 60;
+
+    fn visit(checked_in_at: u64, checked_out_at: Option<u64>) -> Visit {
+        Visit {
+            user_id: "1".to_owned(),
+            room_id: "1".to_owned(),
+            checked_in_at: checked_in_at,
+            expected_duration: None,
+            checked_out_at: checked_out_at,
+            reason: checked_out_at.map(|_| CheckOutReason::User),
+        }
+    }
+
+    #[test]
+    fn counts_the_peak_of_overlapping_visits() {
+        let visits = vec![visit(0, Some(100)), visit(50, Some(150)), visit(100, Some(200))];
+        let visits: Vec<&Visit> = visits.iter().collect();
+
+        // The first user leaves when the third one enters
+        let report = occupancy(&visits, Some(4), 0, 200, 1000);
+        assert_eq!(report.visits, 3);
+        assert_eq!(report.peak_occupancy, 2);
+        assert_eq!(report.average_occupancy, 1.5);
+        assert_eq!(report.utilisation, Some(0.375));
+        assert_eq!(report.average_stay, Some(100));
+    }
+
+    #[test]
+    fn clips_the_visits_to_the_period() {
+        let visits = vec![visit(0, Some(100)), visit(150, None), visit(300, Some(400))];
+        let visits: Vec<&Visit> = visits.iter().collect();
+
+        // The open visit lasts until now
+        let report = occupancy(&visits, None, 50, 250, 200);
+        assert_eq!(report.visits, 2);
+        assert_eq!(report.peak_occupancy, 1);
+        assert_eq!(report.average_occupancy, 0.5);
+        assert_eq!(report.utilisation, None);
+        assert_eq!(report.average_stay, Some(100));
+    }
+
+    #[test]
+    fn splits_at_the_boundaries_of_the_granularity() {
+        assert_eq!(split(HOUR, 3 * HOUR, Granularity::Hour).unwrap(),
+                   vec![(HOUR, 2 * HOUR), (2 * HOUR, 3 * HOUR)]);
+        assert_eq!(split(HOUR + 30, 2 * HOUR + 30, Granularity::Hour).unwrap(),
+                   vec![(HOUR + 30, 2 * HOUR), (2 * HOUR, 2 * HOUR + 30)]);
+        assert_eq!(split(10, 20, Granularity::Day).unwrap(), vec![(10, 20)]);
+    }
+
+    #[test]
+    fn refuses_too_many_periods() {
+        let max: u64 = super::MAX_PERIODS;
+
+        assert_eq!(split(0, max * HOUR, Granularity::Hour).unwrap().len() as u64, max);
+        assert!(split(0, max * HOUR + 1, Granularity::Hour).is_err());
+        assert!(split(0, u64::max_value(), Granularity::Hour).is_err());
+        assert!(split(u64::max_value() - 1, u64::max_value(), Granularity::Hour).is_ok());
+    }
+}
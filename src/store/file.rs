@@ -0,0 +1,784 @@
+//! `RoomStore` kept in an embedded append-only file
+//!
+//! The file is made of JSON lines. The first line is a header with the schema
+//! version and every other line is an `Entry` describing one change. On
+//! startup the entries are replayed in memory. A file written with an older
+//! schema is migrated entry by entry and rewritten with the current one.
+//! Check-outs close the check-ins, which are kept as the history of visits.
+//!
+//! A line left incomplete by a crash, or by a failed write, is cut from the
+//! file before anything else is appended to it.
+extern crate serde_json;
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use error::{Error, Result};
+use reservations;
+use utils;
+use super::{RoomStore, User, Room, NewRoom, CheckIn, CheckOut, CheckOutReason, Visit,
+            Reservation, NewReservation, ReservationQuery};
+
+/// Version of the format written by this store
+const SCHEMA_VERSION: u32 = 4;
+
+/// Migrations of a single entry. `MIGRATIONS[i]` turns an entry of version
+/// `i + 1` into an entry of version `i + 2`.
+const MIGRATIONS: &'static [fn(serde_json::Value) -> Result<serde_json::Value>] =
+    &[timestamp_check_ins, add_reservations, type_capacities];
+
+/// First line of the file
+#[derive(Serialize, Deserialize)]
+struct Header {
+    schema_version: u32,
+}
+
+/// A change to the store
+#[derive(Serialize, Deserialize)]
+enum Entry {
+    User(User),
+    Room(Room),
+    CheckIn(CheckIn),
+    CheckOut(CheckOut),
+    /// A new reservation or the new state of an existing one
+    Reservation(Reservation),
+}
+
+/// Contents of the store after replaying every entry
+#[derive(Default)]
+struct State {
+    users: BTreeMap<String, User>,
+    rooms: BTreeMap<String, Room>,
+    checkins: Vec<CheckIn>,
+    history: Vec<Visit>,
+    reservations: BTreeMap<String, Reservation>,
+    last_user_id: u64,
+    last_room_id: u64,
+    last_reservation_id: u64,
+}
+
+impl State {
+    /// Apply a change that was already validated
+    fn apply(&mut self, entry: Entry) {
+        match entry {
+            Entry::User(user) => {
+                self.last_user_id = next_id(self.last_user_id, &user.id);
+                self.users.insert(user.id.clone(), user);
+            }
+            Entry::Room(room) => {
+                self.last_room_id = next_id(self.last_room_id, &room.id);
+                self.rooms.insert(room.id.clone(), room);
+            }
+            Entry::CheckIn(check_in) => self.checkins.push(check_in),
+            Entry::CheckOut(check_out) => {
+                let (closed, open): (Vec<CheckIn>, Vec<CheckIn>) = self.checkins
+                    .drain(..)
+                    .partition(|check_in| check_in.is_of(&check_out.user_id, &check_out.room_id));
+
+                self.checkins = open;
+                self.history
+                    .extend(closed.into_iter().map(|check_in| Visit::closed(check_in, &check_out)));
+            }
+            Entry::Reservation(reservation) => {
+                self.last_reservation_id = next_id(self.last_reservation_id, &reservation.id);
+                self.reservations.insert(reservation.id.clone(), reservation);
+            }
+        }
+    }
+
+    /// The entries needed to rebuild this state
+    fn entries(&self) -> Vec<Entry> {
+        let users = self.users.values().cloned().map(Entry::User);
+        let rooms = self.rooms.values().cloned().map(Entry::Room);
+        let history = self.history.iter().flat_map(visit_entries);
+        let checkins = self.checkins.iter().cloned().map(Entry::CheckIn);
+        let reservations = self.reservations.values().cloned().map(Entry::Reservation);
+
+        users.chain(rooms).chain(history).chain(checkins).chain(reservations).collect()
+    }
+
+    /// Check that a room exists
+    fn room(&self, room_id: &str) -> Result<&Room> {
+        match self.rooms.get(room_id) {
+            Some(room) => Ok(room),
+            None => Err(Error::NotFound(format!("The room id: {} was not found", room_id))),
+        }
+    }
+
+    /// True when the user `user_id` is checked in the room `room_id`
+    fn is_checked_in(&self, user_id: &str, room_id: &str) -> bool {
+        self.checkins.iter().any(|check_in| check_in.is_of(user_id, room_id))
+    }
+
+    /// Check that a reservation exists
+    fn reservation(&self, id: &str) -> Result<&Reservation> {
+        match self.reservations.get(id) {
+            Some(reservation) => Ok(reservation),
+            None => Err(Error::NotFound(format!("The reservation id: {} was not found", id))),
+        }
+    }
+
+    /// Check that a user exists
+    fn user(&self, user_id: &str) -> Result<&User> {
+        match self.users.get(user_id) {
+            Some(user) => Ok(user),
+            None => Err(Error::NotFound(format!("The user id: {} was not found", user_id))),
+        }
+    }
+}
+
+/// The check in and check out of a closed visit
+fn visit_entries(visit: &Visit) -> Vec<Entry> {
+    let check_in = CheckIn {
+        user_id: visit.user_id.clone(),
+        room_id: visit.room_id.clone(),
+        checked_in_at: visit.checked_in_at,
+        expected_duration: visit.expected_duration,
+    };
+    let check_out = CheckOut {
+        user_id: visit.user_id.clone(),
+        room_id: visit.room_id.clone(),
+        checked_out_at: visit.checked_out_at.unwrap_or(visit.checked_in_at),
+        reason: visit.reason.unwrap_or(CheckOutReason::User),
+    };
+
+    vec![Entry::CheckIn(check_in), Entry::CheckOut(check_out)]
+}
+
+/// Highest numeric id seen so far
+fn next_id(last: u64, id: &str) -> u64 {
+    match id.parse::<u64>() {
+        Ok(id) if id > last => id,
+        _ => last,
+    }
+}
+
+struct Inner {
+    state: State,
+    file: File,
+}
+
+/// Store of users, rooms and check-ins in a local file
+pub struct FileRoomStore {
+    path: PathBuf,
+    inner: Mutex<Inner>,
+}
+
+impl FileRoomStore {
+    /// Open the store at `path`, creating it if it doesn't exist and migrating
+    /// it if it was written with an older schema.
+    ///
+    /// # Arguments
+    /// * `path` => path of the file.
+    ///
+    /// # Return Value
+    /// The store or an error if the file can't be read, is corrupted or was
+    /// written by a newer version.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<FileRoomStore> {
+        let path: PathBuf = path.as_ref().to_path_buf();
+
+        let state: State = if path.exists() {
+            let (version, state, complete) = load(&path)?;
+            if version < SCHEMA_VERSION {
+                rewrite(&path, &state)?;
+            } else if let Some(length) = complete {
+                truncate(&path, length)?;
+            }
+            state
+        } else {
+            let state = State::default();
+            rewrite(&path, &state)?;
+            state
+        };
+
+        let file = OpenOptions::new().append(true)
+            .open(&path)
+            .map_err(|err| io_error(&path, err))?;
+
+        Ok(FileRoomStore {
+            path: path,
+            inner: Mutex::new(Inner {
+                state: state,
+                file: file,
+            }),
+        })
+    }
+
+    /// Path of the file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Write `entry` to the file and apply it
+    ///
+    /// When the write fails the file is cut back to its previous length, so
+    /// no partial line is left for the next entry to be appended to.
+    fn commit(&self, inner: &mut Inner, entry: Entry) -> Result<()> {
+        let line: String = utils::from_obj_to_json(&entry)?;
+        let length: u64 = inner.file.metadata().map_err(|err| io_error(&self.path, err))?.len();
+
+        let written = writeln!(inner.file, "{}", line).and_then(|_| inner.file.sync_data());
+        if let Err(err) = written {
+            if let Err(err) = inner.file.set_len(length).and_then(|_| inner.file.sync_data()) {
+                println!("Unable to cut {} back after a failed write: {}",
+                         self.path.display(),
+                         err);
+            }
+            return Err(io_error(&self.path, err));
+        }
+
+        inner.state.apply(entry);
+        Ok(())
+    }
+
+    /// Add `reservation` to the store, with the lock held
+    fn insert_reservation(&self,
+                          inner: &mut Inner,
+                          reservation: &NewReservation)
+                          -> Result<Reservation> {
+        inner.state.user(&reservation.user_id)?;
+        inner.state.room(&reservation.room_id)?;
+
+        let reservation = Reservation {
+            id: (inner.state.last_reservation_id + 1).to_string(),
+            user_id: reservation.user_id.clone(),
+            room_id: reservation.room_id.clone(),
+            start: reservation.start,
+            end: reservation.end,
+            created_at: reservation.created_at,
+            cancelled_at: None,
+        };
+        self.commit(inner, Entry::Reservation(reservation.clone()))?;
+
+        Ok(reservation)
+    }
+}
+
+impl RoomStore for FileRoomStore {
+    fn create_user(&self, username: &str) -> Result<User> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.state.users.values().any(|user| user.username == username) {
+            return Err(Error::Conflict(format!("The username {} is already taken", username)));
+        }
+
+        let user = User {
+            id: (inner.state.last_user_id + 1).to_string(),
+            username: username.to_owned(),
+        };
+        self.commit(&mut inner, Entry::User(user.clone()))?;
+
+        Ok(user)
+    }
+
+    fn find_user(&self, username: &str) -> Result<User> {
+        let inner = self.inner.lock().unwrap();
+
+        match inner.state.users.values().find(|user| user.username == username) {
+            Some(user) => Ok(user.clone()),
+            None => Err(Error::NotFound(format!("The user {} was not found", username))),
+        }
+    }
+
+    fn create_room(&self, room: &NewRoom) -> Result<Room> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.state.rooms.values().any(|other| other.fenix_id == room.fenix_id) {
+            return Err(Error::Conflict(format!("The room {} already exists", room.fenix_id)));
+        }
+
+        let room = Room {
+            id: (inner.state.last_room_id + 1).to_string(),
+            fenix_id: room.fenix_id.clone(),
+            location: room.location.clone(),
+            capacity: Some(room.capacity),
+        };
+        self.commit(&mut inner, Entry::Room(room.clone()))?;
+
+        Ok(room)
+    }
+
+    fn list_rooms(&self) -> Result<Vec<Room>> {
+        let inner = self.inner.lock().unwrap();
+
+        Ok(inner.state.rooms.values().cloned().collect())
+    }
+
+    fn room(&self, room_id: &str) -> Result<Room> {
+        let inner = self.inner.lock().unwrap();
+
+        inner.state.room(room_id).map(|room| room.clone())
+    }
+
+    fn check_in(&self, check_in: &CheckIn) -> Result<CheckIn> {
+        self.check_in_with_limit(check_in, None)
+    }
+
+    fn check_in_with_limit(&self, check_in: &CheckIn, limit: Option<u64>) -> Result<CheckIn> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.state.user(&check_in.user_id)?;
+        inner.state.room(&check_in.room_id)?;
+
+        if inner.state.is_checked_in(&check_in.user_id, &check_in.room_id) {
+            return Err(Error::Conflict(format!("The user {} is already checked in the room {}",
+                                               check_in.user_id,
+                                               check_in.room_id)));
+        }
+        if let Some(limit) = limit {
+            let occupancy = inner.state
+                .checkins
+                .iter()
+                .filter(|other| other.room_id == check_in.room_id)
+                .count() as u64;
+            if occupancy >= limit {
+                return Err(Error::RoomFull {
+                    room_id: check_in.room_id.clone(),
+                    occupancy: occupancy,
+                    limit: limit,
+                });
+            }
+        }
+        self.commit(&mut inner, Entry::CheckIn(check_in.clone()))?;
+
+        Ok(check_in.clone())
+    }
+
+    fn check_out(&self, check_out: &CheckOut) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if !inner.state.is_checked_in(&check_out.user_id, &check_out.room_id) {
+            return Err(Error::NotFound(format!("The user {} is not checked in the room {}",
+                                               check_out.user_id,
+                                               check_out.room_id)));
+        }
+
+        self.commit(&mut inner, Entry::CheckOut(check_out.clone()))
+    }
+
+    fn active_check_ins(&self) -> Result<Vec<CheckIn>> {
+        let inner = self.inner.lock().unwrap();
+
+        Ok(inner.state.checkins.clone())
+    }
+
+    fn occupants(&self, room_id: &str) -> Result<Vec<User>> {
+        let inner = self.inner.lock().unwrap();
+        inner.state.room(room_id)?;
+
+        Ok(inner.state
+            .checkins
+            .iter()
+            .filter(|check_in| check_in.room_id == room_id)
+            .filter_map(|check_in| inner.state.users.get(&check_in.user_id).cloned())
+            .collect())
+    }
+
+    fn visits(&self, room_id: Option<&str>, from: u64, to: u64) -> Result<Vec<Visit>> {
+        let inner = self.inner.lock().unwrap();
+        if let Some(room_id) = room_id {
+            inner.state.room(room_id)?;
+        }
+
+        let mut visits: Vec<Visit> = inner.state
+            .history
+            .iter()
+            .cloned()
+            .chain(inner.state.checkins.iter().cloned().map(Visit::open))
+            .filter(|visit| room_id.map_or(true, |room_id| visit.room_id == room_id))
+            .filter(|visit| visit.overlaps(from, to))
+            .collect();
+        visits.sort_by_key(|visit| visit.checked_in_at);
+
+        Ok(visits)
+    }
+
+    fn create_reservation(&self, reservation: &NewReservation) -> Result<Reservation> {
+        let mut inner = self.inner.lock().unwrap();
+
+        self.insert_reservation(&mut inner, reservation)
+    }
+
+    fn create_reservation_checked(&self,
+                                  reservation: &NewReservation,
+                                  limit: Option<u64>)
+                                  -> Result<Reservation> {
+        let mut inner = self.inner.lock().unwrap();
+
+        let overlapping: Vec<Reservation> = inner.state
+            .reservations
+            .values()
+            .filter(|other| other.is_active(reservation.start, reservation.end))
+            .cloned()
+            .collect();
+        reservations::check_booking(reservation, &overlapping, limit)?;
+
+        self.insert_reservation(&mut inner, reservation)
+    }
+
+    fn reservation(&self, id: &str) -> Result<Reservation> {
+        let inner = self.inner.lock().unwrap();
+
+        inner.state.reservation(id).map(|reservation| reservation.clone())
+    }
+
+    fn cancel_reservation(&self, id: &str, cancelled_at: u64) -> Result<Reservation> {
+        let mut inner = self.inner.lock().unwrap();
+
+        let mut reservation: Reservation = inner.state.reservation(id)?.clone();
+        if reservation.cancelled_at.is_some() {
+            return Err(Error::Conflict(format!("The reservation {} is already cancelled", id)));
+        }
+        reservation.cancelled_at = Some(cancelled_at);
+        self.commit(&mut inner, Entry::Reservation(reservation.clone()))?;
+
+        Ok(reservation)
+    }
+
+    fn reservations(&self, query: &ReservationQuery) -> Result<Vec<Reservation>> {
+        let inner = self.inner.lock().unwrap();
+
+        let mut reservations: Vec<Reservation> = inner.state
+            .reservations
+            .values()
+            .filter(|reservation| query.matches(reservation))
+            .cloned()
+            .collect();
+        reservations.sort_by_key(|reservation| reservation.start);
+
+        Ok(reservations)
+    }
+}
+
+/// Replay the file at `path`
+///
+/// A truncated last line, left by a crash while writing, is ignored and its
+/// start reported so it can be cut.
+///
+/// # Return Value
+/// The schema version of the file, the state it holds and, when the last
+/// line was ignored, the length of the lines before it.
+fn load(path: &Path) -> Result<(u32, State, Option<u64>)> {
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(|err| io_error(path, err))?;
+    let lines: Vec<&str> = contents.lines().collect();
+
+    let header: Header = match lines.first() {
+        Some(line) => utils::from_json_to_obj(line)?,
+        None => return Err(Error::Decode(format!("{} has no header", path.display()))),
+    };
+    if header.schema_version == 0 || header.schema_version > SCHEMA_VERSION {
+        return Err(Error::Config(format!("{} has an unsupported schema version ({})",
+                                         path.display(),
+                                         header.schema_version)));
+    }
+
+    let mut state = State::default();
+    // Length of the lines replayed, with their line feed
+    let mut complete: usize = lines[0].len() + 1;
+    let last = lines.len() - 1;
+    for (number, line) in lines.iter().enumerate().skip(1) {
+        // A last line without its line feed is a write that never finished
+        if number == last && !contents.ends_with('\n') {
+            break;
+        }
+
+        if !line.trim().is_empty() {
+            match parse_entry(header.schema_version, line) {
+                Ok(entry) => state.apply(entry),
+                Err(_) if number == last => break,
+                Err(err) => {
+                    return Err(Error::Decode(format!("{}:{}: {}",
+                                                     path.display(),
+                                                     number + 1,
+                                                     err)))
+                }
+            }
+        }
+        complete += line.len() + 1;
+    }
+    let complete: Option<u64> = if complete < contents.len() {
+        Some(complete as u64)
+    } else {
+        None
+    };
+
+    Ok((header.schema_version, state, complete))
+}
+
+/// Parse an entry written with schema `version`, migrating it to the current one
+fn parse_entry(version: u32, line: &str) -> Result<Entry> {
+    let mut value: serde_json::Value = utils::from_json_to_obj(line)?;
+    for migration in &MIGRATIONS[(version - 1) as usize..] {
+        value = migration(value)?;
+    }
+
+    serde_json::from_value(value).map_err(|err| Error::Decode(format!("{}", err)))
+}
+
+/// Migration from version 1: check-ins gained their time and check-outs their
+/// time and reason. The real times are unknown, the time of the migration is
+/// used instead.
+fn timestamp_check_ins(mut value: serde_json::Value) -> Result<serde_json::Value> {
+    let now = serde_json::Value::U64(utils::unix_time());
+
+    if let serde_json::Value::Object(ref mut entry) = value {
+        if let Some(&mut serde_json::Value::Object(ref mut check_in)) = entry.get_mut("CheckIn") {
+            check_in.insert("checked_in_at".to_owned(), now.clone());
+        }
+        if let Some(&mut serde_json::Value::Object(ref mut check_out)) = entry.get_mut("CheckOut") {
+            check_out.insert("checked_out_at".to_owned(), now.clone());
+            check_out.insert("reason".to_owned(), serde_json::Value::String("user".to_owned()));
+        }
+    }
+
+    Ok(value)
+}
+
+/// Migration from version 2: the reservations were added, the existing
+/// entries are unchanged.
+fn add_reservations(value: serde_json::Value) -> Result<serde_json::Value> {
+    Ok(value)
+}
+
+/// Migration from version 3: the capacity of the rooms became a number, or
+/// `null` when it wasn't one.
+fn type_capacities(mut value: serde_json::Value) -> Result<serde_json::Value> {
+    if let serde_json::Value::Object(ref mut entry) = value {
+        if let Some(&mut serde_json::Value::Object(ref mut room)) = entry.get_mut("Room") {
+            let capacity = match room.get("capacity") {
+                Some(&serde_json::Value::String(ref capacity)) => {
+                    capacity.trim()
+                        .parse()
+                        .map(serde_json::Value::U64)
+                        .unwrap_or(serde_json::Value::Null)
+                }
+                Some(capacity) => capacity.clone(),
+                None => serde_json::Value::Null,
+            };
+            room.insert("capacity".to_owned(), capacity);
+        }
+    }
+
+    Ok(value)
+}
+
+/// Cut the file at `path` to its first `length` bytes
+fn truncate(path: &Path, length: u64) -> Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_len(length).and_then(|_| file.sync_all()))
+        .map_err(|err| io_error(path, err))
+}
+
+/// Replace the file at `path` with a compacted copy of `state` using the
+/// current schema
+fn rewrite(path: &Path, state: &State) -> Result<()> {
+    let tmp: PathBuf = path.with_extension("tmp");
+    {
+        let mut file = File::create(&tmp).map_err(|err| io_error(&tmp, err))?;
+        let header = Header { schema_version: SCHEMA_VERSION };
+
+        let mut contents: String = utils::from_obj_to_json(&header)?;
+        contents.push('\n');
+        for entry in state.entries() {
+            contents.push_str(&utils::from_obj_to_json(&entry)?);
+            contents.push('\n');
+        }
+
+        file.write_all(contents.as_bytes())
+            .and_then(|_| file.sync_all())
+            .map_err(|err| io_error(&tmp, err))?;
+    }
+
+    fs::rename(&tmp, path).map_err(|err| io_error(path, err))
+}
+
+/// Report an IO error on `path`
+fn io_error(path: &Path, err: ::std::io::Error) -> Error {
+    Error::Internal(format!("{}: {}", path.display(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{Read, Write};
+    use std::path::PathBuf;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use error::Error;
+    use store::{RoomStore, NewRoom, CheckIn, NewReservation};
+    use super::FileRoomStore;
+
+    /// Path of a file for the test `name` in the temporary directory
+    fn temp_path(name: &str) -> PathBuf {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+        env::temp_dir().join(format!("fenix_rooms_{}_{}.db", name, nanos))
+    }
+
+    fn contents(path: &PathBuf) -> String {
+        let mut contents = String::new();
+        File::open(path).unwrap().read_to_string(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn migrates_version_1_to_the_current_schema() {
+        let path = temp_path("migration");
+        {
+            let mut file = File::create(&path).unwrap();
+            write!(file,
+                   "{{\"schema_version\":1}}\n\
+                    {{\"User\":{{\"id\":\"1\",\"username\":\"alice\"}}}}\n\
+                    {{\"Room\":{{\"id\":\"1\",\"fenix_id\":\"2448131361155\",\
+                    \"location\":\"Sala 1\",\"capacity\":\"30\"}}}}\n\
+                    {{\"Room\":{{\"id\":\"2\",\"fenix_id\":\"2448131361156\",\
+                    \"location\":\"Sala 2\",\"capacity\":\"n/a\"}}}}\n\
+                    {{\"CheckIn\":{{\"user_id\":\"1\",\"room_id\":\"1\"}}}}\n\
+                    {{\"CheckOut\":{{\"user_id\":\"1\",\"room_id\":\"1\"}}}}\n\
+                    {{\"CheckIn\":{{\"user_id\":\"1\",\"room_id\":\"2\"}}}}\n")
+                .unwrap();
+        }
+
+        for _ in 0..2 {
+            let store = FileRoomStore::open(&path).unwrap();
+
+            assert_eq!(store.find_user("alice").unwrap().id, "1");
+            assert_eq!(store.room("1").unwrap().capacity, Some(30));
+            assert_eq!(store.room("2").unwrap().capacity, None);
+            assert_eq!(store.active_check_ins().unwrap().len(), 1);
+            assert_eq!(store.active_check_ins().unwrap()[0].room_id, "2");
+
+            let visits = store.visits(None, 0, u64::max_value()).unwrap();
+            assert_eq!(visits.len(), 2);
+            assert!(visits.iter().any(|visit| visit.checked_out_at.is_some()));
+            assert!(contents(&path).starts_with("{\"schema_version\":4}\n"));
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn refuses_check_ins_above_the_limit() {
+        let path = temp_path("limit");
+        let store = FileRoomStore::open(&path).unwrap();
+        let room = NewRoom {
+            fenix_id: "2448131361155".to_owned(),
+            location: "Sala 1".to_owned(),
+            capacity: 1,
+        };
+        let room_id: String = store.create_room(&room).unwrap().id;
+        let check_in = |user_id: &str| {
+            CheckIn {
+                user_id: user_id.to_owned(),
+                room_id: room_id.clone(),
+                checked_in_at: 0,
+                expected_duration: None,
+            }
+        };
+
+        let alice: String = store.create_user("alice").unwrap().id;
+        let bob: String = store.create_user("bob").unwrap().id;
+        assert!(store.check_in_with_limit(&check_in(&alice), Some(1)).is_ok());
+        match store.check_in_with_limit(&check_in(&bob), Some(1)) {
+            Err(Error::RoomFull { occupancy: 1, limit: 1, .. }) => {}
+            result => panic!("expected a full room, got {:?}", result),
+        }
+        assert!(store.check_in_with_limit(&check_in(&bob), None).is_ok());
+        assert_eq!(store.occupants(&room_id).unwrap().len(), 2);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn refuses_bookings_above_the_limit() {
+        let path = temp_path("bookings");
+        let store = FileRoomStore::open(&path).unwrap();
+        let room = NewRoom {
+            fenix_id: "2448131361155".to_owned(),
+            location: "Sala 1".to_owned(),
+            capacity: 1,
+        };
+        let room_id: String = store.create_room(&room).unwrap().id;
+        let booking = |user_id: &str, start: u64, end: u64| {
+            NewReservation {
+                user_id: user_id.to_owned(),
+                room_id: room_id.clone(),
+                start: start,
+                end: end,
+                created_at: 0,
+            }
+        };
+
+        let alice: String = store.create_user("alice").unwrap().id;
+        let bob: String = store.create_user("bob").unwrap().id;
+        assert!(store.create_reservation_checked(&booking(&alice, 10, 20), Some(1)).is_ok());
+        match store.create_reservation_checked(&booking(&bob, 15, 25), Some(1)) {
+            Err(Error::RoomFull { occupancy: 1, limit: 1, .. }) => {}
+            result => panic!("expected a full room, got {:?}", result),
+        }
+        match store.create_reservation_checked(&booking(&alice, 15, 25), None) {
+            Err(Error::Conflict(_)) => {}
+            result => panic!("expected a conflict, got {:?}", result),
+        }
+        assert!(store.create_reservation_checked(&booking(&bob, 20, 30), Some(1)).is_ok());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cuts_a_truncated_last_line_before_appending() {
+        let path = temp_path("truncated");
+        {
+            let store = FileRoomStore::open(&path).unwrap();
+            store.create_user("alice").unwrap();
+        }
+        {
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            write!(file, "{{\"User\":{{\"id\":\"2\",\"user").unwrap();
+        }
+
+        {
+            let store = FileRoomStore::open(&path).unwrap();
+            assert_eq!(store.find_user("alice").unwrap().id, "1");
+            assert_eq!(store.create_user("bob").unwrap().id, "2");
+        }
+
+        let store = FileRoomStore::open(&path).unwrap();
+        assert_eq!(store.find_user("alice").unwrap().id, "1");
+        assert_eq!(store.find_user("bob").unwrap().id, "2");
+        assert!(contents(&path).ends_with("\n"));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn drops_a_complete_entry_missing_its_line_feed() {
+        let path = temp_path("line_feed");
+        {
+            let store = FileRoomStore::open(&path).unwrap();
+            store.create_user("alice").unwrap();
+        }
+        {
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            write!(file, "{{\"User\":{{\"id\":\"2\",\"username\":\"bob\"}}}}").unwrap();
+        }
+
+        {
+            let store = FileRoomStore::open(&path).unwrap();
+            assert!(store.find_user("bob").is_err());
+            store.create_user("carol").unwrap();
+        }
+
+        let store = FileRoomStore::open(&path).unwrap();
+        assert_eq!(store.find_user("carol").unwrap().id, "2");
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,359 @@
+//! `RoomStore` backed by the REST database
+//!
+//! The original database exposes these collections:
+//!
+//! * `POST /users` with `{"username"}`;
+//! * `GET /rooms` and `POST /rooms` with `{"fenix_id", "location", "capacity"}`,
+//!   the capacity being a number;
+//! * `POST /checkins` with `{"user_id", "room_id", "checked_in_at",
+//!   "expected_duration"}`, `DELETE /checkins` with `{"user_id", "room_id"}`
+//!   and `GET /checkins/<room_id>` with the users in the room.
+//!
+//! A database migrated to the extended API, see `Config::db_extended_api`,
+//! also has:
+//!
+//! * `GET /users/<username>` with the user named `username`;
+//! * `GET /checkins` with the open check-ins of every room;
+//! * `POST /checkouts` with `{"user_id", "room_id", "checked_out_at",
+//!   "reason"}`, which closes the check in without deleting it, instead of
+//!   `DELETE /checkins`;
+//! * `GET /visits?from=&to=&room_id=` with the closed and open check-ins
+//!   overlapping the period;
+//! * `POST /reservations` with `{"user_id", "room_id", "start", "end",
+//!   "created_at"}`, `GET /reservations/<id>`,
+//!   `GET /reservations?from=&to=&room_id=&user_id=` with the active
+//!   reservations overlapping the period and
+//!   `POST /reservations/<id>/cancellation` with `{"cancelled_at"}`.
+//!
+//! Without it, the operations needing them fail with an `Error::Unsupported`.
+extern crate hyper;
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use serde::{Serialize, Deserialize};
+use self::hyper::Url;
+use self::hyper::status::StatusCode;
+use self::hyper::client::Response as HyperResponse;
+
+use config::Config;
+use error::{Error, Result};
+use reservations;
+use utils;
+use super::{RoomStore, User, Room, NewRoom, CheckIn, CheckOut, Visit, Reservation,
+            NewReservation, ReservationQuery};
+
+/// Name of the database in error messages
+const DB_SERVICE: &'static str = "The database";
+
+/// Client of the REST database
+///
+/// # Fields
+/// * `base_url` => base URL of the database;
+/// * `extended` => the database has the extended API;
+/// * `room_locks` => lock of each room, held while checking a user in it so
+///   the occupancy can't change between the count and the check in;
+/// * `booking_lock` => lock held while booking a room, so the reservations
+///   can't change between the checks and the booking. The conflicts of a
+///   user span every room, so there is a single one.
+#[derive(Debug, Clone)]
+pub struct HttpRoomStore {
+    base_url: String,
+    extended: bool,
+    room_locks: Arc<Mutex<HashMap<String, Arc<Mutex<()>>>>>,
+    booking_lock: Arc<Mutex<()>>,
+}
+
+impl HttpRoomStore {
+    /// Create a store for the database at `base_url`
+    pub fn new(base_url: &str) -> HttpRoomStore {
+        HttpRoomStore {
+            base_url: base_url.trim_right_matches('/').to_owned(),
+            extended: false,
+            room_locks: Arc::new(Mutex::new(HashMap::new())),
+            booking_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Use the extended API of the database, or only its original collections
+    pub fn with_extended_api(mut self, extended: bool) -> HttpRoomStore {
+        self.extended = extended;
+        self
+    }
+
+    /// Create a store for the database set in `config`
+    pub fn from_config(config: &Config) -> HttpRoomStore {
+        HttpRoomStore::new(&config.db_base_url).with_extended_api(config.db_extended_api)
+    }
+
+    /// Fail with an `Error::Unsupported` unless the database has the extended
+    /// API, which `feature` needs
+    fn require_extended(&self, feature: &str) -> Result<()> {
+        if self.extended {
+            Ok(())
+        } else {
+            Err(Error::Unsupported(format!("{} needs a database with the extended API \
+                                            (db_extended_api)",
+                                           feature)))
+        }
+    }
+
+    /// Lock of the room `room_id`, created on first use
+    fn room_lock(&self, room_id: &str) -> Arc<Mutex<()>> {
+        let mut locks = self.room_locks.lock().unwrap();
+
+        locks.entry(room_id.to_owned()).or_insert_with(|| Arc::new(Mutex::new(()))).clone()
+    }
+
+    /// URL of the `resource` collection
+    fn url(&self, resource: &str) -> String {
+        format!("{}/{}", self.base_url, resource)
+    }
+
+    /// POST `body` to `resource` and decode the created entity
+    fn post<B, T>(&self, resource: &str, body: &B) -> Result<T>
+        where B: Serialize,
+              T: Deserialize
+    {
+        let body: String = utils::from_obj_to_json(body)?;
+        let mut response: HyperResponse = utils::post_request(&self.url(resource), &body)?;
+
+        if response.status == StatusCode::Ok || response.status == StatusCode::Created {
+            let buffer: String = utils::read_response_body(&mut response)?;
+            utils::from_json_to_obj(&buffer)
+        } else {
+            Err(db_error(&mut response))
+        }
+    }
+
+    /// GET `resource` and decode it
+    fn get<T>(&self, resource: &str) -> Result<T>
+        where T: Deserialize
+    {
+        self.get_url(&self.url(resource))
+    }
+
+    /// GET `url` and decode it
+    fn get_url<T>(&self, url: &str) -> Result<T>
+        where T: Deserialize
+    {
+        let mut response: HyperResponse = utils::get_request(url)?;
+
+        if response.status == StatusCode::Ok {
+            let buffer: String = utils::read_response_body(&mut response)?;
+            utils::from_json_to_obj(&buffer)
+        } else {
+            Err(db_error(&mut response))
+        }
+    }
+}
+
+impl RoomStore for HttpRoomStore {
+    fn create_user(&self, username: &str) -> Result<User> {
+        let mut body = BTreeMap::new();
+        body.insert("username", username);
+
+        self.post("users", &body)
+    }
+
+    fn find_user(&self, username: &str) -> Result<User> {
+        self.require_extended("Looking users up")?;
+
+        // The username is escaped as a path segment
+        let invalid = || Error::Config(format!("{} is not a valid base URL", self.base_url));
+
+        let mut url = Url::parse(&self.url("users")).map_err(|_| invalid())?;
+        url.path_segments_mut().map_err(|_| invalid())?.push(username);
+
+        match self.get_url(url.as_str()) {
+            Err(Error::NotFound(_)) => {
+                Err(Error::NotFound(format!("The user {} was not found", username)))
+            }
+            result => result,
+        }
+    }
+
+    fn create_room(&self, room: &NewRoom) -> Result<Room> {
+        self.post("rooms", room)
+    }
+
+    fn list_rooms(&self) -> Result<Vec<Room>> {
+        self.get("rooms")
+    }
+
+    fn check_in(&self, check_in: &CheckIn) -> Result<CheckIn> {
+        self.post("checkins", check_in)
+    }
+
+    /// The check-ins of a room are serialized in this process, so the limit
+    /// holds as long as this server is the only client of the database
+    fn check_in_with_limit(&self, check_in: &CheckIn, limit: Option<u64>) -> Result<CheckIn> {
+        let limit: u64 = match limit {
+            Some(limit) => limit,
+            None => return self.check_in(check_in),
+        };
+
+        let lock: Arc<Mutex<()>> = self.room_lock(&check_in.room_id);
+        let _guard = lock.lock().unwrap();
+
+        let occupancy = self.occupants(&check_in.room_id)?.len() as u64;
+        if occupancy >= limit {
+            return Err(Error::RoomFull {
+                room_id: check_in.room_id.clone(),
+                occupancy: occupancy,
+                limit: limit,
+            });
+        }
+
+        self.check_in(check_in)
+    }
+
+    fn check_out(&self, check_out: &CheckOut) -> Result<()> {
+        let mut response: HyperResponse = if self.extended {
+            let body: String = utils::from_obj_to_json(check_out)?;
+            utils::post_request(&self.url("checkouts"), &body)?
+        } else {
+            // The original database deletes the check in, losing its history
+            let mut body = BTreeMap::new();
+            body.insert("user_id", &check_out.user_id);
+            body.insert("room_id", &check_out.room_id);
+            let body: String = utils::from_obj_to_json(&body)?;
+            utils::delete_request(&self.url("checkins"), &body)?
+        };
+
+        // The body of the answer, if any, is not needed
+        if response.status == StatusCode::Ok || response.status == StatusCode::Created ||
+           response.status == StatusCode::NoContent {
+            Ok(())
+        } else {
+            Err(db_error(&mut response))
+        }
+    }
+
+    fn active_check_ins(&self) -> Result<Vec<CheckIn>> {
+        self.require_extended("Listing every check in")?;
+
+        self.get("checkins")
+    }
+
+    fn occupants(&self, room_id: &str) -> Result<Vec<User>> {
+        match self.get(&format!("checkins/{}", room_id)) {
+            Err(Error::NotFound(_)) => {
+                Err(Error::NotFound(format!("The room id: {} was not found", room_id)))
+            }
+            result => result,
+        }
+    }
+
+    fn visits(&self, room_id: Option<&str>, from: u64, to: u64) -> Result<Vec<Visit>> {
+        self.require_extended("The history of the check-ins")?;
+
+        let invalid = || Error::Config(format!("{} is not a valid base URL", self.base_url));
+
+        let mut url = Url::parse(&self.url("visits")).map_err(|_| invalid())?;
+        {
+            let mut query = url.query_pairs_mut();
+            query.append_pair("from", &from.to_string());
+            query.append_pair("to", &to.to_string());
+            if let Some(room_id) = room_id {
+                query.append_pair("room_id", room_id);
+            }
+        }
+
+        let mut visits: Vec<Visit> = self.get_url(url.as_str())?;
+        visits.sort_by_key(|visit| visit.checked_in_at);
+
+        Ok(visits)
+    }
+
+    fn create_reservation(&self, reservation: &NewReservation) -> Result<Reservation> {
+        self.require_extended("Reservations")?;
+
+        self.post("reservations", reservation)
+    }
+
+    /// The bookings are serialized in this process, so the checks hold as long
+    /// as this server is the only client of the database
+    fn create_reservation_checked(&self,
+                                  reservation: &NewReservation,
+                                  limit: Option<u64>)
+                                  -> Result<Reservation> {
+        self.require_extended("Reservations")?;
+
+        let _guard = self.booking_lock.lock().unwrap();
+
+        let query = |room_id: Option<String>, user_id: Option<String>| {
+            ReservationQuery {
+                room_id: room_id,
+                user_id: user_id,
+                from: reservation.start,
+                to: reservation.end,
+            }
+        };
+        let mut overlapping: Vec<Reservation> =
+            self.reservations(&query(None, Some(reservation.user_id.clone())))?;
+        overlapping.extend(self.reservations(&query(Some(reservation.room_id.clone()), None))?);
+        // A reservation of the user in the room is in both answers
+        overlapping.sort_by(|a, b| a.id.cmp(&b.id));
+        overlapping.dedup();
+        reservations::check_booking(reservation, &overlapping, limit)?;
+
+        self.create_reservation(reservation)
+    }
+
+    fn reservation(&self, id: &str) -> Result<Reservation> {
+        self.require_extended("Reservations")?;
+
+        match self.get(&format!("reservations/{}", id)) {
+            Err(Error::NotFound(_)) => {
+                Err(Error::NotFound(format!("The reservation id: {} was not found", id)))
+            }
+            result => result,
+        }
+    }
+
+    fn cancel_reservation(&self, id: &str, cancelled_at: u64) -> Result<Reservation> {
+        self.require_extended("Reservations")?;
+
+        let mut body = BTreeMap::new();
+        body.insert("cancelled_at", cancelled_at);
+
+        self.post(&format!("reservations/{}/cancellation", id), &body)
+    }
+
+    fn reservations(&self, query: &ReservationQuery) -> Result<Vec<Reservation>> {
+        self.require_extended("Reservations")?;
+
+        let invalid = || Error::Config(format!("{} is not a valid base URL", self.base_url));
+
+        let mut url = Url::parse(&self.url("reservations")).map_err(|_| invalid())?;
+        {
+            let mut pairs = url.query_pairs_mut();
+            pairs.append_pair("from", &query.from.to_string());
+            pairs.append_pair("to", &query.to.to_string());
+            if let Some(ref room_id) = query.room_id {
+                pairs.append_pair("room_id", room_id);
+            }
+            if let Some(ref user_id) = query.user_id {
+                pairs.append_pair("user_id", user_id);
+            }
+        }
+
+        // Only keep what was asked for, whatever the database sends
+        let mut reservations: Vec<Reservation> = self.get_url(url.as_str())?;
+        reservations.retain(|reservation| query.matches(reservation));
+        reservations.sort_by_key(|reservation| reservation.start);
+
+        Ok(reservations)
+    }
+}
+
+/// Convert an unexpected response from the database into an `Error`
+///
+/// The client errors of the database (400, 404, 409 and 422) are forwarded
+/// with their message. Everything else means the database is down.
+fn db_error(response: &mut HyperResponse) -> Error {
+    let body: String = utils::read_response_body(response).unwrap_or(String::new());
+
+    Error::from_upstream(DB_SERVICE, response.status.to_u16(), &body)
+}
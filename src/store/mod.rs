@@ -0,0 +1,401 @@
+//! Storage of users, rooms, check-ins and reservations.
+//!
+//! The handlers only depend on the `RoomStore` trait, so the backend can be
+//! swapped or mocked. Two backends are available, selected by
+//! `Config::store_backend`:
+//!
+//! * `http::HttpRoomStore` talks to the REST database at `Config::db_base_url`;
+//! * `file::FileRoomStore` keeps everything in a local file at
+//!   `Config::store_path`.
+extern crate serde_json;
+
+use std::sync::Arc;
+
+use serde::{Deserialize, Deserializer};
+use config::{Config, StoreBackend};
+use error::{Error, Result};
+
+pub use self::http::HttpRoomStore;
+pub use self::file::FileRoomStore;
+
+// /////////////////////////////////////////////////////////////////////////////
+// Basic Structs
+// /////////////////////////////////////////////////////////////////////////////
+/// A registered user
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct User {
+    #[serde(deserialize_with="de_string")]
+    pub id: String,
+    pub username: String,
+}
+
+/// A room available to check in
+///
+/// # Fields
+/// * `id` => id of the room in the store;
+/// * `fenix_id` => id of the space in `FenixEDU`;
+/// * `location` => human readable location of the room;
+/// * `capacity` => number of seats, `None` when unknown.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Room {
+    #[serde(deserialize_with="de_string")]
+    pub id: String,
+    #[serde(deserialize_with="de_string")]
+    pub fenix_id: String,
+    pub location: String,
+    #[serde(default, deserialize_with="de_capacity")]
+    pub capacity: Option<u64>,
+}
+
+impl Room {
+    /// Number of users the room accepts, `capacity` plus `overflow` percent of
+    /// it, rounded down
+    ///
+    /// # Return Value
+    /// The limit or `None` when the capacity is unknown, in which case the
+    /// room has no limit.
+    pub fn capacity_limit(&self, overflow: u64) -> Option<u64> {
+        self.capacity.map(|capacity| capacity.saturating_mul(100 + overflow) / 100)
+    }
+}
+
+/// A room to be created
+///
+/// # Fields
+/// * `fenix_id` => id of the space in `FenixEDU`;
+/// * `location` => names of the spaces from the campus to the room;
+/// * `capacity` => number of seats.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct NewRoom {
+    pub fenix_id: String,
+    pub location: String,
+    pub capacity: u64,
+}
+
+/// A user checked in a room
+///
+/// # Fields
+/// * `user_id` => id of the user;
+/// * `room_id` => id of the room;
+/// * `checked_in_at` => UNIX time of the check in;
+/// * `expected_duration` => seconds the user expects to stay, if known.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct CheckIn {
+    #[serde(deserialize_with="de_string")]
+    pub user_id: String,
+    #[serde(deserialize_with="de_string")]
+    pub room_id: String,
+    #[serde(default)]
+    pub checked_in_at: u64,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub expected_duration: Option<u64>,
+}
+
+impl CheckIn {
+    /// True when this is the check in of `user_id` in `room_id`
+    pub fn is_of(&self, user_id: &str, room_id: &str) -> bool {
+        self.user_id == user_id && self.room_id == room_id
+    }
+}
+
+/// Why a user left a room
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum CheckOutReason {
+    /// The user, or an admin, checked out
+    #[serde(rename="user")]
+    User,
+    /// The check in was closed by the reaper
+    #[serde(rename="expired")]
+    Expired,
+    /// The user checked in another room
+    #[serde(rename="moved")]
+    Moved,
+}
+
+/// A user leaving a room
+///
+/// # Fields
+/// * `user_id` => id of the user;
+/// * `room_id` => id of the room;
+/// * `checked_out_at` => UNIX time of the check out;
+/// * `reason` => why the user left.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct CheckOut {
+    #[serde(deserialize_with="de_string")]
+    pub user_id: String,
+    #[serde(deserialize_with="de_string")]
+    pub room_id: String,
+    pub checked_out_at: u64,
+    pub reason: CheckOutReason,
+}
+
+/// A stay of a user in a room, closed or not
+///
+/// # Fields
+/// * `user_id` => id of the user;
+/// * `room_id` => id of the room;
+/// * `checked_in_at` => UNIX time of the check in;
+/// * `expected_duration` => seconds the user expected to stay, if known;
+/// * `checked_out_at` => UNIX time of the check out, `None` while the user is
+///   in the room;
+/// * `reason` => why the user left, `None` while the user is in the room.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Visit {
+    #[serde(deserialize_with="de_string")]
+    pub user_id: String,
+    #[serde(deserialize_with="de_string")]
+    pub room_id: String,
+    pub checked_in_at: u64,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub expected_duration: Option<u64>,
+    pub checked_out_at: Option<u64>,
+    pub reason: Option<CheckOutReason>,
+}
+
+impl Visit {
+    /// Visit of a user still in the room
+    pub fn open(check_in: CheckIn) -> Visit {
+        Visit {
+            user_id: check_in.user_id,
+            room_id: check_in.room_id,
+            checked_in_at: check_in.checked_in_at,
+            expected_duration: check_in.expected_duration,
+            checked_out_at: None,
+            reason: None,
+        }
+    }
+
+    /// Visit ended by `check_out`
+    pub fn closed(check_in: CheckIn, check_out: &CheckOut) -> Visit {
+        Visit {
+            checked_out_at: Some(check_out.checked_out_at),
+            reason: Some(check_out.reason),
+            ..Visit::open(check_in)
+        }
+    }
+
+    /// True when the user was in the room at some point of `[from, to[`
+    pub fn overlaps(&self, from: u64, to: u64) -> bool {
+        self.checked_in_at < to && self.checked_out_at.map_or(true, |out| out > from)
+    }
+}
+
+/// A room booked by a user for `[start, end[`
+///
+/// # Fields
+/// * `id` => id of the reservation in the store;
+/// * `user_id` => id of the user;
+/// * `room_id` => id of the room;
+/// * `start` => UNIX time of the start;
+/// * `end` => UNIX time of the end;
+/// * `created_at` => UNIX time of the booking;
+/// * `cancelled_at` => UNIX time of the cancellation, if cancelled.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Reservation {
+    #[serde(deserialize_with="de_string")]
+    pub id: String,
+    #[serde(deserialize_with="de_string")]
+    pub user_id: String,
+    #[serde(deserialize_with="de_string")]
+    pub room_id: String,
+    pub start: u64,
+    pub end: u64,
+    pub created_at: u64,
+    pub cancelled_at: Option<u64>,
+}
+
+impl Reservation {
+    /// True when the reservation isn't cancelled and overlaps `[from, to[`
+    pub fn is_active(&self, from: u64, to: u64) -> bool {
+        self.cancelled_at.is_none() && self.start < to && self.end > from
+    }
+}
+
+/// A reservation to be created
+#[derive(Serialize, Debug, Clone)]
+pub struct NewReservation {
+    pub user_id: String,
+    pub room_id: String,
+    pub start: u64,
+    pub end: u64,
+    pub created_at: u64,
+}
+
+/// Selection of reservations
+///
+/// # Fields
+/// * `room_id` => room of the reservations, `None` for every room;
+/// * `user_id` => user of the reservations, `None` for every user;
+/// * `from` => UNIX time of the start of the period;
+/// * `to` => UNIX time of the end of the period.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReservationQuery {
+    pub room_id: Option<String>,
+    pub user_id: Option<String>,
+    pub from: u64,
+    pub to: u64,
+}
+
+impl ReservationQuery {
+    /// True when `reservation` is active and selected
+    pub fn matches(&self, reservation: &Reservation) -> bool {
+        reservation.is_active(self.from, self.to) &&
+        self.room_id.as_ref().map_or(true, |room_id| reservation.room_id == *room_id) &&
+        self.user_id.as_ref().map_or(true, |user_id| reservation.user_id == *user_id)
+    }
+}
+
+// /////////////////////////////////////////////////////////////////////////////
+// Store
+// /////////////////////////////////////////////////////////////////////////////
+/// Operations needed by the handlers on users, rooms and check-ins
+///
+/// Every operation reports the errors of the backend with the crate `Error`:
+/// `NotFound` for missing entities, `Conflict` for duplicates and
+/// `Transport`/`UpstreamStatus` when the backend is unavailable.
+pub trait RoomStore: Send + Sync {
+    /// Register a user called `username`
+    fn create_user(&self, username: &str) -> Result<User>;
+
+    /// Get the user called `username`
+    fn find_user(&self, username: &str) -> Result<User>;
+
+    /// Add a room
+    fn create_room(&self, room: &NewRoom) -> Result<Room>;
+
+    /// List every room
+    fn list_rooms(&self) -> Result<Vec<Room>>;
+
+    /// Get the room `room_id`
+    ///
+    /// The default implementation searches `list_rooms()`.
+    fn room(&self, room_id: &str) -> Result<Room> {
+        match self.list_rooms()?.into_iter().find(|room| room.id == room_id) {
+            Some(room) => Ok(room),
+            None => Err(Error::NotFound(format!("The room id: {} was not found", room_id))),
+        }
+    }
+
+    /// Check a user in a room
+    fn check_in(&self, check_in: &CheckIn) -> Result<CheckIn>;
+
+    /// Check a user in a room unless it already has `limit` users
+    ///
+    /// The occupancy is counted and the check in added as one operation, so
+    /// concurrent check-ins can't fill the room above its limit.
+    ///
+    /// # Return Value
+    /// The check in, an `Error::RoomFull` with the occupancy and the limit or
+    /// the error of the store. Without a `limit` it is the same as
+    /// `check_in()`.
+    fn check_in_with_limit(&self, check_in: &CheckIn, limit: Option<u64>) -> Result<CheckIn>;
+
+    /// Check a user out of a room. The check in is closed and kept in the
+    /// history.
+    fn check_out(&self, check_out: &CheckOut) -> Result<()>;
+
+    /// Every open check in, in all rooms, or an `Error::Unsupported` when the
+    /// backend can't list them
+    fn active_check_ins(&self) -> Result<Vec<CheckIn>>;
+
+    /// Open check-ins of the user `user_id`
+    ///
+    /// The default implementation filters `active_check_ins()`.
+    fn check_ins_of(&self, user_id: &str) -> Result<Vec<CheckIn>> {
+        Ok(self.active_check_ins()?
+            .into_iter()
+            .filter(|check_in| check_in.user_id == user_id)
+            .collect())
+    }
+
+    /// Users currently checked in the room `room_id`
+    fn occupants(&self, room_id: &str) -> Result<Vec<User>>;
+
+    /// Visits, closed or not, overlapping `[from, to[`
+    ///
+    /// # Arguments
+    /// * `room_id` => room of the visits, `None` for every room;
+    /// * `from` => UNIX time of the start of the period;
+    /// * `to` => UNIX time of the end of the period.
+    ///
+    /// # Return Value
+    /// The visits sorted by check in time or the error of the store.
+    fn visits(&self, room_id: Option<&str>, from: u64, to: u64) -> Result<Vec<Visit>>;
+
+    /// Book a room. Conflicts are checked by the caller.
+    fn create_reservation(&self, reservation: &NewReservation) -> Result<Reservation>;
+
+    /// Book a room unless it conflicts with the reservations already made
+    ///
+    /// The conflicts are checked with `reservations::check_booking()` and the
+    /// reservation added as one operation, so concurrent bookings can't
+    /// overbook the room nor give a user two reservations at the same time.
+    ///
+    /// # Arguments
+    /// * `reservation` => reservation to book;
+    /// * `limit` => most reservations the room holds at the same time, `None`
+    ///   without a limit.
+    ///
+    /// # Return Value
+    /// The reservation, the error of `check_booking()` or the error of the
+    /// store.
+    fn create_reservation_checked(&self,
+                                  reservation: &NewReservation,
+                                  limit: Option<u64>)
+                                  -> Result<Reservation>;
+
+    /// Get the reservation `id`
+    fn reservation(&self, id: &str) -> Result<Reservation>;
+
+    /// Cancel the reservation `id` at the UNIX time `cancelled_at`
+    ///
+    /// # Return Value
+    /// The cancelled reservation, an `Error::NotFound` if it doesn't exist or
+    /// an `Error::Conflict` if it was already cancelled.
+    fn cancel_reservation(&self, id: &str, cancelled_at: u64) -> Result<Reservation>;
+
+    /// Active reservations selected by `query`, sorted by start
+    fn reservations(&self, query: &ReservationQuery) -> Result<Vec<Reservation>>;
+}
+
+/// Open the store selected in `config`
+///
+/// # Return Value
+/// The store or the error raised while opening it.
+pub fn open(config: &Config) -> Result<Arc<RoomStore>> {
+    match config.store_backend {
+        StoreBackend::Http => Ok(Arc::new(HttpRoomStore::from_config(config))),
+        StoreBackend::File => Ok(Arc::new(FileRoomStore::open(&config.store_path)?)),
+    }
+}
+
+/// Deserialize an id that may be sent either as a string or as a number
+fn de_string<D>(deserializer: &mut D) -> ::std::result::Result<String, D::Error>
+    where D: Deserializer
+{
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(string) => Ok(string),
+        serde_json::Value::U64(number) => Ok(number.to_string()),
+        serde_json::Value::I64(number) => Ok(number.to_string()),
+        serde_json::Value::F64(number) => Ok(number.to_string()),
+        _ => Err(::serde::de::Error::custom("expected a string or a number")),
+    }
+}
+
+/// Deserialize a capacity sent as a number or, by older databases, as a
+/// string. Anything that isn't a number is an unknown capacity.
+fn de_capacity<D>(deserializer: &mut D) -> ::std::result::Result<Option<u64>, D::Error>
+    where D: Deserializer
+{
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::U64(number) => Ok(Some(number)),
+        serde_json::Value::String(string) => Ok(string.trim().parse().ok()),
+        _ => Ok(None),
+    }
+}
+
+// /////////////////////////////////////////////////////////////////////////////
+// Modules
+// /////////////////////////////////////////////////////////////////////////////
+pub mod http;
+pub mod file;
@@ -2,16 +2,34 @@
 extern crate hyper;
 extern crate serde_json;
 extern crate serde;
+extern crate unicode_normalization;
 
+use std::collections::HashSet;
 use std::io::Read;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use self::serde::{Serialize, Deserialize};
 use self::hyper::client::{Client, Response};
 use self::hyper::header::{Headers, ContentType};
 use self::hyper::mime::{Mime, TopLevel, SubLevel, Attr, Value};
+use self::unicode_normalization::UnicodeNormalization;
+use self::unicode_normalization::char::canonical_combining_class;
+use error::{Error, Result};
 
 // /////////////////////////////////////////////////////////////////////////////
 // REST Client Utilities
 // /////////////////////////////////////////////////////////////////////////////
+/// Seconds a request may wait for the other end to read or answer
+const TIMEOUT: u64 = 10;
+
+/// Hyper client giving up on a silent server after `TIMEOUT` seconds
+fn client() -> Client {
+    let mut client = Client::new();
+    client.set_read_timeout(Some(Duration::from_secs(TIMEOUT)));
+    client.set_write_timeout(Some(Duration::from_secs(TIMEOUT)));
+
+    client
+}
+
 /// Perform a GET request to the specified url
 ///
 /// Build a GET request and query. Quietly bail if the request fails.
@@ -20,15 +38,15 @@ use self::hyper::mime::{Mime, TopLevel, SubLevel, Attr, Value};
 /// * `url` => Specified URL to perform the GET request to.
 ///
 /// # Return Value
-/// The response or the error message.
-pub fn get_request(url: &str) -> Result<Response, String> {
+/// The response or an `Error::Transport`.
+pub fn get_request(url: &str) -> Result<Response> {
     // Create Hyper client to perform REST calls
-    let client = Client::new();
+    let client = client();
 
     // Create and send GET request
     match client.get(url).send() {
         Ok(res) => Ok(res),
-        Err(err) => Err(format!("The GET request failed with: {}", err)),
+        Err(err) => Err(Error::Transport(format!("The GET request failed with: {}", err))),
     }
 }
 
@@ -41,13 +59,27 @@ pub fn get_request(url: &str) -> Result<Response, String> {
 /// * `body` => Content to send
 ///
 /// # Return Value
-/// The response or the error message.
-pub fn post_request(url: &str, body: &str) -> Result<Response, String> {
+/// The response or an `Error::Transport`.
+pub fn post_request(url: &str, body: &str) -> Result<Response> {
+    post_request_with_headers(url, body, Headers::new())
+}
+
+/// Perform a POST request to the specified url with extra headers
+///
+/// Same as `post_request()`, sending `headers` along with the JSON header.
+///
+/// # Arguments
+/// * `url` => Specified URL to perform the POST request to.
+/// * `body` => Content to send
+/// * `headers` => Headers to add
+///
+/// # Return Value
+/// The response or an `Error::Transport`.
+pub fn post_request_with_headers(url: &str, body: &str, mut headers: Headers) -> Result<Response> {
     // Create Hyper client to perform REST calls
-    let client = Client::new();
+    let client = client();
 
     // Add a JSON header
-    let mut headers = Headers::new();
     headers.set(ContentType(Mime(TopLevel::Application,
                                  SubLevel::Json,
                                  vec![(Attr::Charset, Value::Utf8)])));
@@ -55,7 +87,7 @@ pub fn post_request(url: &str, body: &str) -> Result<Response, String> {
     // Create and send POST request
     match client.post(url).headers(headers).body(body).send() {
         Ok(res) => Ok(res),
-        Err(err) => Err(format!("The POST request failed with: {}", err)),
+        Err(err) => Err(Error::Transport(format!("The POST request failed with: {}", err))),
     }
 
 }
@@ -69,10 +101,10 @@ pub fn post_request(url: &str, body: &str) -> Result<Response, String> {
 /// * `body` => Content to send
 ///
 /// # Return Value
-/// The error message of the problem or the contents of the body.
-pub fn delete_request(url: &str, body: &str) -> Result<Response, String> {
+/// The response or an `Error::Transport`.
+pub fn delete_request(url: &str, body: &str) -> Result<Response> {
     // Create Hyper client to perform REST calls
-    let client = Client::new();
+    let client = client();
 
     // Add a JSON header
     let mut headers = Headers::new();
@@ -83,7 +115,7 @@ pub fn delete_request(url: &str, body: &str) -> Result<Response, String> {
     // Create and send POST request
     match client.delete(url).headers(headers).body(body).send() {
         Ok(res) => Ok(res),
-        Err(err) => Err(format!("The DELETE request failed with: {}", err)),
+        Err(err) => Err(Error::Transport(format!("The DELETE request failed with: {}", err))),
     }
 }
 
@@ -96,14 +128,14 @@ pub fn delete_request(url: &str, body: &str) -> Result<Response, String> {
 ///
 /// # Return Value
 /// The contents of the body or a error message.
-pub fn read_response_body(response: &mut Response) -> Result<String, String> {
+pub fn read_response_body(response: &mut Response) -> Result<String> {
     // Read content from response and write it to a buffer
     let mut buf: String = String::new();
     let read_size = match response.read_to_string(&mut buf) {
         Ok(size) => size,
         Err(err) => {
             let error = format!("Problem while reading message body: {}", err);
-            return Err(error);
+            return Err(Error::Decode(error));
         }
     };
 
@@ -111,7 +143,7 @@ pub fn read_response_body(response: &mut Response) -> Result<String, String> {
         Ok(buf)
     } else {
         let error = format!("{} did not return any information", response.url);
-        Err(error)
+        Err(Error::Decode(error))
     }
 }
 
@@ -125,12 +157,12 @@ pub fn read_response_body(response: &mut Response) -> Result<String, String> {
 ///
 /// # Return Value
 /// The object or an error message.
-pub fn from_json_to_obj<T>(json: &str) -> Result<T, String>
+pub fn from_json_to_obj<T>(json: &str) -> Result<T>
     where T: Deserialize
 {
     match serde_json::from_str(json) {
         Ok(obj) => Ok(obj),
-        Err(err) => Err(format!("JSON encoder error: {}", err)),
+        Err(err) => Err(Error::Decode(format!("JSON decoder error: {}", err))),
     }
 }
 
@@ -141,30 +173,91 @@ pub fn from_json_to_obj<T>(json: &str) -> Result<T, String>
 ///
 /// # Return Value
 /// The string or an error message.
-pub fn from_obj_to_json<T>(obj: &T) -> Result<String, String>
+pub fn from_obj_to_json<T>(obj: &T) -> Result<String>
     where T: Serialize
 {
     match serde_json::to_string(&obj) {
         Ok(obj) => Ok(obj),
-        Err(err) => Err(format!("JSON encoder error: {}", err)),
+        Err(err) => Err(Error::Internal(format!("JSON encoder error: {}", err))),
     }
 }
 
 // /////////////////////////////////////////////////////////////////////////////
 // Misc
 // /////////////////////////////////////////////////////////////////////////////
-/// Remove all accents and other non-pleasant characters from a Portuguese
-/// string
+/// Current time in seconds since the Unix epoch
+pub fn unix_time() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+/// Turn the name of a space into its slug, the segment used in `/api/path/`
+///
+/// The slug format is stable:
+///
+/// * letters are lowercased and decomposed (NFD), and the combining marks are
+///   dropped, so `É` becomes `e` and `ñ` becomes `n`;
+/// * the Latin letters without a decomposition are spelled out (`ß` => `ss`,
+///   `æ` => `ae`, `ø` => `o`, ...);
+/// * letters and digits of any script are kept;
+/// * every run of other characters (spaces, punctuation, `/`) becomes a single
+///   `-`, and no `-` starts or ends the slug.
 ///
-/// This might be the dumbest code...
+/// A name without letters or digits has an empty slug. Slugs of sibling
+/// spaces may collide, see `sibling_slugs()`.
 ///
 /// # Arguments
-/// * `string` => String to convert to sane characters.
+/// * `name` => name to convert, e.g. `Pavilhão de Informática II`.
 ///
 /// # Return Value
-/// Sane String
-pub fn sanitize_string(string: &str) -> String {
-    string.to_lowercase()
+/// The slug, e.g. `pavilhao-de-informatica-ii`.
+pub fn slugify(name: &str) -> String {
+    let mut slug = String::with_capacity(name.len());
+    let mut separator = false;
+
+    let letters = name.nfd().filter(|&c| canonical_combining_class(c) == 0);
+
+    for c in letters.flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            if separator && !slug.is_empty() {
+                slug.push('-');
+            }
+            separator = false;
+            match c {
+                'ß' => slug.push_str("ss"),
+                'æ' => slug.push_str("ae"),
+                'œ' => slug.push_str("oe"),
+                'ø' => slug.push('o'),
+                'đ' | 'ð' => slug.push('d'),
+                'ł' => slug.push('l'),
+                'þ' => slug.push_str("th"),
+                'ı' => slug.push('i'),
+                _ => slug.push(c),
+            }
+        } else {
+            separator = true;
+        }
+    }
+
+    slug
+}
+
+/// Turn the name of a space into the segment used in `/api/path/` before
+/// `slugify()`
+///
+/// Kept so that the paths of the first releases, e.g. `sala-0.08` or
+/// `piso_-1`, still resolve. Only the spaces and the Portuguese accents were
+/// replaced, and `/` became `_`.
+///
+/// # Arguments
+/// * `name` => name to convert, e.g. `Sala 0.08`.
+///
+/// # Return Value
+/// The legacy segment, e.g. `sala-0.08`.
+pub fn legacy_slug(name: &str) -> String {
+    name.to_lowercase()
         .replace(" ", "-")
         .replace("/", "_")
         .replace("á", "a")
@@ -180,3 +273,68 @@ pub fn sanitize_string(string: &str) -> String {
         .replace("ú", "u")
         .replace("ç", "c")
 }
+
+/// Slugs of sibling spaces, unique among them
+///
+/// Each name gets its `slugify()` slug. When several siblings share a slug,
+/// the first keeps it and the next ones get `-2`, `-3`, ... appended, in the
+/// order of `names`, skipping the suffixes already taken. Empty slugs are left
+/// empty, they can't be used in a path.
+///
+/// # Arguments
+/// * `names` => names of the spaces, in the order of `FenixEDU`.
+///
+/// # Return Value
+/// The slug of each name, in the same order.
+pub fn sibling_slugs<S: AsRef<str>>(names: &[S]) -> Vec<String> {
+    let slugs: Vec<String> = names.iter().map(|name| slugify(name.as_ref())).collect();
+    let mut taken: HashSet<String> = slugs.iter().cloned().collect();
+    let mut seen: HashSet<String> = HashSet::new();
+
+    slugs.into_iter()
+        .map(|slug| {
+            if slug.is_empty() || seen.insert(slug.clone()) {
+                return slug;
+            }
+
+            let mut suffix = 2;
+            while taken.contains(&format!("{}-{}", slug, suffix)) {
+                suffix += 1;
+            }
+            let unique = format!("{}-{}", slug, suffix);
+            taken.insert(unique.clone());
+            unique
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{legacy_slug, sibling_slugs, slugify};
+
+    #[test]
+    fn folds_names_into_slugs() {
+        assert_eq!(slugify("Pavilhão de Informática II"), "pavilhao-de-informatica-ii");
+        assert_eq!(slugify("  Sala 0.08 / Piso -1 "), "sala-0-08-piso-1");
+        assert_eq!(slugify("Ñandú"), "nandu");
+        assert_eq!(slugify("Straße Æsir Ørsted"), "strasse-aesir-orsted");
+        assert_eq!(slugify("LAB-1--A"), "lab-1-a");
+        assert_eq!(slugify("--- ..."), "");
+    }
+
+    #[test]
+    fn suffixes_colliding_siblings() {
+        assert_eq!(sibling_slugs(&["A", "A", "A-2"]), vec!["a", "a-3", "a-2"]);
+        assert_eq!(sibling_slugs(&["Sala 1", "sala 1", "SALA-1"]),
+                   vec!["sala-1", "sala-1-2", "sala-1-3"]);
+        assert_eq!(sibling_slugs(&["B", "A"]), vec!["b", "a"]);
+        assert_eq!(sibling_slugs(&["", "?"]), vec!["", ""]);
+    }
+
+    #[test]
+    fn keeps_the_legacy_segments() {
+        assert_eq!(legacy_slug("Sala 0.08"), "sala-0.08");
+        assert_eq!(legacy_slug("Piso -1/2"), "piso--1_2");
+        assert_eq!(legacy_slug("Pavilhão de Informática II"), "pavilhao-de-informatica-ii");
+    }
+}
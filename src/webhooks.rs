@@ -0,0 +1,717 @@
+//! Webhook notifications of the room events.
+//!
+//! Admins register URLs receiving the events of one room, of every room under
+//! a `FenixEDU` space or of every room. Each matching event is sent as a JSON
+//! POST signed with the secret of the webhook: the
+//! `X-Fenix-Rooms-Signature` header holds `sha256=<hex HMAC-SHA256 of the
+//! body>`. A delivery answered with anything but a 2xx status is retried up to
+//! `MAX_ATTEMPTS` times, waiting twice as long before each retry. The last
+//! `LOG_SIZE` deliveries are kept in a log.
+//!
+//! The deliveries are made by `WORKERS` threads. At most `QUEUE` deliveries
+//! wait for them; the others are logged as failed right away, as are the
+//! events the dispatcher was too late to receive from the `EventBus`.
+//!
+//! The registrations are saved to a JSON file when the registry is opened
+//! with `Webhooks::open()`, so they survive a restart; the log of the
+//! deliveries isn't saved.
+extern crate crypto;
+extern crate hyper;
+extern crate rand;
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use self::crypto::hmac::Hmac;
+use self::crypto::mac::Mac;
+use self::crypto::sha2::Sha256;
+use self::hyper::header::Headers;
+use self::hyper::Url;
+
+use auth;
+use error::{Error, Result};
+use events::{Event, EventKind};
+use fenix::FenixSpacesClient;
+use store::RoomStore;
+use utils;
+
+/// Attempts made to deliver an event
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Seconds before the first retry
+const BACKOFF: u64 = 2;
+
+/// Deliveries kept in the log
+const LOG_SIZE: usize = 1000;
+
+/// Threads delivering the events
+const WORKERS: usize = 4;
+
+/// Most deliveries waiting for a worker
+const QUEUE: usize = 256;
+
+/// Most rooms whose spaces are kept by the dispatcher
+const SPACES_CACHE: usize = 10000;
+
+/// Kinds sent to a webhook registered without `events`
+pub const DEFAULT_EVENTS: &'static [EventKind] = &[EventKind::CheckIn,
+                                                   EventKind::CheckOut,
+                                                   EventKind::RoomFull,
+                                                   EventKind::RoomCreated];
+
+/// A registered webhook
+///
+/// # Fields
+/// * `id` => id of the webhook;
+/// * `url` => URL receiving the events;
+/// * `room_id` => room followed, `None` for every room;
+/// * `path` => `FenixEDU` path of the space whose rooms are followed;
+/// * `events` => kinds of the events sent;
+/// * `secret` => key signing the deliveries, only shown when registering;
+/// * `created_at` => UNIX time of the registration.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub room_id: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub path: Option<String>,
+    pub events: Vec<EventKind>,
+    #[serde(skip_serializing_if="String::is_empty")]
+    pub secret: String,
+    pub created_at: u64,
+    #[serde(skip_serializing)]
+    space_id: Option<String>,
+}
+
+impl Webhook {
+    /// True when the event `event` is sent to the webhook
+    ///
+    /// # Arguments
+    /// * `event` => the event;
+    /// * `spaces` => ids of the spaces holding the room of the event, from
+    ///   its campus to its own space, only needed by the webhooks of a path.
+    pub fn matches(&self, event: &Event, spaces: &[String]) -> bool {
+        if !self.events.contains(&event.kind) {
+            return false;
+        }
+        if let Some(ref room_id) = self.room_id {
+            if *room_id != event.room_id {
+                return false;
+            }
+        }
+        if let Some(ref space_id) = self.space_id {
+            return spaces.contains(space_id);
+        }
+
+        true
+    }
+
+    /// The webhook without its secret
+    fn redacted(&self) -> Webhook {
+        Webhook { secret: String::new(), ..self.clone() }
+    }
+}
+
+/// A webhook to register
+///
+/// # Fields
+/// * `url` => URL receiving the events, `http` or `https`;
+/// * `room_id` => room followed, if any;
+/// * `path` => `FenixEDU` path of the space whose rooms are followed, if any;
+/// * `space_id` => id of the space at `path`, if any;
+/// * `events` => kinds of the events sent, `DEFAULT_EVENTS` when empty;
+/// * `secret` => key signing the deliveries, generated when `None`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewWebhook {
+    pub url: String,
+    pub room_id: Option<String>,
+    pub path: Option<String>,
+    pub space_id: Option<String>,
+    pub events: Vec<EventKind>,
+    pub secret: Option<String>,
+}
+
+/// Outcome of a delivery
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum DeliveryStatus {
+    #[serde(rename="pending")]
+    Pending,
+    #[serde(rename="delivered")]
+    Delivered,
+    #[serde(rename="failed")]
+    Failed,
+}
+
+/// An event sent to a webhook
+///
+/// # Fields
+/// * `id` => id of the delivery, sent in `X-Fenix-Rooms-Delivery`;
+/// * `webhook_id` => webhook receiving the event;
+/// * `event` => kind of the event;
+/// * `room_id` => room of the event;
+/// * `status` => outcome of the delivery;
+/// * `attempts` => requests made so far;
+/// * `response_code` => status of the last response, if any;
+/// * `error` => why the last attempt failed, if it did;
+/// * `created_at` => UNIX time of the event;
+/// * `updated_at` => UNIX time of the last attempt.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Delivery {
+    pub id: String,
+    pub webhook_id: String,
+    pub event: EventKind,
+    pub room_id: String,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub response_code: Option<u16>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub error: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+/// Body of a delivery
+#[derive(Serialize)]
+struct Payload<'a> {
+    delivery_id: &'a str,
+    webhook_id: &'a str,
+    event: &'a Event,
+}
+
+struct Registry {
+    webhooks: BTreeMap<u64, Webhook>,
+    last_webhook_id: u64,
+    deliveries: VecDeque<Delivery>,
+    last_delivery_id: u64,
+}
+
+/// The registrations as saved in the file of the webhooks
+#[derive(Serialize, Deserialize)]
+struct Saved {
+    last_webhook_id: u64,
+    webhooks: Vec<SavedWebhook>,
+}
+
+/// A webhook as saved, with its secret and the id of its space
+#[derive(Serialize, Deserialize)]
+struct SavedWebhook {
+    id: String,
+    url: String,
+    room_id: Option<String>,
+    path: Option<String>,
+    space_id: Option<String>,
+    events: Vec<EventKind>,
+    secret: String,
+    created_at: u64,
+}
+
+impl SavedWebhook {
+    fn new(webhook: &Webhook) -> SavedWebhook {
+        SavedWebhook {
+            id: webhook.id.clone(),
+            url: webhook.url.clone(),
+            room_id: webhook.room_id.clone(),
+            path: webhook.path.clone(),
+            space_id: webhook.space_id.clone(),
+            events: webhook.events.clone(),
+            secret: webhook.secret.clone(),
+            created_at: webhook.created_at,
+        }
+    }
+
+    fn into_webhook(self) -> Webhook {
+        Webhook {
+            id: self.id,
+            url: self.url,
+            room_id: self.room_id,
+            path: self.path,
+            events: self.events,
+            secret: self.secret,
+            created_at: self.created_at,
+            space_id: self.space_id,
+        }
+    }
+}
+
+/// The registered webhooks and the log of their deliveries
+///
+/// # Fields
+/// * `registry` => the webhooks and the log;
+/// * `path` => file the webhooks are saved to, `None` to keep them in memory;
+/// * `spaces` => ids of the spaces holding each room, kept by the dispatcher.
+pub struct Webhooks {
+    registry: Mutex<Registry>,
+    path: Option<PathBuf>,
+    spaces: Mutex<HashMap<String, Vec<String>>>,
+}
+
+impl Webhooks {
+    /// Create a registry without webhooks, kept in memory
+    pub fn new() -> Webhooks {
+        Webhooks {
+            registry: Mutex::new(Registry {
+                webhooks: BTreeMap::new(),
+                last_webhook_id: 0,
+                deliveries: VecDeque::new(),
+                last_delivery_id: 0,
+            }),
+            path: None,
+            spaces: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Open the registry saved in the file at `path`, created on the first
+    /// registration
+    ///
+    /// # Return Value
+    /// The registry, an `Error::Internal` when the file can't be read or an
+    /// `Error::Decode` when it isn't a saved registry.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Webhooks> {
+        let path: PathBuf = path.as_ref().to_path_buf();
+        let webhooks = Webhooks { path: Some(path.clone()), ..Webhooks::new() };
+        if !path.exists() {
+            return Ok(webhooks);
+        }
+
+        let mut contents = String::new();
+        File::open(&path)
+            .and_then(|mut file| file.read_to_string(&mut contents))
+            .map_err(|err| io_error(&path, err))?;
+        let saved: Saved = utils::from_json_to_obj(&contents)?;
+
+        {
+            let mut registry = webhooks.registry.lock().unwrap();
+            registry.last_webhook_id = saved.last_webhook_id;
+            for webhook in saved.webhooks {
+                let id: u64 = webhook.id.parse().map_err(|_| {
+                        Error::Decode(format!("{}: invalid webhook id {}",
+                                              path.display(),
+                                              webhook.id))
+                    })?;
+                registry.webhooks.insert(id, webhook.into_webhook());
+            }
+        }
+
+        Ok(webhooks)
+    }
+
+    /// Save the webhooks of `registry`, replacing the previous file
+    /// atomically. Nothing is done for a registry kept in memory.
+    fn save(&self, registry: &Registry) -> Result<()> {
+        let path: &Path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let saved = Saved {
+            last_webhook_id: registry.last_webhook_id,
+            webhooks: registry.webhooks.values().map(SavedWebhook::new).collect(),
+        };
+        let contents: String = utils::from_obj_to_json(&saved)?;
+
+        let tmp: PathBuf = path.with_extension("tmp");
+        File::create(&tmp)
+            .and_then(|mut file| file.write_all(contents.as_bytes()).and_then(|_| file.sync_all()))
+            .and_then(|_| fs::rename(&tmp, path))
+            .map_err(|err| io_error(path, err))
+    }
+
+    /// Register `webhook`
+    ///
+    /// # Return Value
+    /// The webhook with its secret, an `Error::Validation` for an invalid
+    /// URL or for the `Occupancy` kind, which is only sent to subscribers, or
+    /// an `Error::Internal` when it can't be saved.
+    pub fn register(&self, webhook: NewWebhook) -> Result<Webhook> {
+        match Url::parse(&webhook.url) {
+            Ok(ref url) if url.scheme() == "http" || url.scheme() == "https" => {}
+            _ => return Err(Error::Validation(format!("{} isn't an HTTP URL", webhook.url))),
+        }
+        if webhook.events.contains(&EventKind::Occupancy) {
+            return Err(Error::Validation("occupancy events can't be sent to webhooks".to_owned()));
+        }
+
+        let events: Vec<EventKind> = if webhook.events.is_empty() {
+            DEFAULT_EVENTS.to_vec()
+        } else {
+            webhook.events
+        };
+        let secret: String = match webhook.secret {
+            Some(secret) => secret,
+            None => auth::to_hex(&rand::random::<[u8; 32]>()),
+        };
+
+        let mut registry = self.registry.lock().unwrap();
+        registry.last_webhook_id += 1;
+        let id = registry.last_webhook_id;
+        let webhook = Webhook {
+            id: id.to_string(),
+            url: webhook.url,
+            room_id: webhook.room_id,
+            path: webhook.path,
+            events: events,
+            secret: secret,
+            created_at: utils::unix_time(),
+            space_id: webhook.space_id,
+        };
+        registry.webhooks.insert(id, webhook.clone());
+        if let Err(err) = self.save(&registry) {
+            registry.webhooks.remove(&id);
+            return Err(err);
+        }
+
+        Ok(webhook)
+    }
+
+    /// Remove the webhook `id`
+    ///
+    /// # Return Value
+    /// The webhook removed, an `Error::NotFound` or an `Error::Internal` when
+    /// the removal can't be saved.
+    pub fn unregister(&self, id: &str) -> Result<Webhook> {
+        let mut registry = self.registry.lock().unwrap();
+
+        let key: u64 = id.parse().map_err(|_| not_found(id))?;
+        let webhook: Webhook = registry.webhooks.remove(&key).ok_or_else(|| not_found(id))?;
+        if let Err(err) = self.save(&registry) {
+            registry.webhooks.insert(key, webhook);
+            return Err(err);
+        }
+
+        Ok(webhook.redacted())
+    }
+
+    /// The registered webhooks, without their secrets
+    pub fn list(&self) -> Vec<Webhook> {
+        self.registry.lock().unwrap().webhooks.values().map(Webhook::redacted).collect()
+    }
+
+    /// The deliveries to the webhook `id` in the log, latest first
+    ///
+    /// # Return Value
+    /// The deliveries or an `Error::NotFound` when the webhook isn't
+    /// registered.
+    pub fn deliveries(&self, id: &str) -> Result<Vec<Delivery>> {
+        let registry = self.registry.lock().unwrap();
+        if !registry.webhooks.values().any(|webhook| webhook.id == id) {
+            return Err(not_found(id));
+        }
+
+        Ok(registry.deliveries
+            .iter()
+            .rev()
+            .filter(|delivery| delivery.webhook_id == id)
+            .cloned()
+            .collect())
+    }
+
+    /// True when a webhook follows the rooms of a space
+    fn follows_paths(&self) -> bool {
+        self.registry.lock().unwrap().webhooks.values().any(|webhook| webhook.path.is_some())
+    }
+
+    /// Log a pending delivery of `event` to every webhook receiving it
+    ///
+    /// # Return Value
+    /// The webhooks with the id of their delivery.
+    fn schedule(&self, event: &Event, spaces: &[String]) -> Vec<(Webhook, String)> {
+        let mut registry = self.registry.lock().unwrap();
+        let webhooks: Vec<Webhook> = registry.webhooks
+            .values()
+            .filter(|webhook| webhook.matches(event, spaces))
+            .cloned()
+            .collect();
+
+        let now = utils::unix_time();
+        let mut scheduled = Vec::new();
+        for webhook in webhooks {
+            registry.last_delivery_id += 1;
+            let id = registry.last_delivery_id.to_string();
+            if registry.deliveries.len() == LOG_SIZE {
+                registry.deliveries.pop_front();
+            }
+            registry.deliveries.push_back(Delivery {
+                id: id.clone(),
+                webhook_id: webhook.id.clone(),
+                event: event.kind,
+                room_id: event.room_id.clone(),
+                status: DeliveryStatus::Pending,
+                attempts: 0,
+                response_code: None,
+                error: None,
+                created_at: event.time,
+                updated_at: now,
+            });
+            scheduled.push((webhook, id));
+        }
+
+        scheduled
+    }
+
+    /// Ids of the spaces holding the room `room_id`, from its campus to its
+    /// own space, kept once found
+    ///
+    /// # Return Value
+    /// The ids, none when the room or its space is unknown.
+    fn spaces_of(&self,
+                 store: &RoomStore,
+                 fenix: &FenixSpacesClient,
+                 room_id: &str)
+                 -> Vec<String> {
+        if let Some(spaces) = self.spaces.lock().unwrap().get(room_id) {
+            return spaces.clone();
+        }
+
+        // The space of a room never changes, so it is looked up once
+        match store.room(room_id).and_then(|room| fenix.ancestry(&room.fenix_id)) {
+            Ok(ancestry) => {
+                let ids: Vec<String> = ancestry.into_iter().map(|space| space.id).collect();
+                let mut spaces = self.spaces.lock().unwrap();
+                if spaces.len() >= SPACES_CACHE {
+                    spaces.clear();
+                }
+                spaces.insert(room_id.to_owned(), ids.clone());
+                ids
+            }
+            Err(err) => {
+                println!("Spaces of the room {} unknown: {}", room_id, err);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Log a failed delivery of `event` to every webhook receiving it, for an
+    /// event the dispatcher was too late to receive
+    ///
+    /// The publisher calls it, so only the spaces already kept by the
+    /// dispatcher are used: the webhooks of a path don't see the events lost
+    /// for a room it never looked up.
+    pub fn record_lost(&self, event: &Event) {
+        let spaces: Vec<String> =
+            self.spaces.lock().unwrap().get(&event.room_id).cloned().unwrap_or_else(Vec::new);
+
+        for (webhook, id) in self.schedule(event, &spaces) {
+            println!("Delivery {} to {} lost: the dispatcher is late", id, webhook.url);
+            self.record(&id,
+                        DeliveryStatus::Failed,
+                        None,
+                        Some("The event was lost before its delivery".to_owned()));
+        }
+    }
+
+    /// Record an attempt of the delivery `id`
+    fn record(&self, id: &str, status: DeliveryStatus, code: Option<u16>, error: Option<String>) {
+        let mut registry = self.registry.lock().unwrap();
+
+        if let Some(delivery) = registry.deliveries.iter_mut().find(|delivery| delivery.id == id) {
+            delivery.status = status;
+            delivery.attempts += 1;
+            delivery.response_code = code;
+            delivery.error = error;
+            delivery.updated_at = utils::unix_time();
+        }
+    }
+}
+
+impl Default for Webhooks {
+    fn default() -> Webhooks {
+        Webhooks::new()
+    }
+}
+
+fn not_found(id: &str) -> Error {
+    Error::NotFound(format!("The webhook {} doesn't exist", id))
+}
+
+fn io_error(path: &Path, err: ::std::io::Error) -> Error {
+    Error::Internal(format!("{}: {}", path.display(), err))
+}
+
+/// Signature of `body` with `secret`, as sent in `X-Fenix-Rooms-Signature`
+pub fn signature(secret: &str, body: &str) -> String {
+    let mut hmac = Hmac::new(Sha256::new(), secret.as_bytes());
+    hmac.input(body.as_bytes());
+
+    format!("sha256={}", auth::to_hex(hmac.result().code()))
+}
+
+/// A delivery waiting for a worker: the webhook, the id of the delivery and
+/// the event
+type Job = (Webhook, String, Event);
+
+/// Deliver the events of `receiver` to the webhooks of `webhooks` in the
+/// background
+///
+/// # Arguments
+/// * `webhooks` => the registered webhooks;
+/// * `store` => store of the rooms, to find the space of the room of an event;
+/// * `fenix` => client of `FenixEDU`, to find the spaces holding that space;
+/// * `receiver` => subscription to the `EventBus`.
+pub fn spawn_dispatcher(webhooks: Arc<Webhooks>,
+                        store: Arc<RoomStore>,
+                        fenix: Arc<FenixSpacesClient>,
+                        receiver: Receiver<Event>)
+                        -> JoinHandle<()> {
+    let jobs: SyncSender<Job> = spawn_workers(webhooks.clone());
+
+    thread::spawn(move || {
+        for event in receiver.iter() {
+            // The spaces of the room only matter to the webhooks of a path
+            let spaces: Vec<String> = if webhooks.follows_paths() {
+                webhooks.spaces_of(&*store, &fenix, &event.room_id)
+            } else {
+                Vec::new()
+            };
+
+            for (webhook, id) in webhooks.schedule(&event, &spaces) {
+                match jobs.try_send((webhook, id, event.clone())) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full((webhook, id, _))) |
+                    Err(TrySendError::Disconnected((webhook, id, _))) => {
+                        println!("Delivery {} to {} dropped: the queue is full", id, webhook.url);
+                        webhooks.record(&id,
+                                        DeliveryStatus::Failed,
+                                        None,
+                                        Some("The delivery queue is full".to_owned()));
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Spawn the `WORKERS` threads delivering the jobs of the returned queue
+fn spawn_workers(webhooks: Arc<Webhooks>) -> SyncSender<Job> {
+    let (sender, receiver) = mpsc::sync_channel::<Job>(QUEUE);
+    let receiver = Arc::new(Mutex::new(receiver));
+
+    for _ in 0..WORKERS {
+        let webhooks = webhooks.clone();
+        let receiver = receiver.clone();
+        thread::spawn(move || {
+            loop {
+                // The lock is only held while waiting for a job
+                let job = receiver.lock().unwrap().recv();
+                match job {
+                    Ok((webhook, id, event)) => deliver(&webhooks, &webhook, &id, &event),
+                    Err(_) => return,
+                }
+            }
+        });
+    }
+
+    sender
+}
+
+/// Send `event` to `webhook` until it is accepted or `MAX_ATTEMPTS` requests
+/// failed
+fn deliver(webhooks: &Webhooks, webhook: &Webhook, id: &str, event: &Event) {
+    let payload = Payload {
+        delivery_id: id,
+        webhook_id: &webhook.id,
+        event: event,
+    };
+    let body: String = match utils::from_obj_to_json(&payload) {
+        Ok(body) => body,
+        Err(err) => {
+            webhooks.record(id, DeliveryStatus::Failed, None, Some(err.to_string()));
+            return;
+        }
+    };
+
+    let mut delay = BACKOFF;
+    for attempt in 1..MAX_ATTEMPTS + 1 {
+        let mut headers = Headers::new();
+        headers.set_raw("X-Fenix-Rooms-Event", vec![event.kind.name().as_bytes().to_vec()]);
+        headers.set_raw("X-Fenix-Rooms-Delivery", vec![id.as_bytes().to_vec()]);
+        headers.set_raw("X-Fenix-Rooms-Signature",
+                        vec![signature(&webhook.secret, &body).into_bytes()]);
+
+        let (code, error) = match utils::post_request_with_headers(&webhook.url, &body, headers) {
+            Ok(ref response) if response.status.is_success() => {
+                webhooks.record(id,
+                                DeliveryStatus::Delivered,
+                                Some(response.status.to_u16()),
+                                None);
+                return;
+            }
+            Ok(response) => {
+                let error = format!("The webhook answered {}", response.status);
+                (Some(response.status.to_u16()), error)
+            }
+            Err(err) => (None, err.to_string()),
+        };
+
+        if attempt == MAX_ATTEMPTS {
+            println!("Delivery {} to {} failed: {}", id, webhook.url, error);
+            webhooks.record(id, DeliveryStatus::Failed, code, Some(error));
+            return;
+        }
+
+        webhooks.record(id, DeliveryStatus::Pending, code, Some(error));
+        thread::sleep(Duration::from_secs(delay));
+        delay *= 2;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use events::EventKind;
+    use super::{NewWebhook, Webhooks};
+
+    /// Path of a file for the test `name` in the temporary directory
+    fn temp_path(name: &str) -> PathBuf {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+        env::temp_dir().join(format!("fenix_rooms_webhooks_{}_{}.json", name, nanos))
+    }
+
+    fn new_webhook(url: &str) -> NewWebhook {
+        NewWebhook {
+            url: url.to_owned(),
+            room_id: None,
+            path: Some("alameda".to_owned()),
+            space_id: Some("2448131360897".to_owned()),
+            events: vec![EventKind::CheckIn],
+            secret: Some("secret".to_owned()),
+        }
+    }
+
+    #[test]
+    fn registrations_survive_a_reopen() {
+        let path = temp_path("reopen");
+        let (first, second) = {
+            let webhooks = Webhooks::open(&path).unwrap();
+            let first = webhooks.register(new_webhook("http://example.org/1")).unwrap();
+            let second = webhooks.register(new_webhook("http://example.org/2")).unwrap();
+            webhooks.unregister(&first.id).unwrap();
+            (first, second)
+        };
+
+        let webhooks = Webhooks::open(&path).unwrap();
+        let listed = webhooks.list();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].id, second.id);
+        assert_eq!(listed[0].url, second.url);
+
+        // The secret and the space are kept, and the ids aren't reused
+        let reopened = webhooks.registry.lock().unwrap().webhooks.values().next().unwrap().clone();
+        assert_eq!(reopened, second);
+        let third = webhooks.register(new_webhook("http://example.org/3")).unwrap();
+        assert!(third.id != first.id && third.id != second.id);
+
+        fs::remove_file(&path).unwrap();
+    }
+}